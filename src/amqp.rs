@@ -0,0 +1,522 @@
+use crate::{TaskBatchResultMessage, TaskResultMessage};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use lapin::protocol::{AMQPErrorKind, AMQPSoftError};
+use lapin::tcp::{OwnedIdentity, OwnedTLSConfig};
+use lapin::types::{AMQPValue, FieldTable};
+use lapin::uri::AMQPUri;
+use lapin::{BasicProperties, Connection, ConnectionProperties};
+use std::borrow::Cow;
+use std::io::{Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{env, fs, io};
+
+/// Arguments for declaring the task queue.
+///
+/// Workers and clients must declare the queue with identical arguments, since
+/// RabbitMQ rejects a redeclaration whose arguments differ.
+pub fn task_queue_arguments(
+    dead_letter_exchange: Option<&str>,
+    max_priority: Option<u8>,
+) -> FieldTable {
+    let mut arguments = FieldTable::default();
+    if let Some(exchange) = dead_letter_exchange {
+        arguments.insert(
+            "x-dead-letter-exchange".into(),
+            AMQPValue::LongString(exchange.into()),
+        );
+    }
+    if let Some(priority) = max_priority {
+        arguments.insert("x-max-priority".into(), AMQPValue::ShortShortUInt(priority));
+    }
+    arguments
+}
+
+/// `TASK_QUEUE_MAX_PRIORITY`, the task queue's `x-max-priority`, when set.
+pub fn max_priority_from_env() -> io::Result<Option<u8>> {
+    env::var("TASK_QUEUE_MAX_PRIORITY")
+        .ok()
+        .map(|value| parse_max_priority(&value))
+        .transpose()
+}
+
+/// RabbitMQ supports priorities from 1 to 255; a queue declared with 0 would
+/// silently ignore every message's priority.
+fn parse_max_priority(value: &str) -> io::Result<u8> {
+    match value.parse::<u8>() {
+        Ok(priority) if priority >= 1 => Ok(priority),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "invalid TASK_QUEUE_MAX_PRIORITY '{}': must be 1 to 255",
+                value
+            ),
+        )),
+    }
+}
+
+/// Properties for a persistent JSON message: content type, `message_id` and
+/// the current time as timestamp.
+///
+/// Ids should repeat when the same message is published again, e.g. a result
+/// after a redelivery, so deduplicating consumers can drop the copy.
+pub fn json_properties(message_id: &str) -> BasicProperties {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    BasicProperties::default()
+        .with_delivery_mode(2)
+        .with_content_type("application/json".into())
+        .with_message_id(message_id.into())
+        .with_timestamp(timestamp)
+}
+
+/// `content_encoding` of a gzip-compressed payload.
+pub const GZIP_ENCODING: &str = "gzip";
+
+/// Compresses a payload to publish with `content_encoding: gzip`.
+pub fn gzip(payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(payload)?;
+    encoder.finish()
+}
+
+/// Largest payload [`decode_payload`] decompresses, so a small gzip bomb
+/// cannot exhaust the worker's memory.
+pub const MAX_DECODED_PAYLOAD_BYTES: u64 = 16 * 1024 * 1024;
+
+/// The payload of a message, decompressed when its `content_encoding` is
+/// `gzip`. Without an encoding, or with `identity`, the bytes are returned as
+/// they are; any other encoding, or a payload that decompresses to more than
+/// [`MAX_DECODED_PAYLOAD_BYTES`], is an error.
+pub fn decode_payload<'a>(
+    data: &'a [u8],
+    properties: &BasicProperties,
+) -> io::Result<Cow<'a, [u8]>> {
+    match properties
+        .content_encoding()
+        .as_ref()
+        .map(|encoding| encoding.as_str())
+    {
+        None | Some("") | Some("identity") => Ok(Cow::Borrowed(data)),
+        Some(encoding) if encoding.eq_ignore_ascii_case(GZIP_ENCODING) => {
+            let mut decoded = Vec::new();
+            GzDecoder::new(data)
+                .take(MAX_DECODED_PAYLOAD_BYTES + 1)
+                .read_to_end(&mut decoded)?;
+            if decoded.len() as u64 > MAX_DECODED_PAYLOAD_BYTES {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "payload decompresses to more than {} bytes",
+                        MAX_DECODED_PAYLOAD_BYTES
+                    ),
+                ));
+            }
+            Ok(Cow::Owned(decoded))
+        }
+        Some(encoding) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported content encoding {}", encoding),
+        )),
+    }
+}
+
+/// `addr` with the password of its user info, if any, replaced by `***`, for
+/// printing or logging.
+pub fn redact_credentials(addr: &str) -> String {
+    let Some((scheme, rest)) = addr.split_once("://") else {
+        return addr.to_string();
+    };
+    let authority_end = rest.find('/').unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(authority_end);
+    let Some((userinfo, host)) = authority.rsplit_once('@') else {
+        return addr.to_string();
+    };
+    let user = match userinfo.split_once(':') {
+        Some((user, _)) => format!("{}:***", user),
+        None => userinfo.to_string(),
+    };
+    format!("{}://{}@{}{}", scheme, user, host, path)
+}
+
+/// Reads the TLS settings for `addr` from the environment.
+///
+/// Only `amqps://` URLs use TLS. For those, `AMQP_CA_CERT` names a PEM bundle
+/// of trusted CAs (the platform roots are used otherwise), and
+/// `AMQP_CLIENT_CERT` / `AMQP_CLIENT_KEY` name a PEM certificate chain and
+/// PKCS#8 key for client authentication; both or neither must be set. Missing
+/// or unreadable files are reported with the variable and path at fault.
+pub fn tls_config_from_env(addr: &str) -> io::Result<Option<OwnedTLSConfig>> {
+    if !addr.starts_with("amqps://") {
+        return Ok(None);
+    }
+
+    let cert_chain = read_env_file("AMQP_CA_CERT")?
+        .map(|pem| {
+            String::from_utf8(pem).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "AMQP_CA_CERT is not a PEM file")
+            })
+        })
+        .transpose()?;
+
+    let identity = match (
+        read_env_file("AMQP_CLIENT_CERT")?,
+        read_env_file("AMQP_CLIENT_KEY")?,
+    ) {
+        (Some(pem), Some(key)) => Some(OwnedIdentity::PKCS8 { pem, key }),
+        (None, None) => None,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "AMQP_CLIENT_CERT and AMQP_CLIENT_KEY must be set together",
+            ))
+        }
+    };
+
+    Ok(Some(OwnedTLSConfig {
+        identity,
+        cert_chain,
+    }))
+}
+
+/// Heartbeat interval, in seconds, used when `AMQP_HEARTBEAT` is unset.
+pub const DEFAULT_HEARTBEAT_SECS: u16 = 30;
+
+/// Broker address, heartbeat and TLS settings, resolved once at startup so
+/// reconnects do not re-read the environment or certificate files.
+#[derive(Clone)]
+pub struct ConnectionConfig {
+    uri: AMQPUri,
+    tls: Option<OwnedTLSConfig>,
+}
+
+impl ConnectionConfig {
+    /// Parses `addr` and applies `AMQP_HEARTBEAT` (seconds, `0` disables;
+    /// default [`DEFAULT_HEARTBEAT_SECS`]) and the TLS variables read by
+    /// [`tls_config_from_env`].
+    pub fn from_env(addr: &str) -> io::Result<Self> {
+        let mut uri: AMQPUri = addr.parse().map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid AMQP_ADDR: {}", err),
+            )
+        })?;
+
+        let heartbeat = match env::var("AMQP_HEARTBEAT") {
+            Ok(value) => value.parse::<u16>().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid AMQP_HEARTBEAT '{}'", value),
+                )
+            })?,
+            Err(_) => DEFAULT_HEARTBEAT_SECS,
+        };
+        uri.query.heartbeat = Some(heartbeat);
+
+        Ok(Self {
+            uri,
+            tls: tls_config_from_env(addr)?,
+        })
+    }
+
+    /// Opens a connection that shows up as `name` in the management UI.
+    pub async fn connect(&self, name: &str) -> lapin::Result<Connection> {
+        let properties = ConnectionProperties::default().with_connection_name(name.into());
+        match &self.tls {
+            Some(tls) => {
+                Connection::connect_uri_with_config(self.uri.clone(), properties, tls.clone()).await
+            }
+            None => Connection::connect_uri(self.uri.clone(), properties).await,
+        }
+    }
+}
+
+/// Contents of the file named by the environment variable `name`, if it is set.
+fn read_env_file(name: &str) -> io::Result<Option<Vec<u8>>> {
+    let Some(path) = env::var_os(name).filter(|path| !path.is_empty()) else {
+        return Ok(None);
+    };
+
+    fs::read(&path).map(Some).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("cannot read {} ({}): {}", name, path.to_string_lossy(), err),
+        )
+    })
+}
+
+/// True when the broker refused a declaration because it conflicts with an
+/// existing entity, e.g. a queue redeclared with different arguments.
+pub fn is_precondition_failed(err: &lapin::Error) -> bool {
+    matches!(
+        err,
+        lapin::Error::ProtocolError(amqp_error)
+            if matches!(amqp_error.kind(), AMQPErrorKind::Soft(AMQPSoftError::PRECONDITIONFAILED))
+    )
+}
+
+/// Routing key template for results when `RESULT_ROUTING_KEY` is unset.
+pub const DEFAULT_RESULT_ROUTING_KEY: &str = "{eval_id}";
+
+/// Template for the routing key of task results, built from literal text and
+/// the placeholders `{eval_id}`, `{engine_id}`, `{speaker_id}` and `{task_id}`,
+/// e.g. `results.{engine_id}.{eval_id}` for consumers sharded by engine.
+///
+/// `{eval_id}` is required and every placeholder must be a whole
+/// dot-separated word, so a topic binding can match it with `*`. Dots in the
+/// values are rendered as `_` to keep each one a single word.
+#[derive(Clone, Debug)]
+pub struct ResultRoutingKey {
+    template: String,
+    parts: Vec<RoutingKeyPart>,
+}
+
+#[derive(Clone, Debug)]
+enum RoutingKeyPart {
+    Literal(String),
+    EvalId,
+    EngineId,
+    SpeakerId,
+    TaskId,
+}
+
+impl ResultRoutingKey {
+    /// Parses `template`, rejecting unknown placeholders, unclosed braces,
+    /// placeholders that share a word with other text, topic wildcards and a
+    /// missing `{eval_id}`.
+    pub fn new(template: &str) -> io::Result<Self> {
+        let invalid = |reason: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid result routing key {}: {}", template, reason),
+            )
+        };
+
+        let mut parts = Vec::new();
+        let mut rest = template;
+        let mut word_start = true;
+        while let Some(open) = rest.find('{') {
+            if open > 0 {
+                parts.push(RoutingKeyPart::Literal(rest[..open].to_string()));
+                word_start = rest[..open].ends_with('.');
+            }
+            let after = &rest[open + 1..];
+            let close = after
+                .find('}')
+                .ok_or_else(|| invalid("unclosed `{`".into()))?;
+            let name = &after[..close];
+            parts.push(match name {
+                "eval_id" => RoutingKeyPart::EvalId,
+                "engine_id" => RoutingKeyPart::EngineId,
+                "speaker_id" => RoutingKeyPart::SpeakerId,
+                "task_id" => RoutingKeyPart::TaskId,
+                other => return Err(invalid(format!("unknown placeholder {{{}}}", other))),
+            });
+            rest = &after[close + 1..];
+            if !word_start || !(rest.is_empty() || rest.starts_with('.')) {
+                return Err(invalid(format!(
+                    "{{{}}} must be a whole dot-separated word",
+                    name
+                )));
+            }
+        }
+        if !rest.is_empty() {
+            parts.push(RoutingKeyPart::Literal(rest.to_string()));
+        }
+        let literal_wildcard = parts
+            .iter()
+            .any(|part| matches!(part, RoutingKeyPart::Literal(text) if text.contains(['*', '#'])));
+        if literal_wildcard {
+            return Err(invalid("`*` and `#` are topic wildcards".into()));
+        }
+        if !parts
+            .iter()
+            .any(|part| matches!(part, RoutingKeyPart::EvalId))
+        {
+            return Err(invalid("{eval_id} is required".into()));
+        }
+
+        Ok(Self {
+            template: template.to_string(),
+            parts,
+        })
+    }
+
+    /// Reads `RESULT_ROUTING_KEY`, falling back to [`DEFAULT_RESULT_ROUTING_KEY`].
+    pub fn from_env() -> io::Result<Self> {
+        match env::var("RESULT_ROUTING_KEY") {
+            Ok(template) if !template.is_empty() => Self::new(&template),
+            _ => Self::new(DEFAULT_RESULT_ROUTING_KEY),
+        }
+    }
+
+    pub fn template(&self) -> &str {
+        &self.template
+    }
+
+    /// The routing key to publish `result` under.
+    pub fn render(&self, result: &TaskResultMessage) -> String {
+        self.render_values(
+            &result.eval_id,
+            result.engine_id,
+            result.speaker_id,
+            &result.task_id,
+        )
+    }
+
+    /// The routing key to publish a batch result under. `{speaker_id}` and
+    /// `{task_id}` take the values of the batch's first item.
+    pub fn render_batch(&self, result: &TaskBatchResultMessage) -> String {
+        let first = result.results.first();
+        self.render_values(
+            &result.eval_id,
+            result.engine_id,
+            first.map_or(0, |item| item.speaker_id),
+            first.map_or("", |item| item.task_id.as_str()),
+        )
+    }
+
+    fn render_values(
+        &self,
+        eval_id: &str,
+        engine_id: u32,
+        speaker_id: u32,
+        task_id: &str,
+    ) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                RoutingKeyPart::Literal(text) => text.clone(),
+                RoutingKeyPart::EvalId => routing_word(eval_id),
+                RoutingKeyPart::EngineId => engine_id.to_string(),
+                RoutingKeyPart::SpeakerId => speaker_id.to_string(),
+                RoutingKeyPart::TaskId => routing_word(task_id),
+            })
+            .collect()
+    }
+
+    /// A topic binding pattern matching every result of `eval_id`: each other
+    /// placeholder becomes `*`.
+    pub fn binding_pattern(&self, eval_id: &str) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                RoutingKeyPart::Literal(text) => text.clone(),
+                RoutingKeyPart::EvalId => routing_word(eval_id),
+                _ => "*".to_string(),
+            })
+            .collect()
+    }
+}
+
+/// `value` as a single routing key word.
+fn routing_word(value: &str) -> String {
+    value.replace('.', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(eval_id: &str, task_id: &str) -> TaskResultMessage {
+        TaskResultMessage {
+            eval_id: eval_id.into(),
+            task_id: task_id.into(),
+            engine_id: 2,
+            speaker_id: 8,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn result_routing_key_renders_and_binds_each_placeholder_as_a_word() {
+        let key = ResultRoutingKey::new("results.{engine_id}.{eval_id}.{task_id}").unwrap();
+        assert_eq!(key.render(&result("e1", "t1")), "results.2.e1.t1");
+        assert_eq!(key.render(&result("e.1", "t.1")), "results.2.e_1.t_1");
+        assert_eq!(key.binding_pattern("e.1"), "results.*.e_1.*");
+
+        let default = ResultRoutingKey::new(DEFAULT_RESULT_ROUTING_KEY).unwrap();
+        assert_eq!(default.render(&result("e1", "t1")), "e1");
+        assert_eq!(default.binding_pattern("e1"), "e1");
+    }
+
+    #[test]
+    fn batch_results_use_the_first_item() {
+        let key = ResultRoutingKey::new("results.{eval_id}.{speaker_id}.{task_id}").unwrap();
+        let batch = TaskBatchResultMessage {
+            eval_id: "e1".into(),
+            engine_id: 2,
+            results: vec![crate::TaskBatchItemResult {
+                task_id: "t1".into(),
+                speaker_id: 3,
+                ..Default::default()
+            }],
+        };
+        assert_eq!(key.render_batch(&batch), "results.e1.3.t1");
+    }
+
+    #[test]
+    fn result_routing_key_rejects_bad_templates() {
+        for template in [
+            "results.{engine_id}",
+            "results.x{eval_id}",
+            "results.{eval_id}x",
+            "{eval_id}{engine_id}",
+            "results.#.{eval_id}",
+            "results.{eval_id}.*",
+            "results.{eval}",
+            "results.{eval_id",
+        ] {
+            assert!(
+                ResultRoutingKey::new(template).is_err(),
+                "{} was accepted",
+                template
+            );
+        }
+    }
+
+    fn gzip_properties() -> BasicProperties {
+        BasicProperties::default().with_content_encoding(GZIP_ENCODING.into())
+    }
+
+    #[test]
+    fn gzip_payload_round_trips() {
+        let payload =
+            r#"{"eval_id":"e","task_id":"t","speaker_id":1,"text":"こんにちは"}"#.as_bytes();
+        let compressed = gzip(payload).unwrap();
+        assert_ne!(compressed, payload);
+
+        let decoded = decode_payload(&compressed, &gzip_properties()).unwrap();
+        assert_eq!(decoded.as_ref(), payload);
+        let plain = decode_payload(payload, &BasicProperties::default()).unwrap();
+        assert!(matches!(plain, Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn oversized_gzip_payload_is_rejected() {
+        let bomb = gzip(&vec![b' '; MAX_DECODED_PAYLOAD_BYTES as usize + 1]).unwrap();
+        let err = decode_payload(&bomb, &gzip_properties()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let limit = gzip(&vec![b' '; MAX_DECODED_PAYLOAD_BYTES as usize]).unwrap();
+        assert!(decode_payload(&limit, &gzip_properties()).is_ok());
+    }
+
+    #[test]
+    fn unknown_encodings_are_rejected() {
+        let properties = BasicProperties::default().with_content_encoding("br".into());
+        assert!(decode_payload(b"{}", &properties).is_err());
+    }
+
+    #[test]
+    fn max_priority_must_be_between_1_and_255() {
+        assert_eq!(parse_max_priority("1").unwrap(), 1);
+        assert_eq!(parse_max_priority("255").unwrap(), 255);
+        for value in ["0", "256", "-1", "high"] {
+            assert!(parse_max_priority(value).is_err(), "{} was accepted", value);
+        }
+    }
+}
@@ -0,0 +1,823 @@
+use crate::{
+    messages::{BitDepth, OutputFormat},
+    tts::{EngineError, EngineResult},
+};
+use std::io;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// Decoded PCM audio with interleaved samples normalised to `[-1.0, 1.0]`.
+#[derive(Clone, Debug)]
+pub struct PcmAudio {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub samples: Vec<f32>,
+}
+
+impl PcmAudio {
+    /// Parses a RIFF/WAVE buffer containing 8/16/24/32-bit integer or 32-bit float PCM.
+    pub fn from_wav(bytes: &[u8]) -> EngineResult<Self> {
+        let (format, data) = parse_wav(bytes)?;
+        let samples = decode_samples(&format, data)?;
+
+        Ok(Self {
+            sample_rate: format.sample_rate,
+            channels: format.channels,
+            samples,
+        })
+    }
+
+    /// Encodes the samples as a 16-bit PCM WAV file.
+    pub fn to_wav(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.samples.len() * 2);
+        for sample in self.to_i16() {
+            data.extend_from_slice(&sample.to_le_bytes());
+        }
+        self.wav_container(WAVE_FORMAT_PCM, 16, &data)
+    }
+
+    /// Encodes the samples in `depth`. Integer formats get TPDF dither when
+    /// `dither` is set, which should be whenever the source had more
+    /// resolution than the target.
+    pub fn to_wav_with_depth(&self, depth: BitDepth, dither: bool) -> Vec<u8> {
+        match depth {
+            BitDepth::Float32 => {
+                let mut data = Vec::with_capacity(self.samples.len() * 4);
+                for sample in &self.samples {
+                    data.extend_from_slice(&sample.to_le_bytes());
+                }
+                self.wav_container(WAVE_FORMAT_IEEE_FLOAT, 32, &data)
+            }
+            BitDepth::Int16 => {
+                let mut data = Vec::with_capacity(self.samples.len() * 2);
+                for sample in &self.samples {
+                    let value = quantize(*sample, 32_768.0, dither) as i16;
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+                self.wav_container(WAVE_FORMAT_PCM, 16, &data)
+            }
+            BitDepth::Int24 => {
+                let mut data = Vec::with_capacity(self.samples.len() * 3);
+                for sample in &self.samples {
+                    let value = quantize(*sample, 8_388_608.0, dither) as i32;
+                    data.extend_from_slice(&value.to_le_bytes()[..3]);
+                }
+                self.wav_container(WAVE_FORMAT_PCM, 24, &data)
+            }
+        }
+    }
+
+    /// Wraps encoded sample `data` in RIFF/WAVE headers. Float files get the
+    /// extended `fmt ` chunk and `fact` chunk that non-PCM formats require.
+    fn wav_container(&self, tag: u16, bits_per_sample: u16, data: &[u8]) -> Vec<u8> {
+        let block_align = self.channels * (bits_per_sample / 8);
+        let is_pcm = tag == WAVE_FORMAT_PCM;
+        let fmt_len: u32 = if is_pcm { 16 } else { 18 };
+        let fact_len: u32 = if is_pcm { 0 } else { 12 };
+        let riff_len = 4 + (8 + fmt_len) + fact_len + 8 + data.len() as u32;
+
+        let mut wav = Vec::with_capacity(8 + riff_len as usize);
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&riff_len.to_le_bytes());
+        wav.extend_from_slice(b"WAVE");
+        wav.extend_from_slice(b"fmt ");
+        wav.extend_from_slice(&fmt_len.to_le_bytes());
+        wav.extend_from_slice(&tag.to_le_bytes());
+        wav.extend_from_slice(&self.channels.to_le_bytes());
+        wav.extend_from_slice(&self.sample_rate.to_le_bytes());
+        wav.extend_from_slice(&(self.sample_rate * block_align as u32).to_le_bytes());
+        wav.extend_from_slice(&block_align.to_le_bytes());
+        wav.extend_from_slice(&bits_per_sample.to_le_bytes());
+        if !is_pcm {
+            wav.extend_from_slice(&0u16.to_le_bytes());
+            let frames = data.len() as u32 / block_align.max(1) as u32;
+            wav.extend_from_slice(b"fact");
+            wav.extend_from_slice(&4u32.to_le_bytes());
+            wav.extend_from_slice(&frames.to_le_bytes());
+        }
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(data);
+        wav
+    }
+
+    /// Samples quantised to signed 16-bit integers.
+    pub fn to_i16(&self) -> Vec<i16> {
+        self.samples
+            .iter()
+            .map(|sample| {
+                (sample * 32768.0)
+                    .round()
+                    .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+            })
+            .collect()
+    }
+}
+
+/// Stream properties read from a WAV header.
+#[derive(Clone, Copy, Debug)]
+pub struct WavInfo {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: u16,
+    /// Number of sample frames, i.e. samples per channel.
+    pub frames: usize,
+    pub duration_ms: f64,
+}
+
+/// Reads stream properties and playback length from a WAV buffer without
+/// decoding samples.
+pub fn wav_info(bytes: &[u8]) -> Option<WavInfo> {
+    let (format, data) = parse_wav(bytes).ok()?;
+    let frame_bytes = format.channels as usize * (format.bits_per_sample as usize / 8);
+    if frame_bytes == 0 || format.sample_rate == 0 {
+        return None;
+    }
+    let frames = data.len() / frame_bytes;
+
+    Some(WavInfo {
+        sample_rate: format.sample_rate,
+        channels: format.channels,
+        bits_per_sample: format.bits_per_sample,
+        frames,
+        duration_ms: frames as f64 * 1000.0 / format.sample_rate as f64,
+    })
+}
+
+/// Audio kept on each side of the speech when trimming silence, so soft onsets
+/// and releases are not clipped.
+const SILENCE_GUARD_MS: u32 = 50;
+
+/// Removes leading and trailing frames whose peak stays below `threshold_db`
+/// dBFS, keeping [`SILENCE_GUARD_MS`] of audio around what remains. Audio that
+/// is silent throughout, or has nothing to trim, is returned untouched.
+pub fn trim_silence(wav: Vec<u8>, threshold_db: f32) -> EngineResult<Vec<u8>> {
+    let audio = PcmAudio::from_wav(&wav)?;
+    let channels = audio.channels as usize;
+    let frames = audio.samples.len() / channels;
+    let threshold = 10f32.powf(threshold_db / 20.0);
+
+    let loud = |frame: &usize| {
+        audio.samples[frame * channels..(frame + 1) * channels]
+            .iter()
+            .any(|sample| sample.abs() > threshold)
+    };
+    let (Some(first), Some(last)) = ((0..frames).find(loud), (0..frames).rev().find(loud)) else {
+        return Ok(wav);
+    };
+
+    let guard = (audio.sample_rate * SILENCE_GUARD_MS / 1000) as usize;
+    let start = first.saturating_sub(guard);
+    let end = (last + 1 + guard).min(frames);
+    if start == 0 && end == frames {
+        return Ok(wav);
+    }
+
+    Ok(PcmAudio {
+        samples: audio.samples[start * channels..end * channels].to_vec(),
+        ..audio
+    }
+    .to_wav())
+}
+
+/// Level reported for digital silence, which has no finite dBFS value.
+const LEVEL_FLOOR_DB: f64 = -120.0;
+
+/// Sample peak and RMS level of a signal, in dBFS.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Levels {
+    pub peak_dbfs: f64,
+    pub rms_dbfs: f64,
+}
+
+/// Measures the peak and RMS level over every sample of every channel.
+/// Silence, or an empty buffer, reports `-120` for both.
+pub fn levels(wav: &[u8]) -> EngineResult<Levels> {
+    let audio = PcmAudio::from_wav(wav)?;
+    let peak = audio
+        .samples
+        .iter()
+        .fold(0f64, |peak, sample| peak.max(sample.abs() as f64));
+    let sum_squares: f64 = audio
+        .samples
+        .iter()
+        .map(|sample| (*sample as f64).powi(2))
+        .sum();
+    let rms = (sum_squares / audio.samples.len().max(1) as f64).sqrt();
+
+    let dbfs = |amplitude: f64| {
+        if amplitude > 0.0 {
+            (20.0 * amplitude.log10()).max(LEVEL_FLOOR_DB)
+        } else {
+            LEVEL_FLOOR_DB
+        }
+    };
+    Ok(Levels {
+        peak_dbfs: dbfs(peak),
+        rms_dbfs: dbfs(rms),
+    })
+}
+
+/// Scales a `[-1.0, 1.0]` sample to a signed integer with full scale `scale`,
+/// optionally adding triangular (TPDF) dither of ±1 LSB before rounding.
+fn quantize(sample: f32, scale: f64, dither: bool) -> i64 {
+    let mut value = sample as f64 * scale;
+    if dither {
+        value += rand::random::<f64>() - rand::random::<f64>();
+    }
+    value.round().clamp(-scale, scale - 1.0) as i64
+}
+
+/// Converts a WAV buffer to `depth`. Buffers already in that format are
+/// returned untouched. Dither is applied when the source is float or has more
+/// bits than the target, so lossless widenings stay exact.
+pub fn convert_bit_depth(wav: Vec<u8>, depth: BitDepth) -> EngineResult<Vec<u8>> {
+    let (format, _) = parse_wav(&wav)?;
+    let source_is_float = format.tag == WAVE_FORMAT_IEEE_FLOAT;
+    let target_bits = match depth {
+        BitDepth::Int16 => 16,
+        BitDepth::Int24 => 24,
+        BitDepth::Float32 => 32,
+    };
+
+    let unchanged = match depth {
+        BitDepth::Float32 => source_is_float && format.bits_per_sample == 32,
+        _ => format.tag == WAVE_FORMAT_PCM && format.bits_per_sample == target_bits,
+    };
+    if unchanged {
+        return Ok(wav);
+    }
+
+    let dither =
+        depth != BitDepth::Float32 && (source_is_float || format.bits_per_sample > target_bits);
+    Ok(PcmAudio::from_wav(&wav)?.to_wav_with_depth(depth, dither))
+}
+
+/// Lowest and highest output rates accepted for `target_sample_rate`.
+const MIN_SAMPLE_RATE: u32 = 8_000;
+const MAX_SAMPLE_RATE: u32 = 192_000;
+
+/// Rejects target sample rates this build cannot produce.
+pub fn check_sample_rate(rate: u32) -> EngineResult<()> {
+    if !(MIN_SAMPLE_RATE..=MAX_SAMPLE_RATE).contains(&rate) {
+        return Err(EngineError::InvalidTask(format!(
+            "unsupported target sample rate {} Hz (expected {}-{} Hz)",
+            rate, MIN_SAMPLE_RATE, MAX_SAMPLE_RATE
+        )));
+    }
+
+    if cfg!(feature = "resample") {
+        Ok(())
+    } else {
+        Err(EngineError::InvalidTask(
+            "target_sample_rate requires the `resample` cargo feature".into(),
+        ))
+    }
+}
+
+/// Resamples a WAV buffer to `rate`, returning 16-bit PCM WAV. Buffers already
+/// at `rate` are returned untouched.
+pub fn resample(wav: Vec<u8>, rate: u32) -> EngineResult<Vec<u8>> {
+    check_sample_rate(rate)?;
+    let audio = PcmAudio::from_wav(&wav)?;
+    if audio.sample_rate == rate {
+        return Ok(wav);
+    }
+    Ok(resample_pcm(&audio, rate)?.to_wav())
+}
+
+/// Band-limited sinc interpolation (256 taps, Blackman-Harris window) via
+/// `rubato`. This favours quality over speed, but a sentence of speech still
+/// resamples in a few milliseconds, well below the cost of synthesis.
+#[cfg(feature = "resample")]
+fn resample_pcm(audio: &PcmAudio, rate: u32) -> EngineResult<PcmAudio> {
+    use rubato::{
+        Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
+    };
+
+    const CHUNK_FRAMES: usize = 1024;
+
+    let channels = audio.channels as usize;
+    let frames = audio.samples.len() / channels;
+    let ratio = rate as f64 / audio.sample_rate as f64;
+    let parameters = SincInterpolationParameters {
+        sinc_len: 256,
+        f_cutoff: 0.95,
+        interpolation: SincInterpolationType::Linear,
+        oversampling_factor: 256,
+        window: WindowFunction::BlackmanHarris2,
+    };
+    let mut resampler = SincFixedIn::<f32>::new(ratio, 1.0, parameters, CHUNK_FRAMES, channels)
+        .map_err(|err| resample_error(err.to_string()))?;
+
+    let input: Vec<Vec<f32>> = (0..channels)
+        .map(|channel| {
+            audio
+                .samples
+                .iter()
+                .skip(channel)
+                .step_by(channels)
+                .copied()
+                .collect()
+        })
+        .collect();
+    let mut output: Vec<Vec<f32>> = vec![Vec::new(); channels];
+    let append = |output: &mut Vec<Vec<f32>>, chunk: Vec<Vec<f32>>| {
+        for (channel, samples) in output.iter_mut().zip(chunk) {
+            channel.extend(samples);
+        }
+    };
+
+    let mut position = 0;
+    while position + CHUNK_FRAMES <= frames {
+        let chunk: Vec<&[f32]> = input
+            .iter()
+            .map(|channel| &channel[position..position + CHUNK_FRAMES])
+            .collect();
+        append(
+            &mut output,
+            resampler
+                .process(&chunk, None)
+                .map_err(|err| resample_error(err.to_string()))?,
+        );
+        position += CHUNK_FRAMES;
+    }
+    let rest: Vec<&[f32]> = input.iter().map(|channel| &channel[position..]).collect();
+    append(
+        &mut output,
+        resampler
+            .process_partial(Some(&rest), None)
+            .map_err(|err| resample_error(err.to_string()))?,
+    );
+
+    // Flush the filter so the tail is not cut off, then drop its leading delay.
+    let delay = resampler.output_delay();
+    let expected = (frames as f64 * ratio).round() as usize;
+    while output[0].len() < delay + expected {
+        let before = output[0].len();
+        append(
+            &mut output,
+            resampler
+                .process_partial(None::<&[Vec<f32>]>, None)
+                .map_err(|err| resample_error(err.to_string()))?,
+        );
+        if output[0].len() == before {
+            break;
+        }
+    }
+
+    let end = (delay + expected).min(output[0].len());
+    let mut samples = Vec::with_capacity(end.saturating_sub(delay) * channels);
+    for frame in delay..end {
+        for channel in &output {
+            samples.push(channel[frame]);
+        }
+    }
+
+    Ok(PcmAudio {
+        sample_rate: rate,
+        channels: audio.channels,
+        samples,
+    })
+}
+
+#[cfg(not(feature = "resample"))]
+fn resample_pcm(_audio: &PcmAudio, _rate: u32) -> EngineResult<PcmAudio> {
+    Err(EngineError::InvalidTask(
+        "target_sample_rate requires the `resample` cargo feature".into(),
+    ))
+}
+
+/// Range accepted for `normalize_lufs`.
+const MIN_LOUDNESS_LUFS: f64 = -70.0;
+const MAX_LOUDNESS_LUFS: f64 = -5.0;
+
+/// Sample-peak ceiling of the limiter that follows loudness normalization, in
+/// dBFS. Samples are not oversampled, so inter-sample (true) peaks may still
+/// exceed it slightly.
+const LIMITER_CEILING_DBFS: f64 = -1.0;
+
+/// Rejects loudness targets this build cannot produce.
+pub fn check_loudness_target(lufs: f64) -> EngineResult<()> {
+    if !(MIN_LOUDNESS_LUFS..=MAX_LOUDNESS_LUFS).contains(&lufs) {
+        return Err(EngineError::InvalidTask(format!(
+            "unsupported loudness target {} LUFS (expected {} to {} LUFS)",
+            lufs, MIN_LOUDNESS_LUFS, MAX_LOUDNESS_LUFS
+        )));
+    }
+
+    if cfg!(feature = "loudness") {
+        Ok(())
+    } else {
+        Err(EngineError::InvalidTask(
+            "normalize_lufs requires the `loudness` cargo feature".into(),
+        ))
+    }
+}
+
+/// Applies EBU R128 loudness normalization to a WAV buffer, returning 16-bit
+/// PCM WAV. Gain that would push sample peaks past -1 dBFS is soft-limited
+/// rather than clipped. Silent audio is returned untouched.
+pub fn normalize_loudness(wav: Vec<u8>, lufs: f64) -> EngineResult<Vec<u8>> {
+    check_loudness_target(lufs)?;
+    let mut audio = PcmAudio::from_wav(&wav)?;
+    let Some(loudness) = measure_loudness(&audio)? else {
+        return Ok(wav);
+    };
+
+    let gain = 10f64.powf((lufs - loudness) / 20.0) as f32;
+    for sample in &mut audio.samples {
+        *sample *= gain;
+    }
+    limit_peaks(&mut audio.samples);
+
+    Ok(audio.to_wav())
+}
+
+/// Integrated loudness in LUFS, or `None` when the audio is too quiet to be
+/// measured (below the absolute gate).
+#[cfg(feature = "loudness")]
+fn measure_loudness(audio: &PcmAudio) -> EngineResult<Option<f64>> {
+    use ebur128::{EbuR128, Mode};
+
+    let mut meter = EbuR128::new(audio.channels as u32, audio.sample_rate, Mode::I)
+        .map_err(|err| loudness_error(err.to_string()))?;
+    meter
+        .add_frames_f32(&audio.samples)
+        .map_err(|err| loudness_error(err.to_string()))?;
+    let loudness = meter
+        .loudness_global()
+        .map_err(|err| loudness_error(err.to_string()))?;
+
+    Ok(loudness.is_finite().then_some(loudness))
+}
+
+#[cfg(not(feature = "loudness"))]
+fn measure_loudness(_audio: &PcmAudio) -> EngineResult<Option<f64>> {
+    Err(EngineError::InvalidTask(
+        "normalize_lufs requires the `loudness` cargo feature".into(),
+    ))
+}
+
+/// Soft-knee limiter: samples below the knee pass through unchanged, louder
+/// ones are compressed with `tanh` so they approach but never reach the
+/// ceiling. Keeps 16-bit quantization from clipping after a gain boost.
+fn limit_peaks(samples: &mut [f32]) {
+    let ceiling = 10f32.powf(LIMITER_CEILING_DBFS as f32 / 20.0);
+    let knee = ceiling * 0.8;
+    let range = ceiling - knee;
+
+    for sample in samples {
+        let magnitude = sample.abs();
+        if magnitude > knee {
+            let limited = knee + range * ((magnitude - knee) / range).tanh();
+            *sample = limited.copysign(*sample);
+        }
+    }
+}
+
+/// Inserts a `LIST`/`INFO` chunk holding `tags`, pairs of a four-character
+/// INFO id such as `INAM` and its UTF-8 text, in front of the `data` chunk so
+/// that readers stopping at the samples still see it.
+pub fn embed_info(wav: Vec<u8>, tags: &[([u8; 4], &str)]) -> EngineResult<Vec<u8>> {
+    parse_wav(&wav)?;
+    let data_offset = find_chunk(&wav, b"data").ok_or_else(|| invalid_wav("missing data chunk"))?;
+
+    let mut list = Vec::new();
+    list.extend_from_slice(b"INFO");
+    for (id, text) in tags {
+        // Values are NUL-terminated, so embedded NULs would cut them short.
+        let mut value: Vec<u8> = text.bytes().filter(|byte| *byte != 0).collect();
+        value.push(0);
+        list.extend_from_slice(id);
+        list.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        list.extend_from_slice(&value);
+        if value.len() % 2 == 1 {
+            list.push(0);
+        }
+    }
+
+    let riff_len = read_u32(&wav, 4) + 8 + list.len() as u32;
+    let mut embedded = Vec::with_capacity(wav.len() + 8 + list.len());
+    embedded.extend_from_slice(b"RIFF");
+    embedded.extend_from_slice(&riff_len.to_le_bytes());
+    embedded.extend_from_slice(&wav[8..data_offset]);
+    embedded.extend_from_slice(b"LIST");
+    embedded.extend_from_slice(&(list.len() as u32).to_le_bytes());
+    embedded.extend_from_slice(&list);
+    embedded.extend_from_slice(&wav[data_offset..]);
+    Ok(embedded)
+}
+
+/// Converts VOICEVOX WAV output into the requested container.
+///
+/// WAV is returned untouched so the default path never re-encodes.
+pub fn encode(format: OutputFormat, wav: Vec<u8>) -> EngineResult<Vec<u8>> {
+    match format {
+        OutputFormat::Wav => Ok(wav),
+        OutputFormat::Mp3 => encode_mp3(&PcmAudio::from_wav(&wav)?),
+        OutputFormat::Flac => encode_flac(&PcmAudio::from_wav(&wav)?),
+    }
+}
+
+#[cfg(feature = "mp3")]
+fn encode_mp3(audio: &PcmAudio) -> EngineResult<Vec<u8>> {
+    use mp3lame_encoder::{Bitrate, Builder, FlushNoGap, InterleavedPcm, MonoPcm, Quality};
+
+    let mut builder =
+        Builder::new().ok_or_else(|| encoder_error("failed to create LAME encoder".into()))?;
+    builder
+        .set_num_channels(audio.channels as u8)
+        .map_err(|err| encoder_error(format!("{:?}", err)))?;
+    builder
+        .set_sample_rate(audio.sample_rate)
+        .map_err(|err| encoder_error(format!("{:?}", err)))?;
+    builder
+        .set_brate(Bitrate::Kbps128)
+        .map_err(|err| encoder_error(format!("{:?}", err)))?;
+    builder
+        .set_quality(Quality::Good)
+        .map_err(|err| encoder_error(format!("{:?}", err)))?;
+    let mut encoder = builder
+        .build()
+        .map_err(|err| encoder_error(format!("{:?}", err)))?;
+
+    let pcm = audio.to_i16();
+    let mut output = Vec::with_capacity(mp3lame_encoder::max_required_buffer_size(pcm.len()));
+    let encoded = match audio.channels {
+        1 => encoder.encode_to_vec(MonoPcm(&pcm), &mut output),
+        2 => encoder.encode_to_vec(InterleavedPcm(&pcm), &mut output),
+        other => {
+            return Err(EngineError::InvalidTask(format!(
+                "mp3 output supports mono or stereo audio, got {} channels",
+                other
+            )))
+        }
+    };
+    encoded.map_err(|err| encoder_error(format!("{:?}", err)))?;
+
+    output.reserve(7200);
+    encoder
+        .flush_to_vec::<FlushNoGap>(&mut output)
+        .map_err(|err| encoder_error(format!("{:?}", err)))?;
+
+    Ok(output)
+}
+
+#[cfg(not(feature = "mp3"))]
+fn encode_mp3(_audio: &PcmAudio) -> EngineResult<Vec<u8>> {
+    Err(EngineError::InvalidTask(
+        "mp3 output requires the `mp3` cargo feature".into(),
+    ))
+}
+
+#[cfg(feature = "flac")]
+fn encode_flac(audio: &PcmAudio) -> EngineResult<Vec<u8>> {
+    use flacenc::component::BitRepr;
+    use flacenc::error::Verify;
+
+    let samples: Vec<i32> = audio.to_i16().into_iter().map(i32::from).collect();
+    let config = flacenc::config::Encoder::default()
+        .into_verified()
+        .map_err(|(_, err)| encoder_error(format!("{:?}", err)))?;
+    let source = flacenc::source::MemSource::from_samples(
+        &samples,
+        audio.channels as usize,
+        16,
+        audio.sample_rate as usize,
+    );
+    let stream = flacenc::encode_with_fixed_block_size(&config, source, config.block_size)
+        .map_err(|err| encoder_error(format!("{:?}", err)))?;
+
+    let mut sink = flacenc::bitsink::ByteSink::new();
+    stream
+        .write(&mut sink)
+        .map_err(|err| encoder_error(format!("{:?}", err)))?;
+
+    Ok(sink.as_slice().to_vec())
+}
+
+#[cfg(not(feature = "flac"))]
+fn encode_flac(_audio: &PcmAudio) -> EngineResult<Vec<u8>> {
+    Err(EngineError::InvalidTask(
+        "flac output requires the `flac` cargo feature".into(),
+    ))
+}
+
+struct WavFormat {
+    tag: u16,
+    channels: u16,
+    sample_rate: u32,
+    bits_per_sample: u16,
+}
+
+/// Locates the `fmt ` and `data` chunks of a RIFF/WAVE buffer.
+fn parse_wav(bytes: &[u8]) -> EngineResult<(WavFormat, &[u8])> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(invalid_wav("missing RIFF/WAVE header"));
+    }
+
+    let mut format = None;
+    let mut data = None;
+    let mut offset = 12;
+
+    while offset + 8 <= bytes.len() {
+        let id = &bytes[offset..offset + 4];
+        let size = read_u32(bytes, offset + 4) as usize;
+        let start = offset + 8;
+        let end = start
+            .checked_add(size)
+            .filter(|end| *end <= bytes.len())
+            .ok_or_else(|| invalid_wav("truncated chunk"))?;
+
+        match id {
+            b"fmt " => format = Some(parse_format(&bytes[start..end])?),
+            b"data" => data = Some(&bytes[start..end]),
+            _ => {}
+        }
+
+        offset = end + (size & 1);
+    }
+
+    let format = format.ok_or_else(|| invalid_wav("missing fmt chunk"))?;
+    let data = data.ok_or_else(|| invalid_wav("missing data chunk"))?;
+    Ok((format, data))
+}
+
+/// Offset of the header of the first chunk with `id`, in a buffer that
+/// [`parse_wav`] accepted.
+fn find_chunk(bytes: &[u8], id: &[u8; 4]) -> Option<usize> {
+    let mut offset = 12;
+    while offset + 8 <= bytes.len() {
+        if &bytes[offset..offset + 4] == id {
+            return Some(offset);
+        }
+        let size = read_u32(bytes, offset + 4) as usize;
+        offset += 8 + size + (size & 1);
+    }
+    None
+}
+
+fn parse_format(chunk: &[u8]) -> EngineResult<WavFormat> {
+    if chunk.len() < 16 {
+        return Err(invalid_wav("fmt chunk too short"));
+    }
+
+    let mut tag = read_u16(chunk, 0);
+    if tag == WAVE_FORMAT_EXTENSIBLE && chunk.len() >= 26 {
+        tag = read_u16(chunk, 24);
+    }
+
+    Ok(WavFormat {
+        tag,
+        channels: read_u16(chunk, 2),
+        sample_rate: read_u32(chunk, 4),
+        bits_per_sample: read_u16(chunk, 14),
+    })
+}
+
+fn decode_samples(format: &WavFormat, data: &[u8]) -> EngineResult<Vec<f32>> {
+    if format.channels == 0 {
+        return Err(invalid_wav("zero channels"));
+    }
+
+    let samples = match (format.tag, format.bits_per_sample) {
+        (WAVE_FORMAT_PCM, 8) => data
+            .iter()
+            .map(|byte| (*byte as f32 - 128.0) / 128.0)
+            .collect(),
+        (WAVE_FORMAT_PCM, 16) => data
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0)
+            .collect(),
+        (WAVE_FORMAT_PCM, 24) => data
+            .chunks_exact(3)
+            .map(|b| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8_388_608.0)
+            .collect(),
+        (WAVE_FORMAT_PCM, 32) => data
+            .chunks_exact(4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.0)
+            .collect(),
+        (WAVE_FORMAT_IEEE_FLOAT, 32) => data
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+        (tag, bits) => {
+            return Err(invalid_wav(&format!(
+                "unsupported sample format {} with {} bits per sample",
+                tag, bits
+            )))
+        }
+    };
+
+    Ok(samples)
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn invalid_wav(reason: &str) -> EngineError {
+    EngineError::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid wav data: {}", reason),
+    ))
+}
+
+#[cfg(feature = "resample")]
+fn resample_error(reason: String) -> EngineError {
+    EngineError::Io(io::Error::other(format!("resampling failed: {}", reason)))
+}
+
+#[cfg(feature = "loudness")]
+fn loudness_error(reason: String) -> EngineError {
+    EngineError::Io(io::Error::other(format!(
+        "loudness normalization failed: {}",
+        reason
+    )))
+}
+
+#[cfg(any(feature = "mp3", feature = "flac"))]
+fn encoder_error(reason: String) -> EngineError {
+    EngineError::Io(io::Error::other(format!(
+        "audio encoding failed: {}",
+        reason
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wav() -> Vec<u8> {
+        PcmAudio {
+            sample_rate: 24_000,
+            channels: 1,
+            samples: vec![0.0, 0.5, -0.5, 0.25],
+        }
+        .to_wav()
+    }
+
+    /// The `(id, value)` pairs of the first `LIST`/`INFO` chunk.
+    fn read_info(wav: &[u8]) -> Vec<([u8; 4], String)> {
+        let list = find_chunk(wav, b"LIST").expect("LIST chunk");
+        let size = read_u32(wav, list + 4) as usize;
+        let body = &wav[list + 8..list + 8 + size];
+        assert_eq!(&body[..4], b"INFO");
+
+        let mut tags = Vec::new();
+        let mut offset = 4;
+        while offset + 8 <= body.len() {
+            let id: [u8; 4] = body[offset..offset + 4].try_into().unwrap();
+            let len = read_u32(body, offset + 4) as usize;
+            let value = &body[offset + 8..offset + 8 + len];
+            let value = value.strip_suffix(&[0]).expect("NUL-terminated value");
+            tags.push((id, String::from_utf8(value.to_vec()).unwrap()));
+            offset += 8 + len + (len & 1);
+        }
+        tags
+    }
+
+    #[test]
+    fn embedded_info_round_trips() {
+        let original = wav();
+        let embedded = embed_info(
+            original.clone(),
+            &[
+                (*b"INAM", "こんにちは"),
+                (*b"ICMT", "eval_id=e task_id=t speaker_id=3 style_id=302"),
+                (*b"ISFT", "vvx-worker"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            read_info(&embedded),
+            [
+                (*b"INAM", "こんにちは".to_string()),
+                (
+                    *b"ICMT",
+                    "eval_id=e task_id=t speaker_id=3 style_id=302".to_string()
+                ),
+                (*b"ISFT", "vvx-worker".to_string()),
+            ]
+        );
+        assert_eq!(read_u32(&embedded, 4) as usize, embedded.len() - 8);
+        // The LIST chunk sits in front of the samples, which are unchanged.
+        assert!(find_chunk(&embedded, b"LIST") < find_chunk(&embedded, b"data"));
+        let (_, data) = parse_wav(&embedded).unwrap();
+        assert_eq!(data, parse_wav(&original).unwrap().1);
+    }
+
+    #[test]
+    fn embedded_values_drop_nul_bytes() {
+        let embedded = embed_info(wav(), &[(*b"INAM", "a\0b")]).unwrap();
+        assert_eq!(read_info(&embedded), [(*b"INAM", "ab".to_string())]);
+    }
+}
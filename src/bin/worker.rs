@@ -1,18 +1,50 @@
 use camino::Utf8PathBuf;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use futures::stream::{self, SelectAll};
 use futures::StreamExt;
+use lapin::message::Delivery;
 use lapin::options::{
-    BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions, BasicQosOptions,
-    ExchangeDeclareOptions, QueueDeclareOptions,
+    BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicNackOptions,
+    BasicPublishOptions, BasicQosOptions, ExchangeDeclareOptions, QueueBindOptions,
+    QueueDeclareOptions,
 };
 use lapin::types::FieldTable;
-use lapin::{BasicProperties, Channel, Connection, ConnectionProperties, ExchangeKind};
+use lapin::{Channel, Connection, Consumer, ExchangeKind};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::error::Error;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::task::JoinSet;
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+use vvx_worker::amqp;
+use vvx_worker::config::{EngineIds, WorkerFileConfig};
+use vvx_worker::health::{self, Readiness};
+use vvx_worker::journal::{self, JournalEvent, TaskJournal};
+use vvx_worker::metrics;
+use vvx_worker::runner;
 use vvx_worker::{
-    MockTtsEngine, TaskMessage, TaskResultMessage, TtsEngine, VoicevoxConfig, VoicevoxTtsEngine,
+    discover_styles, AccelerationMode, CancelMessage, CancellationToken, EngineError, EngineResult,
+    HttpVoicevoxTtsEngine, MockTtsEngine, ModelScanProgress, OutputFormat, OutputSink,
+    ProgressSink, RetryPolicy, S3Config, SynthesisParams, TaskBatchItemResult, TaskBatchMessage,
+    TaskBatchResultMessage, TaskMessage, TaskOutput, TaskPayload, TaskProgressMessage,
+    TaskResultMessage, TaskStage, TraceContext, TtsEngine, VoicevoxConfig, VoicevoxTtsEngine,
+    WorkerAnnounceMessage, SCHEMA_VERSION,
 };
 
 const DEFAULT_QUEUE: &str = "vvx_tasks";
@@ -39,14 +71,29 @@ impl Error for WorkerConfigError {}
     about = "RabbitMQ worker that performs VOICEVOX or mock synthesis"
 )]
 struct Args {
-    /// Engine identifier used when reporting results (falls back to ENGINE_ID env var)
+    /// Engine identifier used when reporting results (falls back to ENGINE_ID
+    /// env var); a list or range such as `0,2` or `0-3` serves several ids
+    /// from one engine.
     #[arg(value_name = "ENGINE_ID")]
-    engine_id: Option<u32>,
+    engine_id: Option<EngineIds>,
+
+    /// TOML config file; environment variables and flags override its values.
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
 
     /// Use the mock HTTP engine instead of VOICEVOX.
     #[arg(long)]
     mock: bool,
 
+    /// Synthesize through the VOICEVOX engine server at this URL instead of
+    /// linking VOICEVOX directly.
+    #[arg(long, value_name = "URL", conflicts_with = "mock")]
+    http_engine: Option<String>,
+
+    /// Task queue to consume; repeat to drain several (overrides TASK_QUEUE).
+    #[arg(long = "queue", value_name = "QUEUE")]
+    queues: Vec<String>,
+
     /// Path to the ONNX Runtime shared library.
     #[arg(long)]
     voicevox_onnx: Option<PathBuf>,
@@ -55,184 +102,2076 @@ struct Args {
     #[arg(long)]
     voicevox_dict: Option<PathBuf>,
 
-    /// Directory containing VOICEVOX model assets (.vvm files or folders).
-    #[arg(long)]
-    voicevox_model_dir: Option<PathBuf>,
+    /// Path to a VOICEVOX user dictionary JSON file.
+    #[arg(long)]
+    voicevox_user_dict: Option<PathBuf>,
+
+    /// JSON object mapping task speaker ids to VOICEVOX style ids.
+    #[arg(long)]
+    speaker_map: Option<PathBuf>,
+
+    /// JSON object of default prosody parameters per VOICEVOX style id.
+    #[arg(long)]
+    speaker_defaults: Option<PathBuf>,
+
+    /// Directory containing VOICEVOX model assets (.vvm files or folders).
+    #[arg(long)]
+    voicevox_model_dir: Option<PathBuf>,
+
+    /// Number of VOICEVOX synthesizers to run concurrently (defaults to 1).
+    #[arg(long)]
+    voicevox_pool_size: Option<usize>,
+
+    /// Directory that all synthesized output must stay inside.
+    #[arg(long)]
+    output_root: Option<PathBuf>,
+
+    /// Directory that task `text_path` files must be inside.
+    #[arg(long)]
+    text_root: Option<PathBuf>,
+
+    /// Speaker id this worker accepts; repeat to allow several (defaults to all).
+    #[arg(long = "allow-speaker", value_name = "SPEAKER_ID")]
+    allow_speakers: Vec<u32>,
+
+    /// Style ids to load at startup: comma-separated ids or `all`.
+    #[arg(long, value_name = "STYLES")]
+    voicevox_preload: Option<String>,
+
+    /// Execution provider for VOICEVOX: auto, cpu or gpu (defaults to auto).
+    #[arg(long, value_name = "MODE")]
+    acceleration: Option<AccelerationMode>,
+
+    /// CPU threads per VOICEVOX synthesizer (0 lets VOICEVOX decide).
+    #[arg(long, value_name = "THREADS")]
+    cpu_threads: Option<u16>,
+
+    /// Reject tasks whose text is longer than this many characters.
+    #[arg(long, value_name = "CHARS")]
+    max_text_chars: Option<usize>,
+
+    /// Validate tasks and report synthetic success without synthesizing audio.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Refuse to start when two voice models expose the same style id.
+    #[arg(long)]
+    strict_models: bool,
+
+    /// Refuse to start when the prefetch count does not match the
+    /// synthesizer pool size, instead of logging a warning.
+    #[arg(long)]
+    strict: bool,
+
+    /// Check at startup that every voice model opens and has at least one
+    /// style, listing all bad files before refusing to start.
+    #[arg(long)]
+    validate_models: bool,
+
+    /// Run a throwaway synthesis at startup so the first task is not slowed
+    /// by lazy initialization.
+    #[arg(long)]
+    warmup: bool,
+
+    /// Settle deliveries without publishing results (also `PUBLISH_RESULTS=0`).
+    #[arg(long)]
+    no_results: bool,
+
+    /// Process a single delivery, then exit; the exit status reports whether
+    /// its task succeeded.
+    #[arg(long)]
+    once: bool,
+
+    /// Print the resolved configuration as JSON, with AMQP credentials
+    /// redacted, and exit without loading models or connecting.
+    #[arg(long)]
+    print_config: bool,
+
+    /// Print the speaker/style ids found in the model directory and exit.
+    #[arg(long)]
+    list_speakers: bool,
+
+    /// Output format for --list-speakers.
+    #[arg(long, value_enum, default_value_t = ListFormat::Table)]
+    format: ListFormat,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ListFormat {
+    Table,
+    Json,
+}
+
+#[tokio::main]
+async fn main() -> WorkerResult<()> {
+    let args = Args::parse();
+    init_tracing();
+
+    let file_config = match &args.config {
+        Some(path) => WorkerFileConfig::load(path)?,
+        None => WorkerFileConfig::default(),
+    }
+    .with_env()?;
+
+    if args.list_speakers {
+        return list_speakers(&args, &file_config);
+    }
+
+    let engine_ids = args
+        .engine_id
+        .clone()
+        .or_else(|| file_config.engine_id.clone())
+        .ok_or_else(|| {
+            Box::new(WorkerConfigError(
+                "provide engine id as positional argument, ENGINE_ID environment variable or config file"
+                    .into(),
+            )) as Box<dyn Error + Send + Sync>
+        })?;
+    // Names the connection and consumers and tags the worker's own logs; each
+    // delivery is reported under one of `engine_ids`.
+    let engine_id = engine_ids.primary();
+
+    let api_base = file_config
+        .api_base
+        .clone()
+        .unwrap_or_else(|| DEFAULT_API.to_string());
+    let result_exchange = result_exchange(&args, &file_config);
+
+    if (args.mock || args.http_engine.is_some()) && args.dry_run {
+        return Err(Box::new(WorkerConfigError(
+            "--dry-run is only supported by the VOICEVOX engine".into(),
+        )) as Box<dyn Error + Send + Sync>);
+    }
+
+    if args.print_config {
+        return print_config(&args, &file_config, &engine_ids, &api_base, result_exchange);
+    }
+
+    // Started before the engine so probes see 503 while models load.
+    let readiness = Readiness::default();
+    let health_server = match env::var("HEALTH_PORT") {
+        Ok(value) => {
+            let port = value.parse::<u16>().map_err(|_| {
+                Box::new(WorkerConfigError(format!(
+                    "invalid HEALTH_PORT '{}'",
+                    value
+                ))) as Box<dyn Error + Send + Sync>
+            })?;
+            let handle = health::serve(port, readiness.clone()).await?;
+            info!(engine_id, port, "health endpoint listening on /healthz");
+            Some(handle)
+        }
+        Err(_) => None,
+    };
+
+    let metrics_server = match env::var("METRICS_PORT") {
+        Ok(value) => {
+            let port = value.parse::<u16>().map_err(|_| {
+                Box::new(WorkerConfigError(format!(
+                    "invalid METRICS_PORT '{}'",
+                    value
+                ))) as Box<dyn Error + Send + Sync>
+            })?;
+            let handle = metrics::serve(port).await?;
+            info!(engine_id, port, "metrics endpoint listening on /metrics");
+            Some(handle)
+        }
+        Err(_) => None,
+    };
+
+    // Progress messages go out on whichever session channel is current.
+    let (progress_channel, _) = watch::channel(None::<Channel>);
+    let progress_reporting = env::var("PROGRESS_REPORTING").as_deref() == Ok("1");
+    let progress = match &result_exchange {
+        Some(exchange) if progress_reporting => Some(spawn_progress_publisher(
+            engine_id,
+            exchange.clone(),
+            progress_channel.subscribe(),
+        )),
+        None if progress_reporting => {
+            warn!(
+                engine_id,
+                "PROGRESS_REPORTING is ignored while result publishing is disabled"
+            );
+            None
+        }
+        _ => None,
+    };
+
+    let mut announce = WorkerAnnounceMessage {
+        engine_id,
+        ..Default::default()
+    };
+
+    let (engine, default_prefetch): (Arc<dyn TtsEngine>, u16) = if args.mock {
+        (Arc::new(build_mock_engine(&api_base)?), 1)
+    } else if let Some(url) = &args.http_engine {
+        let engine = build_http_engine(url, &args, &file_config)?;
+        announce.supported_formats = OutputFormat::supported()
+            .into_iter()
+            .map(|format| format.extension().to_string())
+            .collect();
+        (Arc::new(engine), 1)
+    } else {
+        let config = build_voicevox_config(&args, &file_config)?;
+        check_prefetch(&args, &file_config, config.pool_size.max(1))?;
+        let mut engine = VoicevoxTtsEngine::new(config)?;
+        if let Some(progress) = progress {
+            engine = engine.with_progress(progress);
+        }
+        announce.core_version = Some(engine.core_version().to_string());
+        announce.loaded_model_count = engine.model_count();
+        announce.supported_styles = engine.supported_styles();
+        announce.supported_formats = OutputFormat::supported()
+            .into_iter()
+            .map(|format| format.extension().to_string())
+            .collect();
+        let prefetch = u16::try_from(engine.pool_size()).unwrap_or(u16::MAX);
+        (Arc::new(engine), prefetch)
+    };
+
+    info!(
+        engine_id,
+        core_version = announce.core_version.as_deref(),
+        models = announce.loaded_model_count,
+        styles = ?announce.supported_styles,
+        formats = ?announce.supported_formats,
+        "engine ready"
+    );
+
+    let prefetch = prefetch_count(&args, default_prefetch)?;
+
+    let context = Arc::new(WorkerContext {
+        engine,
+        engine_id,
+        engine_ids: engine_ids.as_slice().to_vec(),
+        next_engine_id: AtomicUsize::new(0),
+        result_exchange: result_exchange.clone(),
+        result_routing_key: amqp::ResultRoutingKey::from_env()?,
+        task_timeout: task_timeout()?,
+        journal: open_journal()?,
+        cancelled: CancelledEvals::new(Duration::from_millis(env_parse(
+            "CANCEL_TTL_MS",
+            3_600_000,
+        )?)),
+        stats: TaskStats::default(),
+        in_progress: Arc::default(),
+        running: RunningTasks::default(),
+        dead_letter_invalid_payloads: env::var("INVALID_PAYLOAD_DEAD_LETTER").as_deref() == Ok("1"),
+    });
+
+    let stats_interval = Duration::from_secs(env_parse("STATS_INTERVAL_SECS", 0)?);
+    if !stats_interval.is_zero() {
+        tokio::spawn(log_stats(Arc::clone(&context), stats_interval));
+    }
+
+    let settings = build_amqp_settings(&args, &file_config, engine_id, result_exchange, prefetch)?;
+    let backoff = ReconnectBackoff::from_env()?;
+    let breaker = CircuitBreaker::from_env()?.map(Arc::new);
+
+    // Deliveries from every session share one set so shutdown drains them all.
+    let mut in_flight = JoinSet::new();
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut draining = false;
+    let mut attempt = 0u32;
+    // Set once the single delivery of `--once` has been handled.
+    let mut once_succeeded: Option<bool> = None;
+
+    'session: loop {
+        let session = match open_session(&settings).await {
+            Ok(session) => {
+                attempt = 0;
+                session
+            }
+            Err(err) if err.is::<WorkerConfigError>() => return Err(err),
+            Err(err) => {
+                let delay = backoff.delay(attempt);
+                attempt = attempt.saturating_add(1);
+                warn!(
+                    engine_id,
+                    error = %err,
+                    retry_in_ms = delay.as_millis() as u64,
+                    "failed to connect to RabbitMQ"
+                );
+                tokio::select! {
+                    _ = &mut shutdown => {
+                        draining = true;
+                        break 'session;
+                    }
+                    _ = tokio::time::sleep(delay) => continue 'session,
+                }
+            }
+        };
+
+        let AmqpSession {
+            connection,
+            channel,
+            mut consumer,
+            control,
+        } = session;
+
+        if let Some(control) = control {
+            // Ends by itself when the session's channel closes.
+            tokio::spawn(watch_cancellations(Arc::clone(&context), control));
+        }
+
+        if let Some(exchange) = &settings.announce_exchange {
+            for &id in engine_ids.as_slice() {
+                announce.engine_id = id;
+                // Each session announces afresh, so the id must not repeat.
+                let message_id = format!("{}:announce:{}", id, Uuid::new_v4());
+                if let Err(err) = publish_message(
+                    &channel,
+                    exchange,
+                    &id.to_string(),
+                    &message_id,
+                    &announce,
+                    None,
+                )
+                .await
+                {
+                    warn!(engine_id = id, error = %err, "failed to publish worker announcement");
+                }
+            }
+        }
+
+        readiness.set(true);
+        progress_channel.send_replace(Some(channel.clone()));
+        info!(
+            engine_id,
+            engine_ids = %engine_ids,
+            queues = ?settings.queue_names,
+            prefetch = settings.prefetch,
+            "worker listening"
+        );
+
+        // The broker never has more than `prefetch` unacked deliveries outstanding
+        // per queue, so spawning one task per delivery bounds concurrency to the
+        // prefetch count times the number of queues. Each task settles its own
+        // delivery, so results may complete out of order.
+        loop {
+            let delivery = tokio::select! {
+                signal = &mut shutdown => {
+                    if let Err(err) = signal {
+                        error!(engine_id, error = %err, "failed to listen for shutdown signals");
+                    }
+                    draining = true;
+                    break;
+                }
+                _ = CircuitBreaker::wait_for_trip(breaker.as_deref()) => None,
+                delivery = consumer.next() => match delivery {
+                    Some(delivery) => Some(delivery),
+                    None => break,
+                },
+            };
+
+            let Some(delivery) = delivery else {
+                let cooldown = breaker
+                    .as_ref()
+                    .map(|breaker| breaker.cooldown)
+                    .unwrap_or_default();
+                error!(
+                    engine_id,
+                    cooldown_ms = cooldown.as_millis() as u64,
+                    "circuit breaker tripped after repeated task failures; pausing consumption"
+                );
+                readiness.set(false);
+                match pause_consumption(
+                    &channel,
+                    &settings,
+                    &mut consumer,
+                    cooldown,
+                    shutdown.as_mut(),
+                )
+                .await
+                {
+                    Ok(true) => {
+                        draining = true;
+                        break;
+                    }
+                    Ok(false) => {
+                        if let Some(breaker) = &breaker {
+                            breaker.reset();
+                        }
+                        readiness.set(true);
+                        warn!(
+                            engine_id,
+                            "circuit breaker cooldown over, resuming consumption"
+                        );
+                        continue;
+                    }
+                    Err(err) => {
+                        error!(engine_id, error = %err, "failed to resume consumption");
+                        break;
+                    }
+                }
+            };
+
+            while in_flight.try_join_next().is_some() {}
+
+            match delivery {
+                Ok(delivery) if args.once => {
+                    let outcome = match handle_delivery(&context, &channel, delivery).await {
+                        Ok(outcome) => outcome,
+                        Err(err) => {
+                            error!(engine_id, error = %err, "failed to settle delivery");
+                            DeliveryOutcome::Failed
+                        }
+                    };
+                    once_succeeded = Some(outcome == DeliveryOutcome::Succeeded);
+                    draining = true;
+                    break;
+                }
+                Ok(delivery) => {
+                    let context = Arc::clone(&context);
+                    let channel = channel.clone();
+                    let breaker = breaker.clone();
+                    in_flight.spawn(async move {
+                        let outcome = match handle_delivery(&context, &channel, delivery).await {
+                            Ok(outcome) => outcome,
+                            Err(err) => {
+                                error!(engine_id, error = %err, "failed to settle delivery");
+                                DeliveryOutcome::Failed
+                            }
+                        };
+                        if let Some(breaker) = breaker {
+                            breaker.record(outcome);
+                        }
+                    });
+                }
+                Err(err) => {
+                    error!(engine_id, error = %err, "consumer error");
+                    if !connection.status().connected() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        readiness.set(false);
+
+        if !draining {
+            // Tasks still running on the lost channel finish on their own; their
+            // deliveries are redelivered by the broker and their acks fail harmlessly.
+            warn!(engine_id, "lost connection to RabbitMQ, reconnecting");
+            progress_channel.send_replace(None);
+            close_session(Some(&channel), &connection, "reconnecting").await;
+            continue 'session;
+        }
+
+        if once_succeeded.is_none() {
+            info!(
+                engine_id,
+                in_flight = in_flight.len(),
+                "shutdown requested, cancelling in-flight tasks; signal again to force exit"
+            );
+            context.running.cancel_all();
+        }
+
+        tokio::spawn(async move {
+            if shutdown_signal().await.is_ok() {
+                warn!(engine_id, "second shutdown signal, forcing exit");
+                std::process::exit(130);
+            }
+        });
+
+        // Stop the broker from pushing further deliveries; anything prefetched but
+        // not yet started is requeued when the channel closes.
+        for queue in &settings.queue_names {
+            if let Err(err) = channel
+                .basic_cancel(&settings.consumer_tag(queue), BasicCancelOptions::default())
+                .await
+            {
+                error!(engine_id, queue = %queue, error = %err, "failed to cancel consumer");
+            }
+        }
+
+        while in_flight.join_next().await.is_some() {}
+
+        progress_channel.send_replace(None);
+        channel.close(0, "worker shutting down").await?;
+        connection.close(0, "worker shutting down").await?;
+        break;
+    }
+
+    if draining {
+        context.running.cancel_all();
+    }
+    while in_flight.join_next().await.is_some() {}
+
+    if let Some(handle) = health_server {
+        handle.abort();
+    }
+    if let Some(handle) = metrics_server {
+        handle.abort();
+    }
+
+    match once_succeeded {
+        Some(true) => info!(engine_id, "--once task succeeded, exiting"),
+        Some(false) => return Err("the --once task failed".into()),
+        None if draining => info!(engine_id, "drained, exiting"),
+        None => {}
+    }
+
+    Ok(())
+}
+
+/// Per-worker state shared by every delivery handler.
+struct WorkerContext {
+    engine: Arc<dyn TtsEngine>,
+    /// The first of `engine_ids`, for the worker's own logs.
+    engine_id: u32,
+    /// Ids deliveries are reported under, assigned round-robin; they all share
+    /// `engine` and its loaded models.
+    engine_ids: Vec<u32>,
+    next_engine_id: AtomicUsize,
+    /// `None` when result publishing is disabled.
+    result_exchange: Option<String>,
+    result_routing_key: amqp::ResultRoutingKey,
+    task_timeout: Duration,
+    journal: Option<TaskJournal>,
+    cancelled: CancelledEvals,
+    stats: TaskStats,
+    in_progress: Arc<TasksInProgress>,
+    running: RunningTasks,
+    /// Nack unreadable payloads without requeue, dead-lettering them when a
+    /// dead-letter exchange is configured, instead of acking them.
+    dead_letter_invalid_payloads: bool,
+}
+
+impl WorkerContext {
+    /// The engine id to process and report the next delivery under.
+    fn assign_engine_id(&self) -> u32 {
+        let next = self.next_engine_id.fetch_add(1, Ordering::Relaxed);
+        self.engine_ids[next % self.engine_ids.len()]
+    }
+
+    /// Appends to the task journal when one is configured. A journal write
+    /// failure is logged but never fails the task.
+    async fn journal(
+        &self,
+        engine_id: u32,
+        event: JournalEvent,
+        eval_id: &str,
+        task_id: &str,
+        error: Option<&str>,
+    ) {
+        let Some(journal) = &self.journal else {
+            return;
+        };
+        if let Err(err) = journal
+            .record(event, eval_id, task_id, engine_id, error)
+            .await
+        {
+            warn!(
+                error = %err,
+                path = %journal.path().display(),
+                "failed to write task journal"
+            );
+        }
+    }
+}
+
+struct AmqpSettings {
+    connection: amqp::ConnectionConfig,
+    /// Task queues to consume, in the order given; never empty.
+    queue_names: Vec<String>,
+    result_exchange: Option<String>,
+    dead_letter: Option<DeadLetterSettings>,
+    max_priority: Option<u8>,
+    announce_exchange: Option<String>,
+    /// Fanout exchange carrying `CancelMessage`s; every worker gets its own
+    /// queue on it.
+    control_exchange: Option<String>,
+    prefetch: u16,
+    engine_id: u32,
+}
+
+impl AmqpSettings {
+    /// Consumer tags must be unique per channel, so each queue gets its own.
+    /// A single queue keeps the plain `vvx-worker-<id>` tag.
+    fn consumer_tag(&self, queue: &str) -> String {
+        if self.queue_names.len() == 1 {
+            format!("vvx-worker-{}", self.engine_id)
+        } else {
+            format!("vvx-worker-{}-{}", self.engine_id, queue)
+        }
+    }
+}
+
+/// Where failed tasks are routed when they are nacked without requeue.
+struct DeadLetterSettings {
+    exchange: String,
+    queue: String,
+}
+
+struct AmqpSession {
+    connection: Connection,
+    channel: Channel,
+    /// Deliveries from every task queue, merged.
+    consumer: SelectAll<Consumer>,
+    /// Cancellations, when a control exchange is configured.
+    control: Option<Consumer>,
+}
+
+/// Evaluations cancelled through the control exchange. Entries are forgotten
+/// after `ttl` so the set stays small on a long-running worker.
+struct CancelledEvals {
+    ttl: Duration,
+    evals: Mutex<HashMap<String, Instant>>,
+    changed: Notify,
+}
+
+impl CancelledEvals {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            evals: Mutex::new(HashMap::new()),
+            changed: Notify::new(),
+        }
+    }
+
+    fn insert(&self, eval_id: String) {
+        {
+            let mut evals = self.evals.lock().unwrap_or_else(|err| err.into_inner());
+            evals.retain(|_, cancelled_at| cancelled_at.elapsed() < self.ttl);
+            evals.insert(eval_id, Instant::now());
+        }
+        self.changed.notify_waiters();
+    }
+
+    fn contains(&self, eval_id: &str) -> bool {
+        let evals = self.evals.lock().unwrap_or_else(|err| err.into_inner());
+        evals
+            .get(eval_id)
+            .is_some_and(|cancelled_at| cancelled_at.elapsed() < self.ttl)
+    }
+
+    /// Resolves once `eval_id` is cancelled.
+    async fn wait(&self, eval_id: &str) {
+        loop {
+            // Registered before the check so an insert in between is not missed.
+            let changed = self.changed.notified();
+            if self.contains(eval_id) {
+                return;
+            }
+            changed.await;
+        }
+    }
+}
+
+/// `eval_id:task_id` of every task being processed. A redelivered copy of a
+/// task that is still running waits for it instead of writing the same
+/// output at the same time.
+///
+/// Claims are handed to the task's [`CancellationToken`] with
+/// [`CancellationToken::hold`], so a task that timed out or was cancelled
+/// keeps its claim until any blocking synthesis still writing its output has
+/// returned.
+#[derive(Default)]
+struct TasksInProgress {
+    keys: Mutex<HashSet<String>>,
+    released: Notify,
+}
+
+impl TasksInProgress {
+    /// Waits until no other delivery holds `key`, then holds it until the
+    /// returned guard is dropped.
+    async fn claim(self: &Arc<Self>, key: String) -> TaskClaim {
+        let mut waited = false;
+        loop {
+            // Registered before the check so a release in between is not missed.
+            let released = self.released.notified();
+            {
+                let mut keys = self.keys.lock().unwrap_or_else(|err| err.into_inner());
+                if !keys.contains(&key) {
+                    keys.insert(key.clone());
+                    return TaskClaim {
+                        tasks: Arc::clone(self),
+                        key,
+                    };
+                }
+            }
+            if !waited {
+                info!("same task already in progress, waiting for it to finish");
+                waited = true;
+            }
+            released.await;
+        }
+    }
+
+    /// Claims every key of a batch. Keys are taken in sorted order, so two
+    /// batches sharing tasks cannot each hold a key the other waits for.
+    async fn claim_all(self: &Arc<Self>, mut keys: Vec<String>) -> Vec<TaskClaim> {
+        keys.sort();
+        keys.dedup();
+        let mut claims = Vec::with_capacity(keys.len());
+        for key in keys {
+            claims.push(self.claim(key).await);
+        }
+        claims
+    }
+}
+
+/// Releases its task key on drop.
+struct TaskClaim {
+    tasks: Arc<TasksInProgress>,
+    key: String,
+}
+
+impl Drop for TaskClaim {
+    fn drop(&mut self) {
+        self.tasks
+            .keys
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .remove(&self.key);
+        self.tasks.released.notify_waiters();
+    }
+}
+
+/// Cancellation tokens of the deliveries being processed, so shutdown can
+/// stop all of them at their next check.
+#[derive(Default)]
+struct RunningTasks {
+    next_id: AtomicU64,
+    tokens: Mutex<HashMap<u64, CancellationToken>>,
+    stopping: AtomicBool,
+}
+
+impl RunningTasks {
+    /// A fresh token, tripped by [`RunningTasks::cancel_all`] until the
+    /// returned guard is dropped. Already tripped once shutdown has begun.
+    fn start(&self) -> RunningTask<'_> {
+        let token = CancellationToken::new();
+        if self.is_stopping() {
+            token.cancel();
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.tokens
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .insert(id, token.clone());
+        RunningTask {
+            tasks: self,
+            id,
+            token,
+        }
+    }
+
+    fn cancel_all(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        let tokens = self.tokens.lock().unwrap_or_else(|err| err.into_inner());
+        for token in tokens.values() {
+            token.cancel();
+        }
+    }
+
+    /// Whether a cancelled task was stopped by shutdown rather than by its
+    /// timeout or its evaluation being cancelled.
+    fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::Relaxed)
+    }
+}
+
+/// Unregisters its token on drop.
+struct RunningTask<'a> {
+    tasks: &'a RunningTasks,
+    id: u64,
+    token: CancellationToken,
+}
+
+impl Drop for RunningTask<'_> {
+    fn drop(&mut self) {
+        self.tasks
+            .tokens
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .remove(&self.id);
+    }
+}
+
+/// Records every `CancelMessage` from the control queue.
+async fn watch_cancellations(context: Arc<WorkerContext>, mut control: Consumer) {
+    let engine_id = context.engine_id;
+    while let Some(delivery) = control.next().await {
+        let delivery = match delivery {
+            Ok(delivery) => delivery,
+            Err(err) => {
+                warn!(engine_id, error = %err, "control consumer error");
+                break;
+            }
+        };
+        match serde_json::from_slice::<CancelMessage>(&delivery.data) {
+            Ok(cancel) if !cancel.eval_id.is_empty() => {
+                info!(engine_id, eval_id = %cancel.eval_id, "evaluation cancelled");
+                context.cancelled.insert(cancel.eval_id);
+            }
+            Ok(_) => warn!(engine_id, "cancel message without eval_id"),
+            Err(err) => warn!(engine_id, error = %err, "invalid cancel message"),
+        }
+    }
+}
+
+/// Counters behind the periodic throughput summary. Each report takes the
+/// counts since the previous one and resets them; `total` keeps running for
+/// the life of the process.
+#[derive(Default)]
+struct TaskStats {
+    succeeded: AtomicU64,
+    failed: AtomicU64,
+    /// Tasks that reported a synthesis time, and the sum of those times.
+    timed: AtomicU64,
+    synthesis_ms: AtomicU64,
+    total: AtomicU64,
+}
+
+impl TaskStats {
+    fn record(&self, success: bool, synthesis_ms: Option<u64>) {
+        if success {
+            self.succeeded.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(ms) = synthesis_ms {
+            self.timed.fetch_add(1, Ordering::Relaxed);
+            self.synthesis_ms.fetch_add(ms, Ordering::Relaxed);
+        }
+        self.total.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Logs throughput, success rate and average synthesis time every `interval`.
+async fn log_stats(context: Arc<WorkerContext>, interval: Duration) {
+    let engine_id = context.engine_id;
+    let stats = &context.stats;
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    let mut since = Instant::now();
+
+    loop {
+        ticker.tick().await;
+        let minutes = since.elapsed().as_secs_f64() / 60.0;
+        since = Instant::now();
+
+        let succeeded = stats.succeeded.swap(0, Ordering::Relaxed);
+        let failed = stats.failed.swap(0, Ordering::Relaxed);
+        let timed = stats.timed.swap(0, Ordering::Relaxed);
+        let synthesis_ms = stats.synthesis_ms.swap(0, Ordering::Relaxed);
+        let total = stats.total.load(Ordering::Relaxed);
+        let tasks = succeeded + failed;
+
+        if tasks == 0 {
+            info!(engine_id, total, "no tasks since last report");
+            continue;
+        }
+        info!(
+            engine_id,
+            tasks,
+            failed,
+            total,
+            tasks_per_min = format!("{:.1}", tasks as f64 / minutes).as_str(),
+            success_rate = format!("{:.3}", succeeded as f64 / tasks as f64).as_str(),
+            avg_synthesis_ms = (timed > 0).then(|| synthesis_ms / timed),
+            "throughput"
+        );
+    }
+}
+
+/// Turns engine stage reports into `TaskProgressMessage`s for the result exchange.
+struct ProgressPublisher {
+    engine_id: u32,
+    sender: mpsc::UnboundedSender<TaskProgressMessage>,
+}
+
+impl ProgressSink for ProgressPublisher {
+    fn report(&self, eval_id: &str, task_id: &str, stage: TaskStage) {
+        let _ = self.sender.send(TaskProgressMessage {
+            eval_id: eval_id.to_string(),
+            task_id: task_id.to_string(),
+            engine_id: self.engine_id,
+            stage,
+        });
+    }
+}
+
+/// Spawns the task that publishes progress in order on the current channel.
+/// Reports raised while disconnected are dropped.
+fn spawn_progress_publisher(
+    engine_id: u32,
+    exchange: String,
+    channel: watch::Receiver<Option<Channel>>,
+) -> Arc<ProgressPublisher> {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<TaskProgressMessage>();
+
+    tokio::spawn(async move {
+        while let Some(progress) = receiver.recv().await {
+            let current = channel.borrow().clone();
+            let Some(current) = current else {
+                continue;
+            };
+            // A separate key keeps heartbeats out of queues bound to `<eval_id>`
+            // for results.
+            let routing_key = format!("{}.progress", progress.eval_id);
+            let message_id = format!(
+                "{}:{}:{}",
+                progress.eval_id,
+                progress.task_id,
+                progress.stage.as_str()
+            );
+            if let Err(err) = publish_message(
+                &current,
+                &exchange,
+                &routing_key,
+                &message_id,
+                &progress,
+                None,
+            )
+            .await
+            {
+                warn!(engine_id, error = %err, "failed to publish progress");
+            }
+        }
+    });
+
+    Arc::new(ProgressPublisher { engine_id, sender })
+}
+
+/// Connects and declares the queue, result exchange, QoS and consumer.
+async fn open_session(settings: &AmqpSettings) -> WorkerResult<AmqpSession> {
+    let connection = settings
+        .connection
+        .connect(&format!("vvx-worker-{}", settings.engine_id))
+        .await?;
+    match setup_session(&connection, settings).await {
+        Ok((channel, consumer, control)) => Ok(AmqpSession {
+            connection,
+            channel,
+            consumer,
+            control,
+        }),
+        Err(err) => {
+            close_session(None, &connection, "session setup failed").await;
+            Err(err)
+        }
+    }
+}
+
+/// Closes what is left of a session before a new one is opened, so a
+/// half-open connection does not linger next to its replacement. Either may
+/// already be closed, in which case closing it fails harmlessly.
+async fn close_session(channel: Option<&Channel>, connection: &Connection, reason: &str) {
+    if let Some(channel) = channel {
+        if channel.status().connected() {
+            if let Err(err) = channel.close(0, reason).await {
+                warn!(error = %err, "failed to close channel");
+            }
+        }
+    }
+    if connection.status().connected() {
+        if let Err(err) = connection.close(0, reason).await {
+            warn!(error = %err, "failed to close connection");
+        }
+    }
+}
+
+/// Declares the topology on a fresh channel of `connection` and starts
+/// consuming.
+async fn setup_session(
+    connection: &Connection,
+    settings: &AmqpSettings,
+) -> WorkerResult<(Channel, SelectAll<Consumer>, Option<Consumer>)> {
+    let channel = connection.create_channel().await?;
+
+    if let Some(dead_letter) = &settings.dead_letter {
+        channel
+            .exchange_declare(
+                &dead_letter.exchange,
+                ExchangeKind::Fanout,
+                ExchangeDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        channel
+            .queue_declare(
+                &dead_letter.queue,
+                QueueDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        channel
+            .queue_bind(
+                &dead_letter.queue,
+                &dead_letter.exchange,
+                "",
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+    }
+
+    let dead_letter_exchange = settings
+        .dead_letter
+        .as_ref()
+        .map(|dead_letter| dead_letter.exchange.as_str());
+    for queue in &settings.queue_names {
+        if let Err(err) = channel
+            .queue_declare(
+                queue,
+                QueueDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                amqp::task_queue_arguments(dead_letter_exchange, settings.max_priority),
+            )
+            .await
+        {
+            // Reconnecting would only be refused again, so this ends the worker.
+            if amqp::is_precondition_failed(&err) {
+                return Err(Box::new(WorkerConfigError(format!(
+                    "task queue {} exists with different arguments; delete and recreate it (or adjust DLX_EXCHANGE / TASK_QUEUE_MAX_PRIORITY) so the arguments match: {}",
+                    queue, err
+                ))) as Box<dyn Error + Send + Sync>);
+            }
+            return Err(err.into());
+        }
+    }
+
+    if let Some(exchange) = &settings.result_exchange {
+        channel
+            .exchange_declare(
+                exchange,
+                ExchangeKind::Topic,
+                ExchangeDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+    }
+
+    if let Some(exchange) = &settings.announce_exchange {
+        channel
+            .exchange_declare(
+                exchange,
+                ExchangeKind::Fanout,
+                ExchangeDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+    }
+
+    channel
+        .basic_qos(
+            settings.prefetch,
+            BasicQosOptions {
+                global: false,
+                ..Default::default()
+            },
+        )
+        .await?;
+
+    let consumer = start_consumers(&channel, settings).await?;
+
+    let control = match &settings.control_exchange {
+        Some(exchange) => Some(start_control_consumer(&channel, settings, exchange).await?),
+        None => None,
+    };
+
+    Ok((channel, consumer, control))
+}
+
+/// Starts one consumer per task queue and merges their deliveries.
+async fn start_consumers(
+    channel: &Channel,
+    settings: &AmqpSettings,
+) -> lapin::Result<SelectAll<Consumer>> {
+    let mut consumers = Vec::with_capacity(settings.queue_names.len());
+    for queue in &settings.queue_names {
+        consumers.push(
+            channel
+                .basic_consume(
+                    queue,
+                    &settings.consumer_tag(queue),
+                    BasicConsumeOptions::default(),
+                    FieldTable::default(),
+                )
+                .await?,
+        );
+    }
+    Ok(stream::select_all(consumers))
+}
+
+/// Binds a private, auto-deleted queue to the control exchange, so every
+/// worker sees every cancellation, and consumes it without acks.
+async fn start_control_consumer(
+    channel: &Channel,
+    settings: &AmqpSettings,
+    exchange: &str,
+) -> lapin::Result<Consumer> {
+    channel
+        .exchange_declare(
+            exchange,
+            ExchangeKind::Fanout,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+    let queue = channel
+        .queue_declare(
+            "",
+            QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+    channel
+        .queue_bind(
+            queue.name().as_str(),
+            exchange,
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+    channel
+        .basic_consume(
+            queue.name().as_str(),
+            &format!("vvx-worker-{}-control", settings.engine_id),
+            BasicConsumeOptions {
+                no_ack: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await
+}
+
+/// Cancels every consumer, requeues deliveries that were already prefetched,
+/// waits out `cooldown` and then consumes again. Returns `true` if shutdown was
+/// requested in the meantime, in which case consumption is not restarted.
+async fn pause_consumption<F>(
+    channel: &Channel,
+    settings: &AmqpSettings,
+    consumer: &mut SelectAll<Consumer>,
+    cooldown: Duration,
+    mut shutdown: Pin<&mut F>,
+) -> WorkerResult<bool>
+where
+    F: Future<Output = io::Result<()>>,
+{
+    for queue in &settings.queue_names {
+        channel
+            .basic_cancel(&settings.consumer_tag(queue), BasicCancelOptions::default())
+            .await?;
+    }
+
+    let resume = tokio::time::sleep(cooldown);
+    tokio::pin!(resume);
+    let mut exhausted = false;
+    loop {
+        tokio::select! {
+            _ = &mut resume => break,
+            _ = shutdown.as_mut() => return Ok(true),
+            delivery = consumer.next(), if !exhausted => match delivery {
+                Some(Ok(delivery)) => {
+                    delivery
+                        .nack(BasicNackOptions {
+                            requeue: true,
+                            multiple: false,
+                        })
+                        .await?;
+                }
+                Some(Err(err)) => return Err(err.into()),
+                None => exhausted = true,
+            },
+        }
+    }
+
+    *consumer = start_consumers(channel, settings).await?;
+    Ok(false)
+}
+
+/// Pauses consumption after `threshold` consecutive task failures, the first
+/// and last of them no more than `window` apart. Any success resets the count;
+/// tasks rejected for bad input neither count nor reset it.
+struct CircuitBreaker {
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    /// Current run of failures and when it started.
+    streak: Mutex<Option<(u32, Instant)>>,
+    /// Set on a trip until the consumer loop takes it, so a trip while the
+    /// loop is busy is not lost.
+    tripped: AtomicBool,
+    trip: Notify,
+}
+
+impl CircuitBreaker {
+    /// Enabled by a non-zero `CIRCUIT_BREAKER_FAILURES`.
+    fn from_env() -> WorkerResult<Option<Self>> {
+        let threshold: u32 = env_parse("CIRCUIT_BREAKER_FAILURES", 0)?;
+        if threshold == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            threshold,
+            window: Duration::from_millis(env_parse("CIRCUIT_BREAKER_WINDOW_MS", 60_000)?),
+            cooldown: Duration::from_millis(env_parse("CIRCUIT_BREAKER_COOLDOWN_MS", 30_000)?),
+            streak: Mutex::new(None),
+            tripped: AtomicBool::new(false),
+            trip: Notify::new(),
+        }))
+    }
+
+    fn record(&self, outcome: DeliveryOutcome) {
+        let mut streak = self
+            .streak
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match outcome {
+            DeliveryOutcome::Succeeded => *streak = None,
+            DeliveryOutcome::Rejected | DeliveryOutcome::Cancelled => {}
+            DeliveryOutcome::Failed => {
+                let now = Instant::now();
+                let (count, started) = match *streak {
+                    Some((count, started)) if now.duration_since(started) <= self.window => {
+                        (count + 1, started)
+                    }
+                    _ => (1, now),
+                };
+                if count >= self.threshold {
+                    *streak = None;
+                    self.tripped.store(true, Ordering::SeqCst);
+                    self.trip.notify_waiters();
+                } else {
+                    *streak = Some((count, started));
+                }
+            }
+        }
+    }
+
+    /// Resolves when the breaker trips; never, when there is no breaker.
+    async fn wait_for_trip(breaker: Option<&Self>) {
+        let Some(breaker) = breaker else {
+            return std::future::pending().await;
+        };
+        loop {
+            // Registered before the check so a trip in between is not missed.
+            let trip = breaker.trip.notified();
+            if breaker.tripped.swap(false, Ordering::SeqCst) {
+                return;
+            }
+            trip.await;
+        }
+    }
+
+    /// Forgets failures and trips recorded during a cooldown, from tasks that
+    /// were already running when consumption paused, so they cannot trip the
+    /// breaker again the moment consumption resumes.
+    fn reset(&self) {
+        *self
+            .streak
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+        self.tripped.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Capped exponential backoff with equal jitter between reconnect attempts.
+struct ReconnectBackoff {
+    base: Duration,
+    max: Duration,
+}
+
+impl ReconnectBackoff {
+    fn from_env() -> WorkerResult<Self> {
+        Ok(Self {
+            base: Duration::from_millis(env_parse("AMQP_RECONNECT_BASE_MS", 500)?),
+            max: Duration::from_millis(env_parse("AMQP_RECONNECT_MAX_MS", 30_000)?),
+        })
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base
+            .saturating_mul(2u32.saturating_pow(attempt.min(16)))
+            .min(self.max);
+        let half = exponential / 2;
+        half + half.mul_f64(rand::random::<f64>())
+    }
+}
+
+fn env_parse<T: FromStr>(name: &str, default: T) -> WorkerResult<T> {
+    match env::var(name) {
+        Ok(value) => value.parse::<T>().map_err(|_| {
+            Box::new(WorkerConfigError(format!("invalid {} '{}'", name, value)))
+                as Box<dyn Error + Send + Sync>
+        }),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Installs the global subscriber: `RUST_LOG` filters (default `info`) and
+/// `LOG_FORMAT=json` switches from human-readable lines to JSON.
+fn init_tracing() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match env::var("LOG_FORMAT") {
+        Ok(format) if format.eq_ignore_ascii_case("json") => builder.json().init(),
+        _ => builder.init(),
+    }
+}
+
+/// Resolves on SIGINT or, on unix, SIGTERM.
+async fn shutdown_signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await
+    }
+}
+
+/// How a settled delivery turned out, for `--once` and the circuit breaker.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DeliveryOutcome {
+    /// The task, or every item of the batch, succeeded.
+    Succeeded,
+    /// The message itself was at fault: an unparseable payload or a task
+    /// rejected as `invalid_task`.
+    Rejected,
+    /// Synthesis or publishing failed.
+    Failed,
+    /// The task's evaluation was cancelled, so it was dropped without a
+    /// result, or shutdown stopped it and it was requeued.
+    Cancelled,
+}
+
+/// Settles one delivery and reports how it went.
+async fn handle_delivery(
+    context: &WorkerContext,
+    channel: &Channel,
+    delivery: Delivery,
+) -> WorkerResult<DeliveryOutcome> {
+    let engine_id = context.engine_id;
+    // On failure, keep the payload as loose JSON so `salvage_ids` can read it
+    // without decoding the body again.
+    let parsed = match amqp::decode_payload(&delivery.data, &delivery.properties) {
+        Ok(data) => serde_json::from_slice::<TaskPayload>(&data).map_err(|err| {
+            let value = serde_json::from_slice(&data).unwrap_or_default();
+            (err.to_string(), value)
+        }),
+        Err(err) => Err((err.to_string(), serde_json::Value::Null)),
+    };
+    let payload = match parsed {
+        Ok(payload) => payload,
+        Err((err, value)) => return reject_payload(context, channel, delivery, &value, err).await,
+    };
+
+    if context.cancelled.contains(payload.eval_id()) {
+        info!(
+            engine_id,
+            eval_id = payload.eval_id(),
+            "skipping task of cancelled evaluation"
+        );
+        delivery.ack(BasicAckOptions::default()).await?;
+        return Ok(DeliveryOutcome::Cancelled);
+    }
+
+    let engine_id = context.assign_engine_id();
+    let running = context.running.start();
+    let cancel = &running.token;
+    let task = match payload {
+        TaskPayload::Task(message) => message,
+        TaskPayload::Batch(batch) => {
+            let span = info_span!(
+                "batch",
+                eval_id = %batch.eval_id,
+                engine_id,
+                items = batch.items.len(),
+                trace_id = field::Empty,
+                span_id = field::Empty,
+            );
+            return process_batch_delivery(context, channel, delivery, engine_id, batch, cancel)
+                .instrument(span)
+                .await;
+        }
+        TaskPayload::Dialogue(dialogue) => {
+            let task = dialogue.task_message();
+            let span = info_span!(
+                "dialogue",
+                eval_id = %task.eval_id,
+                task_id = %task.task_id,
+                engine_id,
+                segments = dialogue.segments.len(),
+                trace_id = field::Empty,
+                span_id = field::Empty,
+            );
+            // Each segment is a synthesis of its own, so the whole dialogue
+            // gets the per-task timeout once per segment.
+            let timeout = context
+                .task_timeout
+                .saturating_mul(dialogue.segments.len().max(1) as u32);
+            let work = context
+                .engine
+                .process_dialogue(engine_id, &dialogue, cancel);
+            return process_delivery(
+                context, channel, delivery, engine_id, &task, timeout, cancel, work,
+            )
+            .instrument(span)
+            .await;
+        }
+    };
+
+    let span = info_span!(
+        "task",
+        eval_id = %task.eval_id,
+        task_id = %task.task_id,
+        engine_id,
+        speaker_id = task.speaker_id,
+        trace_id = field::Empty,
+        span_id = field::Empty,
+    );
+
+    let work = context.engine.process_task(engine_id, &task, cancel);
+    process_delivery(
+        context,
+        channel,
+        delivery,
+        engine_id,
+        &task,
+        context.task_timeout,
+        cancel,
+        work,
+    )
+    .instrument(span)
+    .await
+}
+
+/// Runs `work` for `task` under `timeout`, publishes its result and settles the
+/// delivery. `cancel` is the token `work` was started with; it is tripped when
+/// the task is abandoned or the worker shuts down. A task stopped by shutdown
+/// is requeued without a result, for another worker to pick up.
+#[allow(clippy::too_many_arguments)]
+async fn process_delivery(
+    context: &WorkerContext,
+    channel: &Channel,
+    delivery: Delivery,
+    engine_id: u32,
+    task: &TaskMessage,
+    timeout: Duration,
+    cancel: &CancellationToken,
+    work: impl Future<Output = EngineResult<TaskOutput>>,
+) -> WorkerResult<DeliveryOutcome> {
+    info!("task received");
+    if task.schema_version > SCHEMA_VERSION {
+        warn!(
+            schema_version = task.schema_version,
+            supported = SCHEMA_VERSION,
+            "task uses a newer message schema; unknown fields are ignored"
+        );
+    }
+    let claim = context
+        .in_progress
+        .claim(format!("{}:{}", task.eval_id, task.task_id))
+        .await;
+    cancel.hold(claim);
+    let trace = continue_trace(TraceContext::from_task(task, &delivery.properties));
+    context
+        .journal(
+            engine_id,
+            JournalEvent::Received,
+            &task.eval_id,
+            &task.task_id,
+            None,
+        )
+        .await;
+
+    if let (true, Some(exchange)) = (task.ack_start, &context.result_exchange) {
+        let started = TaskProgressMessage {
+            eval_id: task.eval_id.clone(),
+            task_id: task.task_id.clone(),
+            engine_id,
+            stage: TaskStage::Started,
+        };
+        let routing_key = format!("{}.started", task.eval_id);
+        let message_id = format!("{}:{}:started", task.eval_id, task.task_id);
+        if let Err(err) = publish_message(
+            channel,
+            exchange,
+            &routing_key,
+            &message_id,
+            &started,
+            trace.as_ref(),
+        )
+        .await
+        {
+            warn!(error = %err, "failed to publish task start");
+        }
+    }
+
+    // Dropping the future on timeout detaches any `spawn_blocking` synthesis
+    // rather than killing it. Tripping `cancel` makes the blocking thread stop
+    // at its next check, but a VOICEVOX call already running cannot be
+    // interrupted, so that pool member stays busy until the call returns and
+    // the result is discarded. A cancellation of the task's evaluation
+    // abandons it the same way.
+    let outcome = tokio::select! {
+        outcome = tokio::time::timeout(timeout, work) => outcome,
+        _ = context.cancelled.wait(&task.eval_id) => {
+            cancel.cancel();
+            info!("evaluation cancelled, abandoning task");
+            context
+                .journal(engine_id, JournalEvent::Cancelled, &task.eval_id, &task.task_id, None)
+                .await;
+            delivery.ack(BasicAckOptions::default()).await?;
+            return Ok(DeliveryOutcome::Cancelled);
+        }
+    };
+    if matches!(outcome, Ok(Err(EngineError::Cancelled))) && context.running.is_stopping() {
+        return requeue_on_shutdown(
+            context,
+            delivery,
+            engine_id,
+            &task.eval_id,
+            &[task.task_id.as_str()],
+        )
+        .await;
+    }
+    let result_message = match outcome {
+        Ok(outcome) => runner::task_result(engine_id, task, outcome),
+        Err(_) => {
+            cancel.cancel();
+            runner::failed_result(
+                engine_id,
+                task,
+                format!("synthesis timed out after {} ms", timeout.as_millis()),
+                "timeout",
+            )
+        }
+    };
+
+    metrics::record_task(result_message.success);
+    context
+        .stats
+        .record(result_message.success, result_message.synthesis_ms);
+
+    let published = match &context.result_exchange {
+        Some(exchange) => {
+            publish_result(
+                channel,
+                exchange,
+                &context.result_routing_key,
+                &result_message,
+                trace.as_ref(),
+            )
+            .await
+        }
+        None => Ok(()),
+    };
+    if let Err(err) = published {
+        error!(error = %err, "failed to publish result, requeueing task");
+        context
+            .journal(
+                engine_id,
+                JournalEvent::Requeued,
+                &task.eval_id,
+                &task.task_id,
+                Some(&err.to_string()),
+            )
+            .await;
+        delivery
+            .nack(BasicNackOptions {
+                requeue: true,
+                multiple: false,
+            })
+            .await?;
+        return Ok(DeliveryOutcome::Failed);
+    }
+
+    context
+        .journal(
+            engine_id,
+            if result_message.success {
+                JournalEvent::Completed
+            } else {
+                JournalEvent::Failed
+            },
+            &task.eval_id,
+            &task.task_id,
+            result_message.error.as_deref(),
+        )
+        .await;
+
+    if result_message.success {
+        info!(
+            output_file = result_message.output_file.as_deref(),
+            "task completed"
+        );
+        delivery.ack(BasicAckOptions::default()).await?;
+    } else {
+        error!(
+            error = result_message.error.as_deref().unwrap_or("unknown error"),
+            "task failed"
+        );
+        delivery
+            .nack(BasicNackOptions {
+                requeue: false,
+                multiple: false,
+            })
+            .await?;
+    }
+
+    Ok(if result_message.success {
+        DeliveryOutcome::Succeeded
+    } else if result_message.error_kind.as_deref() == Some("invalid_task") {
+        DeliveryOutcome::Rejected
+    } else {
+        DeliveryOutcome::Failed
+    })
+}
+
+/// Requeues a delivery whose work was cancelled by shutdown, publishing
+/// nothing, so another worker runs it from the start.
+async fn requeue_on_shutdown(
+    context: &WorkerContext,
+    delivery: Delivery,
+    engine_id: u32,
+    eval_id: &str,
+    task_ids: &[&str],
+) -> WorkerResult<DeliveryOutcome> {
+    info!("worker shutting down, requeueing task");
+    for task_id in task_ids {
+        context
+            .journal(
+                engine_id,
+                JournalEvent::Requeued,
+                eval_id,
+                task_id,
+                Some("worker shutting down"),
+            )
+            .await;
+    }
+    delivery
+        .nack(BasicNackOptions {
+            requeue: true,
+            multiple: false,
+        })
+        .await?;
+    Ok(DeliveryOutcome::Cancelled)
 }
 
-#[tokio::main]
-async fn main() -> WorkerResult<()> {
-    let args = Args::parse();
+/// Settles a delivery that could not be read as a task. A failed result with
+/// `error_kind: "invalid_payload"` is published under whatever ids could be
+/// salvaged, so a client waiting for the task hears about it; the delivery is
+/// then acked, or dead-lettered with `INVALID_PAYLOAD_DEAD_LETTER`. If that
+/// result cannot be published the delivery is dead-lettered when configured
+/// and requeued otherwise, so the report is not lost with it.
+async fn reject_payload(
+    context: &WorkerContext,
+    channel: &Channel,
+    delivery: Delivery,
+    value: &serde_json::Value,
+    error: String,
+) -> WorkerResult<DeliveryOutcome> {
+    let engine_id = context.engine_id;
+    error!(engine_id, error = %error, "invalid task payload");
+
+    let result = TaskResultMessage {
+        engine_id,
+        success: false,
+        error: Some(error),
+        error_kind: Some("invalid_payload".into()),
+        ..salvage_ids(&delivery, value)
+    };
+    let mut published = true;
+    if let Some(exchange) = &context.result_exchange {
+        if let Err(err) = publish_result(
+            channel,
+            exchange,
+            &context.result_routing_key,
+            &result,
+            None,
+        )
+        .await
+        {
+            warn!(error = %err, "failed to publish result for invalid payload");
+            published = false;
+        }
+    }
 
-    let engine_id = if let Some(id) = args.engine_id {
-        id
+    if context.dead_letter_invalid_payloads || !published {
+        delivery
+            .nack(BasicNackOptions {
+                requeue: !context.dead_letter_invalid_payloads,
+                multiple: false,
+            })
+            .await?;
     } else {
-        let env_value = env::var("ENGINE_ID").map_err(|_| {
-            Box::new(WorkerConfigError(
-                "provide engine id as positional argument or ENGINE_ID environment variable".into(),
-            )) as Box<dyn Error + Send + Sync>
-        })?;
-        parse_engine_id(&env_value)?
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+    Ok(DeliveryOutcome::Rejected)
+}
+
+/// Best-effort ids of an unreadable task: `eval_id`, `task_id` and
+/// `speaker_id` from its decoded payload `value` if that is a JSON object,
+/// else the ids in a `<eval_id>:<task_id>` message id. Missing ids are left
+/// empty.
+fn salvage_ids(delivery: &Delivery, value: &serde_json::Value) -> TaskResultMessage {
+    let field = |name: &str| {
+        value
+            .get(name)
+            .and_then(serde_json::Value::as_str)
+            .map(str::to_string)
     };
+    let message_id = delivery
+        .properties
+        .message_id()
+        .as_ref()
+        .and_then(|id| id.as_str().split_once(':'));
+
+    TaskResultMessage {
+        eval_id: field("eval_id")
+            .or_else(|| message_id.map(|(eval_id, _)| eval_id.to_string()))
+            .unwrap_or_default(),
+        task_id: field("task_id")
+            .or_else(|| message_id.map(|(_, task_id)| task_id.to_string()))
+            .unwrap_or_default(),
+        speaker_id: value
+            .get("speaker_id")
+            .and_then(serde_json::Value::as_u64)
+            .and_then(|id| u32::try_from(id).ok())
+            .unwrap_or_default(),
+        ..Default::default()
+    }
+}
 
-    let api_base = env::var("VXMB_API").unwrap_or_else(|_| DEFAULT_API.to_string());
-    let amqp_addr = env::var("AMQP_ADDR").unwrap_or_else(|_| DEFAULT_AMQP.to_string());
-    let queue_name = env::var("TASK_QUEUE").unwrap_or_else(|_| DEFAULT_QUEUE.to_string());
-    let result_exchange =
-        env::var("RESULT_EXCHANGE").unwrap_or_else(|_| DEFAULT_RESULT_EXCHANGE.to_string());
+/// Continues the dispatcher's trace, if it sent one, under a span id of this
+/// worker's, and records both ids on the current span.
+fn continue_trace(parent: Option<TraceContext>) -> Option<TraceContext> {
+    let trace = parent?.child();
+    let span = Span::current();
+    span.record("trace_id", trace.trace_id.as_str());
+    span.record("span_id", trace.span_id.as_str());
+    Some(trace)
+}
+
+/// The result exchange, or `None` when result publishing is disabled; then
+/// nothing is published to, or declared on, the result exchange.
+fn result_exchange(args: &Args, file_config: &WorkerFileConfig) -> Option<String> {
+    let publish_results = !args.no_results && env::var("PUBLISH_RESULTS").as_deref() != Ok("0");
+    publish_results.then(|| {
+        file_config
+            .result_exchange
+            .clone()
+            .unwrap_or_else(|| DEFAULT_RESULT_EXCHANGE.to_string())
+    })
+}
 
-    let engine: Arc<dyn TtsEngine> = if args.mock {
-        Arc::new(MockTtsEngine::new(api_base.clone()))
+/// The prefetch count from `PREFETCH_COUNT`, falling back to the engine's
+/// `default_prefetch`.
+fn prefetch_count(args: &Args, default_prefetch: u16) -> WorkerResult<u16> {
+    // `--once` must not hold further deliveries while its one task runs.
+    let prefetch = if args.once {
+        1
     } else {
-        let config = build_voicevox_config(&args)?;
-        Arc::new(VoicevoxTtsEngine::new(config)?)
+        env_parse("PREFETCH_COUNT", default_prefetch)?
     };
+    if prefetch == 0 {
+        return Err(Box::new(WorkerConfigError(
+            "PREFETCH_COUNT must be at least 1".into(),
+        )) as Box<dyn Error + Send + Sync>);
+    }
+    Ok(prefetch)
+}
 
-    let connection = Connection::connect(&amqp_addr, ConnectionProperties::default()).await?;
-    let channel = connection.create_channel().await?;
-    channel
-        .queue_declare(
-            &queue_name,
-            QueueDeclareOptions {
-                durable: true,
-                ..Default::default()
-            },
-            FieldTable::default(),
-        )
-        .await?;
+/// Compares the deliveries the worker takes at once, over all its queues, with
+/// the synthesizer pool. Deliveries beyond the pool only wait for a free
+/// synthesizer while holding their messages back from other workers; fewer
+/// leave synthesizers idle. Either is logged, or refused with `--strict`.
+/// Skipped for `--once`, which always takes a single delivery.
+fn check_prefetch(
+    args: &Args,
+    file_config: &WorkerFileConfig,
+    pool_size: usize,
+) -> WorkerResult<()> {
+    if args.once {
+        return Ok(());
+    }
+    let default_prefetch = u16::try_from(pool_size).unwrap_or(u16::MAX);
+    let prefetch = prefetch_count(args, default_prefetch)?;
+    let queues = task_queues(args, file_config).len();
+    let in_flight = usize::from(prefetch) * queues;
+    if in_flight == pool_size {
+        return Ok(());
+    }
 
-    channel
-        .exchange_declare(
-            &result_exchange,
-            ExchangeKind::Topic,
-            ExchangeDeclareOptions {
-                durable: true,
-                ..Default::default()
-            },
-            FieldTable::default(),
-        )
-        .await?;
+    let problem = if in_flight > pool_size {
+        "extra deliveries wait for a free synthesizer"
+    } else {
+        "some synthesizers stay idle"
+    };
+    if args.strict {
+        return Err(Box::new(WorkerConfigError(format!(
+            "PREFETCH_COUNT {} over {} queue(s) takes {} deliveries at once but the synthesizer pool has {}, so {}",
+            prefetch, queues, in_flight, pool_size, problem
+        ))) as Box<dyn Error + Send + Sync>);
+    }
+    warn!(
+        prefetch,
+        queues,
+        in_flight,
+        pool_size,
+        "prefetch does not match the synthesizer pool size; {}",
+        problem
+    );
+    Ok(())
+}
 
-    channel
-        .basic_qos(
-            1,
-            BasicQosOptions {
-                global: false,
-                ..Default::default()
-            },
-        )
-        .await?;
+fn task_timeout() -> WorkerResult<Duration> {
+    Ok(Duration::from_millis(env_parse(
+        "TASK_TIMEOUT_MS",
+        120_000,
+    )?))
+}
 
-    let consumer_tag = format!("vvx-worker-{}", engine_id);
-    let mut consumer = channel
-        .basic_consume(
-            &queue_name,
-            &consumer_tag,
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
+fn amqp_addr(file_config: &WorkerFileConfig) -> String {
+    file_config
+        .amqp_addr
+        .clone()
+        .unwrap_or_else(|| DEFAULT_AMQP.to_string())
+}
 
-    println!(
-        "Worker for engine {} listening on queue {}",
-        engine_id, queue_name
-    );
+fn build_amqp_settings(
+    args: &Args,
+    file_config: &WorkerFileConfig,
+    engine_id: u32,
+    result_exchange: Option<String>,
+    prefetch: u16,
+) -> WorkerResult<AmqpSettings> {
+    let queue_names = task_queues(args, file_config);
+    let dead_letter = env::var("DLX_EXCHANGE")
+        .ok()
+        .filter(|exchange| !exchange.is_empty())
+        .map(|exchange| DeadLetterSettings {
+            queue: env::var("DLX_QUEUE").unwrap_or_else(|_| format!("{}_dead", queue_names[0])),
+            exchange,
+        });
 
-    while let Some(delivery) = consumer.next().await {
-        match delivery {
-            Ok(delivery) => {
-                let task: TaskMessage = match serde_json::from_slice(delivery.data.as_ref()) {
-                    Ok(message) => message,
-                    Err(err) => {
-                        eprintln!("engine {}: invalid task payload: {}", engine_id, err);
-                        delivery.ack(BasicAckOptions::default()).await?;
-                        continue;
-                    }
-                };
+    Ok(AmqpSettings {
+        connection: amqp::ConnectionConfig::from_env(&amqp_addr(file_config))?,
+        queue_names,
+        result_exchange,
+        dead_letter,
+        max_priority: amqp::max_priority_from_env()?,
+        announce_exchange: env::var("ANNOUNCE_EXCHANGE")
+            .ok()
+            .filter(|exchange| !exchange.is_empty()),
+        control_exchange: env::var("CONTROL_EXCHANGE")
+            .ok()
+            .filter(|exchange| !exchange.is_empty()),
+        prefetch,
+        engine_id,
+    })
+}
 
-                let process_result = engine.process_task(engine_id, &task).await;
-                let (success, output_file, error) = match process_result {
-                    Ok(path) => (true, path, None),
-                    Err(err) => (false, None, Some(err.to_string())),
-                };
+/// Resolves the configuration through the same functions as a normal start
+/// and prints it as JSON. Models are not loaded, so the prefetch default is
+/// derived from the configured pool size rather than the running pool.
+fn print_config(
+    args: &Args,
+    file_config: &WorkerFileConfig,
+    engine_ids: &EngineIds,
+    api_base: &str,
+    result_exchange: Option<String>,
+) -> WorkerResult<()> {
+    let voicevox = if args.mock || args.http_engine.is_some() {
+        None
+    } else {
+        Some(build_voicevox_config(args, file_config)?)
+    };
+    let default_prefetch = voicevox.as_ref().map_or(1, |config| {
+        u16::try_from(config.pool_size.max(1)).unwrap_or(u16::MAX)
+    });
+    let settings = build_amqp_settings(
+        args,
+        file_config,
+        engine_ids.primary(),
+        result_exchange,
+        prefetch_count(args, default_prefetch)?,
+    )?;
 
-                let result_message = TaskResultMessage {
-                    eval_id: task.eval_id.clone(),
-                    task_id: task.task_id.clone(),
-                    engine_id,
-                    speaker_id: task.speaker_id,
-                    success,
-                    error,
-                    output_file,
-                };
+    let dead_letter = settings.dead_letter.as_ref();
+    let resolved = json!({
+        "engine_ids": engine_ids.as_slice(),
+        "engine": if args.mock {
+            "mock"
+        } else if args.http_engine.is_some() {
+            "http"
+        } else {
+            "voicevox"
+        },
+        "http_engine": args.http_engine,
+        "api_base": api_base,
+        "task_timeout_ms": task_timeout()?.as_millis() as u64,
+        "amqp": {
+            "addr": amqp::redact_credentials(&amqp_addr(file_config)),
+            "queues": settings.queue_names,
+            "result_exchange": settings.result_exchange,
+            "result_routing_key": amqp::ResultRoutingKey::from_env()?.template(),
+            "dead_letter_exchange": dead_letter.map(|dead_letter| &dead_letter.exchange),
+            "dead_letter_queue": dead_letter.map(|dead_letter| &dead_letter.queue),
+            "max_priority": settings.max_priority,
+            "announce_exchange": settings.announce_exchange,
+            "control_exchange": settings.control_exchange,
+            "prefetch": settings.prefetch,
+        },
+        "voicevox": voicevox,
+    });
+    println!("{}", serde_json::to_string_pretty(&resolved)?);
+    Ok(())
+}
 
-                if let Err(err) = publish_result(&channel, &result_exchange, &result_message).await
-                {
-                    eprintln!(
-                        "engine {}: failed to publish result for task {}: {}",
-                        engine_id, result_message.task_id, err
-                    );
-                    delivery
-                        .nack(BasicNackOptions {
-                            requeue: true,
-                            multiple: false,
-                        })
-                        .await?;
-                    continue;
-                }
+/// Opens the task journal named by `JOURNAL_PATH`, first logging every task a
+/// previous run received but never finished.
+fn open_journal() -> WorkerResult<Option<TaskJournal>> {
+    let Some(path) = env::var_os("JOURNAL_PATH").filter(|path| !path.is_empty()) else {
+        return Ok(None);
+    };
+    let path = PathBuf::from(path);
 
-                if result_message.success {
-                    println!(
-                        "engine {} completed task {} (speaker {}){}",
-                        engine_id,
-                        result_message.task_id,
-                        result_message.speaker_id,
-                        result_message
-                            .output_file
-                            .as_ref()
-                            .map(|path| format!(" -> {}", path))
-                            .unwrap_or_default()
-                    );
-                    delivery.ack(BasicAckOptions::default()).await?;
-                } else {
-                    eprintln!(
-                        "engine {} failed task {} (speaker {}): {}",
-                        engine_id,
-                        result_message.task_id,
-                        result_message.speaker_id,
-                        result_message.error.as_deref().unwrap_or("unknown error")
-                    );
-                    delivery
-                        .nack(BasicNackOptions {
-                            requeue: false,
-                            multiple: false,
-                        })
-                        .await?;
-                }
-            }
-            Err(err) => {
-                eprintln!("consumer error: {}", err);
-            }
-        }
+    let unfinished = journal::unfinished_tasks(&path)?;
+    for entry in &unfinished {
+        warn!(
+            eval_id = %entry.eval_id,
+            task_id = %entry.task_id,
+            engine_id = entry.engine_id,
+            received_at_ms = entry.timestamp_ms,
+            "journal shows task received without completing"
+        );
     }
 
-    connection.close(0, "").await?;
+    let journal = TaskJournal::open(&path)?;
+    info!(
+        path = %path.display(),
+        unfinished = unfinished.len(),
+        "task journal enabled"
+    );
+    Ok(Some(journal))
+}
+
+/// Reads the `--speaker-map` / `VOICEVOX_SPEAKER_MAP` JSON file, e.g.
+/// `{"1": 3, "2": 8}`; no file means no remapping.
+fn load_speaker_map(
+    args: &Args,
+    file_config: &WorkerFileConfig,
+) -> WorkerResult<HashMap<u32, u32>> {
+    let Some(path) = args
+        .speaker_map
+        .clone()
+        .or_else(|| file_config.voicevox.speaker_map.clone())
+        .filter(|path| !path.as_os_str().is_empty())
+    else {
+        return Ok(HashMap::new());
+    };
+    read_json_file(&path, "speaker map")
+}
 
-    Ok(())
+/// Reads the `--speaker-defaults` / `VOICEVOX_SPEAKER_DEFAULTS` JSON file, e.g.
+/// `{"3": {"speed_scale": 1.1, "pitch_scale": 0.02}}`, keyed by style id.
+fn load_speaker_defaults(
+    args: &Args,
+    file_config: &WorkerFileConfig,
+) -> WorkerResult<HashMap<u32, SynthesisParams>> {
+    let Some(path) = args
+        .speaker_defaults
+        .clone()
+        .or_else(|| file_config.voicevox.speaker_defaults.clone())
+        .filter(|path| !path.as_os_str().is_empty())
+    else {
+        return Ok(HashMap::new());
+    };
+    read_json_file(&path, "speaker defaults")
 }
 
-fn parse_engine_id(value: &str) -> Result<u32, Box<dyn Error + Send + Sync>> {
-    value.parse::<u32>().map_err(|_| {
-        Box::new(WorkerConfigError(format!("invalid engine id '{}'", value)))
-            as Box<dyn Error + Send + Sync>
+/// Parses a JSON config file; `what` names it in errors.
+fn read_json_file<T: DeserializeOwned>(path: &Path, what: &str) -> WorkerResult<T> {
+    let contents = fs::read_to_string(path).map_err(|err| {
+        Box::new(WorkerConfigError(format!(
+            "cannot read {} {}: {}",
+            what,
+            path.display(),
+            err
+        ))) as Box<dyn Error + Send + Sync>
+    })?;
+    serde_json::from_str(&contents).map_err(|err| {
+        Box::new(WorkerConfigError(format!(
+            "invalid {} {}: {}",
+            what,
+            path.display(),
+            err
+        ))) as Box<dyn Error + Send + Sync>
     })
 }
 
-fn build_voicevox_config(args: &Args) -> WorkerResult<VoicevoxConfig> {
+/// Queues from `--queue`, else the comma-separated `TASK_QUEUE`, else the
+/// config file, else the default queue. Duplicates are dropped.
+fn task_queues(args: &Args, file_config: &WorkerFileConfig) -> Vec<String> {
+    let configured = if args.queues.is_empty() {
+        file_config.task_queues.clone()
+    } else {
+        args.queues.clone()
+    };
+
+    let mut queues: Vec<String> = Vec::new();
+    for queue in configured {
+        if !queue.is_empty() && !queues.contains(&queue) {
+            queues.push(queue);
+        }
+    }
+    if queues.is_empty() {
+        queues.push(DEFAULT_QUEUE.to_string());
+    }
+    queues
+}
+
+fn build_voicevox_config(
+    args: &Args,
+    file_config: &WorkerFileConfig,
+) -> WorkerResult<VoicevoxConfig> {
+    let paths = &file_config.voicevox;
     let onnxruntime_path = args
         .voicevox_onnx
         .clone()
-        .or_else(|| env::var("VOICEVOX_ORT_LIB").ok().map(PathBuf::from))
+        .or_else(|| paths.onnxruntime.clone())
         .filter(|path| !path.as_os_str().is_empty());
 
     if let Some(ref path) = onnxruntime_path {
@@ -247,10 +2186,11 @@ fn build_voicevox_config(args: &Args) -> WorkerResult<VoicevoxConfig> {
     let dict_dir_path = args
         .voicevox_dict
         .clone()
-        .or_else(|| env::var("VOICEVOX_OPEN_JTALK_DIR").ok().map(PathBuf::from))
+        .or_else(|| paths.open_jtalk_dict.clone())
         .ok_or_else(|| {
             Box::new(WorkerConfigError(
-                "provide --voicevox-dict or VOICEVOX_OPEN_JTALK_DIR".into(),
+                "provide --voicevox-dict, VOICEVOX_OPEN_JTALK_DIR or voicevox.open_jtalk_dict"
+                    .into(),
             )) as Box<dyn Error + Send + Sync>
         })?;
 
@@ -267,13 +2207,38 @@ fn build_voicevox_config(args: &Args) -> WorkerResult<VoicevoxConfig> {
         )) as Box<dyn Error + Send + Sync>
     })?;
 
+    for (name, path) in &paths.named_dicts {
+        if !path.exists() {
+            return Err(Box::new(WorkerConfigError(format!(
+                "open jtalk dictionary directory for {} not found: {}",
+                name,
+                path.display()
+            ))) as Box<dyn Error + Send + Sync>);
+        }
+    }
+
+    let user_dict_path = args
+        .voicevox_user_dict
+        .clone()
+        .or_else(|| paths.user_dict.clone())
+        .filter(|path| !path.as_os_str().is_empty());
+
+    if let Some(ref path) = user_dict_path {
+        if !path.exists() {
+            return Err(Box::new(WorkerConfigError(format!(
+                "user dictionary not found: {}",
+                path.display()
+            ))) as Box<dyn Error + Send + Sync>);
+        }
+    }
+
     let model_dir_path = args
         .voicevox_model_dir
         .clone()
-        .or_else(|| env::var("VOICEVOX_MODEL_DIR").ok().map(PathBuf::from))
+        .or_else(|| paths.model_dir.clone())
         .ok_or_else(|| {
             Box::new(WorkerConfigError(
-                "provide --voicevox-model-dir or VOICEVOX_MODEL_DIR".into(),
+                "provide --voicevox-model-dir, VOICEVOX_MODEL_DIR or voicevox.model_dir".into(),
             )) as Box<dyn Error + Send + Sync>
         })?;
 
@@ -290,28 +2255,642 @@ fn build_voicevox_config(args: &Args) -> WorkerResult<VoicevoxConfig> {
         )) as Box<dyn Error + Send + Sync>
     })?;
 
+    let pool_size = match args.voicevox_pool_size {
+        Some(size) => size,
+        None => match env::var("VOICEVOX_POOL_SIZE") {
+            Ok(value) => value.parse::<usize>().map_err(|_| {
+                Box::new(WorkerConfigError(format!(
+                    "invalid VOICEVOX_POOL_SIZE '{}'",
+                    value
+                ))) as Box<dyn Error + Send + Sync>
+            })?,
+            Err(_) => 1,
+        },
+    };
+
+    if pool_size == 0 {
+        return Err(Box::new(WorkerConfigError(
+            "synthesizer pool size must be at least 1".into(),
+        )) as Box<dyn Error + Send + Sync>);
+    }
+
+    let (preload_styles, preload_all_styles) = match args
+        .voicevox_preload
+        .clone()
+        .or_else(|| env::var("VOICEVOX_PRELOAD_STYLES").ok())
+    {
+        Some(value) if value.trim().eq_ignore_ascii_case("all") => (Vec::new(), true),
+        Some(value) => (parse_id_list(&value)?, false),
+        None => (Vec::new(), false),
+    };
+
+    let acceleration = match args.acceleration {
+        Some(mode) => mode,
+        None => match env::var("VOICEVOX_ACCELERATION") {
+            Ok(value) => value.parse::<AccelerationMode>().map_err(|err| {
+                Box::new(WorkerConfigError(format!("VOICEVOX_ACCELERATION: {}", err)))
+                    as Box<dyn Error + Send + Sync>
+            })?,
+            Err(_) => AccelerationMode::Auto,
+        },
+    };
+
     Ok(VoicevoxConfig {
         onnxruntime_path,
+        onnxruntime_search_paths: env::var_os("VOICEVOX_ORT_SEARCH_PATHS")
+            .map(|paths| {
+                env::split_paths(&paths)
+                    .filter(|path| !path.as_os_str().is_empty())
+                    .collect()
+            })
+            .unwrap_or_default(),
         open_jtalk_dict_dir: dict_dir,
+        named_dicts: paths.named_dicts.clone(),
         model_dir,
+        pool_size,
+        max_retries: env_parse("VOICEVOX_MAX_RETRIES", DEFAULT_MAX_RETRIES)?,
+        retry_delay_ms: env_parse("VOICEVOX_RETRY_DELAY_MS", DEFAULT_RETRY_DELAY_MS)?,
+        preload_styles,
+        preload_all_styles,
+        warmup: args.warmup || env::var("WARMUP").as_deref() == Ok("1"),
+        warmup_style: match env::var("WARMUP_STYLE") {
+            Ok(value) => Some(value.parse().map_err(|_| {
+                Box::new(WorkerConfigError(format!(
+                    "invalid WARMUP_STYLE '{}'",
+                    value
+                ))) as Box<dyn Error + Send + Sync>
+            })?),
+            Err(_) => None,
+        },
+        output_root: output_root(args, file_config),
+        text_root: text_root(args, file_config),
+        allowed_speakers: allowed_speakers(args)?,
+        user_dict_path,
+        acceleration,
+        cpu_num_threads: match args.cpu_threads {
+            Some(threads) => Some(threads),
+            None => env::var("VOICEVOX_CPU_THREADS")
+                .ok()
+                .map(|value| {
+                    value.parse::<u16>().map_err(|_| {
+                        Box::new(WorkerConfigError(format!(
+                            "invalid VOICEVOX_CPU_THREADS '{}'",
+                            value
+                        ))) as Box<dyn Error + Send + Sync>
+                    })
+                })
+                .transpose()?,
+        },
+        dry_run: args.dry_run,
+        output_sink: build_output_sink()?,
+        max_inline_audio_bytes: env_parse("MAX_INLINE_AUDIO_BYTES", 4 * 1024 * 1024)?,
+        strict_models: args.strict_models
+            || env::var("VOICEVOX_STRICT_MODELS").as_deref() == Ok("1"),
+        validate_models: args.validate_models
+            || env::var("VOICEVOX_VALIDATE_MODELS").as_deref() == Ok("1"),
+        filename_template: env::var("FILENAME_TEMPLATE")
+            .ok()
+            .filter(|template| !template.is_empty()),
+        filename_template_subdirs: env::var("FILENAME_TEMPLATE_SUBDIRS").as_deref() == Ok("1"),
+        speaker_map: load_speaker_map(args, file_config)?,
+        speaker_defaults: load_speaker_defaults(args, file_config)?,
+        silence_threshold_db: env_parse("TRIM_SILENCE_THRESHOLD_DB", -50.0)?,
+        model_cache_capacity: env_parse("VOICEVOX_MODEL_CACHE_SIZE", 0)?,
+        min_output_frames: env_parse("MIN_OUTPUT_FRAMES", DEFAULT_MIN_OUTPUT_FRAMES)?,
+        model_scan_progress: Some(ModelScanProgress::new(log_model_scan)),
+        allow_lossy_paths: env::var("VOICEVOX_ALLOW_LOSSY_PATHS").as_deref() == Ok("1"),
+        max_text_chars: max_text_chars(args)?,
+        model_extensions: env::var("VOICEVOX_MODEL_EXTENSIONS")
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(|ext| ext.trim().to_string())
+                    .filter(|ext| !ext.is_empty())
+                    .collect()
+            })
+            .unwrap_or_else(|_| vec!["vvm".to_string()]),
+    })
+}
+
+const DEFAULT_MAX_RETRIES: u32 = 2;
+const DEFAULT_RETRY_DELAY_MS: u64 = 250;
+const DEFAULT_MIN_OUTPUT_FRAMES: usize = 1;
+
+/// Builds the `--http-engine` backend with the settings it shares with the
+/// linked VOICEVOX engine: output sink and root, text root, allowed
+/// speakers, text and output length limits and retries.
+fn build_http_engine(
+    url: &str,
+    args: &Args,
+    file_config: &WorkerFileConfig,
+) -> WorkerResult<HttpVoicevoxTtsEngine> {
+    let mut engine = HttpVoicevoxTtsEngine::new(url)
+        .with_output_sink(build_output_sink()?)
+        .with_min_output_frames(env_parse("MIN_OUTPUT_FRAMES", DEFAULT_MIN_OUTPUT_FRAMES)?)
+        .with_retry_policy(RetryPolicy {
+            max_retries: env_parse("VOICEVOX_MAX_RETRIES", DEFAULT_MAX_RETRIES)?,
+            delay: Duration::from_millis(env_parse(
+                "VOICEVOX_RETRY_DELAY_MS",
+                DEFAULT_RETRY_DELAY_MS,
+            )?),
+        });
+    if let Some(root) = output_root(args, file_config) {
+        engine = engine.with_output_root(&root)?;
+    }
+    if let Some(root) = text_root(args, file_config) {
+        engine = engine.with_text_root(&root)?;
+    }
+    if let Some(speakers) = allowed_speakers(args)? {
+        engine = engine.with_allowed_speakers(speakers);
+    }
+    if let Some(chars) = max_text_chars(args)? {
+        engine = engine.with_max_text_chars(chars);
+    }
+    Ok(engine)
+}
+
+fn output_root(args: &Args, file_config: &WorkerFileConfig) -> Option<PathBuf> {
+    args.output_root
+        .clone()
+        .or_else(|| file_config.voicevox.output_root.clone())
+        .filter(|path| !path.as_os_str().is_empty())
+}
+
+fn text_root(args: &Args, file_config: &WorkerFileConfig) -> Option<PathBuf> {
+    args.text_root
+        .clone()
+        .or_else(|| file_config.voicevox.text_root.clone())
+        .filter(|path| !path.as_os_str().is_empty())
+}
+
+fn allowed_speakers(args: &Args) -> WorkerResult<Option<HashSet<u32>>> {
+    if !args.allow_speakers.is_empty() {
+        return Ok(Some(args.allow_speakers.iter().copied().collect()));
+    }
+    match env::var("ALLOWED_SPEAKERS") {
+        Ok(value) => Ok(Some(parse_id_list(&value)?.into_iter().collect())),
+        Err(_) => Ok(None),
+    }
+}
+
+fn max_text_chars(args: &Args) -> WorkerResult<Option<usize>> {
+    let chars = match args.max_text_chars {
+        Some(chars) => chars,
+        None => env_parse("MAX_TEXT_CHARS", 0)?,
+    };
+    Ok(Some(chars).filter(|&chars| chars > 0))
+}
+
+/// Prints every style the configured model directory provides, without
+/// loading ONNX Runtime or connecting to RabbitMQ.
+fn list_speakers(args: &Args, file_config: &WorkerFileConfig) -> WorkerResult<()> {
+    let config = build_voicevox_config(args, file_config)?;
+    let styles = discover_styles(
+        config.model_dir.as_path(),
+        &config.model_extensions,
+        config.strict_models,
+        config.allow_lossy_paths,
+    )?;
+
+    match args.format {
+        ListFormat::Json => println!("{}", serde_json::to_string_pretty(&styles)?),
+        ListFormat::Table => {
+            println!(
+                "{:>8}  {:<24}  {:<16}  MODEL",
+                "STYLE_ID", "CHARACTER", "STYLE"
+            );
+            for style in &styles {
+                println!(
+                    "{:>8}  {:<24}  {:<16}  {}",
+                    style.style_id,
+                    style.character,
+                    style.style,
+                    style.model_path.display()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Logs style discovery at every tenth of the model files, so a slow cold
+/// start over a large model directory shows how far it has got.
+fn log_model_scan(read: usize, total: usize) {
+    if read == total || read * 10 / total != (read - 1) * 10 / total {
+        info!(read, total, "reading voice models");
+    }
+}
+
+/// Builds the mock engine with any simulated latency and failures from
+/// `MOCK_LATENCY_MS`, `MOCK_FAILURE_RATE` and `MOCK_SEED`.
+fn build_mock_engine(api_base: &str) -> WorkerResult<MockTtsEngine> {
+    let mut engine = MockTtsEngine::new(api_base);
+    let latency_ms: u64 = env_parse("MOCK_LATENCY_MS", 0)?;
+    if latency_ms > 0 {
+        engine = engine.with_latency(Duration::from_millis(latency_ms));
+    }
+    let failure_rate: f64 = env_parse("MOCK_FAILURE_RATE", 0.0)?;
+    if !(0.0..=1.0).contains(&failure_rate) {
+        return Err(Box::new(WorkerConfigError(format!(
+            "MOCK_FAILURE_RATE must be between 0 and 1, got {}",
+            failure_rate
+        ))) as Box<dyn Error + Send + Sync>);
+    }
+    if failure_rate > 0.0 {
+        let seed = env::var("MOCK_SEED")
+            .ok()
+            .map(|value| {
+                value.parse::<u64>().map_err(|_| {
+                    Box::new(WorkerConfigError(format!("invalid MOCK_SEED '{}'", value)))
+                        as Box<dyn Error + Send + Sync>
+                })
+            })
+            .transpose()?;
+        engine = engine.with_failure_rate(failure_rate, seed);
+    }
+    Ok(engine)
+}
+
+/// Reads Unix permission bits written in octal, e.g. `2775` or `0o664`.
+fn env_mode(name: &str) -> WorkerResult<Option<u32>> {
+    let Ok(value) = env::var(name) else {
+        return Ok(None);
+    };
+    if value.is_empty() {
+        return Ok(None);
+    }
+    match u32::from_str_radix(value.trim_start_matches("0o"), 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(Some(mode)),
+        _ => Err(Box::new(WorkerConfigError(format!(
+            "invalid {} '{}': expected octal permission bits such as 0664",
+            name, value
+        ))) as Box<dyn Error + Send + Sync>),
+    }
+}
+
+/// Selects the output sink from `OUTPUT_SINK` (`local` or `s3`).
+fn build_output_sink() -> WorkerResult<OutputSink> {
+    match env::var("OUTPUT_SINK").as_deref() {
+        Err(_) | Ok("") | Ok("local") => Ok(OutputSink::LocalFs {
+            atomic_write: env::var("ATOMIC_WRITE").as_deref() != Ok("0"),
+            dir_mode: env_mode("OUTPUT_DIR_MODE")?,
+            file_mode: env_mode("OUTPUT_FILE_MODE")?,
+        }),
+        Ok("s3") => {
+            let bucket = env::var("S3_BUCKET").map_err(|_| {
+                Box::new(WorkerConfigError(
+                    "OUTPUT_SINK=s3 requires S3_BUCKET".into(),
+                )) as Box<dyn Error + Send + Sync>
+            })?;
+            let config = S3Config {
+                bucket,
+                prefix: env::var("S3_PREFIX").unwrap_or_default(),
+                endpoint: env::var("S3_ENDPOINT")
+                    .ok()
+                    .filter(|endpoint| !endpoint.is_empty()),
+                region: env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            };
+            Ok(OutputSink::s3(config)?)
+        }
+        Ok(other) => Err(Box::new(WorkerConfigError(format!(
+            "invalid OUTPUT_SINK '{}' (expected local or s3)",
+            other
+        ))) as Box<dyn Error + Send + Sync>),
+    }
+}
+
+/// Parses a comma-separated list of ids, ignoring empty entries.
+fn parse_id_list(value: &str) -> WorkerResult<Vec<u32>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            item.parse::<u32>().map_err(|_| {
+                Box::new(WorkerConfigError(format!("invalid id '{}'", item)))
+                    as Box<dyn Error + Send + Sync>
+            })
+        })
+        .collect()
+}
+
+/// Processes a batch and publishes one `TaskBatchResultMessage` for it.
+///
+/// Item failures are reported in the batch result, so the delivery is acked
+/// once that result is published; only a failed publish requeues the batch.
+async fn process_batch_delivery(
+    context: &WorkerContext,
+    channel: &Channel,
+    delivery: Delivery,
+    engine_id: u32,
+    batch: TaskBatchMessage,
+    cancel: &CancellationToken,
+) -> WorkerResult<DeliveryOutcome> {
+    info!("batch received");
+    let claims = context
+        .in_progress
+        .claim_all(
+            batch
+                .items
+                .iter()
+                .map(|item| format!("{}:{}", batch.eval_id, item.task_id))
+                .collect(),
+        )
+        .await;
+    cancel.hold(claims);
+    let trace = continue_trace(TraceContext::from_headers(&delivery.properties));
+    for item in &batch.items {
+        context
+            .journal(
+                engine_id,
+                JournalEvent::Received,
+                &batch.eval_id,
+                &item.task_id,
+                None,
+            )
+            .await;
+    }
+
+    let batch_timeout = context
+        .task_timeout
+        .saturating_mul(u32::try_from(batch.items.len()).unwrap_or(u32::MAX));
+    // Failures carry the error message and its `error_kind`.
+    let outcomes: Vec<Result<TaskOutput, (String, &str)>> = match tokio::time::timeout(
+        batch_timeout,
+        context.engine.process_batch(engine_id, &batch, cancel),
+    )
+    .await
+    {
+        Ok(outcomes)
+            if context.running.is_stopping()
+                && outcomes
+                    .iter()
+                    .any(|outcome| matches!(outcome, Err(EngineError::Cancelled))) =>
+        {
+            let task_ids: Vec<&str> = batch
+                .items
+                .iter()
+                .map(|item| item.task_id.as_str())
+                .collect();
+            return requeue_on_shutdown(context, delivery, engine_id, &batch.eval_id, &task_ids)
+                .await;
+        }
+        Ok(outcomes) => outcomes
+            .into_iter()
+            .map(|outcome| outcome.map_err(|err| (err.to_string(), err.code())))
+            .collect(),
+        Err(_) => {
+            cancel.cancel();
+            let error = format!("batch timed out after {} ms", batch_timeout.as_millis());
+            batch
+                .items
+                .iter()
+                .map(|_| Err((error.clone(), "timeout")))
+                .collect()
+        }
+    };
+
+    let results: Vec<TaskBatchItemResult> = batch
+        .items
+        .iter()
+        .zip(outcomes)
+        .map(|(item, outcome)| match outcome {
+            Ok(output) => TaskBatchItemResult {
+                task_id: item.task_id.clone(),
+                speaker_id: item.speaker_id,
+                success: true,
+                error: None,
+                error_kind: None,
+                output_file: output.output_file,
+            },
+            Err((error, kind)) => TaskBatchItemResult {
+                task_id: item.task_id.clone(),
+                speaker_id: item.speaker_id,
+                success: false,
+                error: Some(error),
+                error_kind: Some(kind.to_string()),
+                output_file: None,
+            },
+        })
+        .collect();
+    for result in &results {
+        metrics::record_task(result.success);
+        context.stats.record(result.success, None);
+    }
+    let failures = results.iter().filter(|result| !result.success).count();
+
+    let result_message = TaskBatchResultMessage {
+        eval_id: batch.eval_id.clone(),
+        engine_id,
+        results,
+    };
+
+    let message_id = format!(
+        "{}:batch:{}",
+        batch.eval_id,
+        batch
+            .items
+            .first()
+            .map(|item| item.task_id.as_str())
+            .unwrap_or_default()
+    );
+    let published = match &context.result_exchange {
+        Some(exchange) => {
+            publish_message(
+                channel,
+                exchange,
+                &context.result_routing_key.render_batch(&result_message),
+                &message_id,
+                &result_message,
+                trace.as_ref(),
+            )
+            .await
+        }
+        None => Ok(()),
+    };
+    if let Err(err) = published {
+        error!(error = %err, "failed to publish batch result, requeueing batch");
+        let error = err.to_string();
+        for item in &result_message.results {
+            context
+                .journal(
+                    engine_id,
+                    JournalEvent::Requeued,
+                    &batch.eval_id,
+                    &item.task_id,
+                    Some(&error),
+                )
+                .await;
+        }
+        delivery
+            .nack(BasicNackOptions {
+                requeue: true,
+                multiple: false,
+            })
+            .await?;
+        return Ok(DeliveryOutcome::Failed);
+    }
+
+    for item in &result_message.results {
+        context
+            .journal(
+                engine_id,
+                if item.success {
+                    JournalEvent::Completed
+                } else {
+                    JournalEvent::Failed
+                },
+                &batch.eval_id,
+                &item.task_id,
+                item.error.as_deref(),
+            )
+            .await;
+    }
+
+    info!(failures, "batch completed");
+    delivery.ack(BasicAckOptions::default()).await?;
+
+    Ok(if failures == 0 {
+        DeliveryOutcome::Succeeded
+    } else {
+        DeliveryOutcome::Failed
     })
 }
 
+/// Publishes `result` under the key `routing_key` renders for it.
 async fn publish_result(
     channel: &Channel,
     exchange: &str,
+    routing_key: &amqp::ResultRoutingKey,
     result: &TaskResultMessage,
+    trace: Option<&TraceContext>,
+) -> WorkerResult<()> {
+    let message_id = format!("{}:{}", result.eval_id, result.task_id);
+    publish_message(
+        channel,
+        exchange,
+        &routing_key.render(result),
+        &message_id,
+        result,
+        trace,
+    )
+    .await
+}
+
+/// Publishes `message` as persistent JSON, carrying `trace` in its headers.
+async fn publish_message<T: Serialize>(
+    channel: &Channel,
+    exchange: &str,
+    routing_key: &str,
+    message_id: &str,
+    message: &T,
+    trace: Option<&TraceContext>,
 ) -> WorkerResult<()> {
-    let payload = serde_json::to_vec(result)?;
+    let payload = serde_json::to_vec(message)?;
+    let mut properties = amqp::json_properties(message_id);
+    if let Some(trace) = trace {
+        properties = trace.inject(properties);
+    }
     channel
         .basic_publish(
             exchange,
-            &result.eval_id,
+            routing_key,
             BasicPublishOptions::default(),
             &payload,
-            BasicProperties::default().with_delivery_mode(2),
+            properties,
         )
         .await?
         .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "eval:task-1";
+
+    /// Starts claiming `key` in the background.
+    fn claim_later(tasks: &Arc<TasksInProgress>, key: &str) -> tokio::task::JoinHandle<TaskClaim> {
+        let tasks = Arc::clone(tasks);
+        let key = key.to_string();
+        tokio::spawn(async move { tasks.claim(key).await })
+    }
+
+    /// Asserts that `claim` is still waiting, then that it gets through once
+    /// `release` has run.
+    async fn assert_waits_for(
+        claim: tokio::task::JoinHandle<TaskClaim>,
+        release: impl FnOnce(),
+    ) -> TaskClaim {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!claim.is_finished(), "duplicate task did not wait");
+        release();
+        tokio::time::timeout(Duration::from_secs(5), claim)
+            .await
+            .expect("duplicate task still waiting after release")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn duplicate_task_waits_for_the_running_one() {
+        let tasks = Arc::new(TasksInProgress::default());
+        let first = tasks.claim(KEY.into()).await;
+        let other = tasks.claim("eval:task-2".into()).await;
+
+        let second = assert_waits_for(claim_later(&tasks, KEY), || drop(first)).await;
+        drop((second, other));
+        assert!(tasks.keys.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn claim_is_held_until_abandoned_blocking_work_returns() {
+        let tasks = Arc::new(TasksInProgress::default());
+        let cancel = CancellationToken::new();
+        cancel.hold(tasks.claim(KEY.into()).await);
+
+        let (finish, finished) = std::sync::mpsc::channel::<()>();
+        let job = {
+            let cancel = cancel.clone();
+            tokio::task::spawn_blocking(move || {
+                finished.recv().unwrap();
+                drop(cancel);
+            })
+        };
+        // The delivery times out and lets go of its token while the job runs.
+        cancel.cancel();
+        drop(cancel);
+
+        let second = assert_waits_for(claim_later(&tasks, KEY), move || {
+            finish.send(()).unwrap();
+        })
+        .await;
+        job.await.unwrap();
+        drop(second);
+    }
+
+    #[tokio::test]
+    async fn overlapping_batches_claim_without_deadlock() {
+        let tasks = Arc::new(TasksInProgress::default());
+        let batch = |keys: [&str; 3]| {
+            let tasks = Arc::clone(&tasks);
+            let keys = keys.iter().map(|key| key.to_string()).collect();
+            tokio::spawn(async move {
+                let claims = tasks.claim_all(keys).await;
+                tokio::task::yield_now().await;
+                claims.len()
+            })
+        };
+        let first = batch(["e:a", "e:b", "e:a"]);
+        let second = batch(["e:b", "e:a", "e:c"]);
+
+        let claimed = tokio::time::timeout(Duration::from_secs(5), async {
+            (first.await.unwrap(), second.await.unwrap())
+        })
+        .await
+        .expect("batches deadlocked");
+        assert_eq!(claimed, (2, 3));
+        assert!(tasks.keys.lock().unwrap().is_empty());
+    }
+}
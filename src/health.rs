@@ -0,0 +1,86 @@
+use std::io;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+/// Readiness flag shared between the worker loop and the health endpoint.
+#[derive(Clone, Debug, Default)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    pub fn set(&self, ready: bool) {
+        self.0.store(ready, Ordering::SeqCst);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Binds `0.0.0.0:port` and answers `GET /healthz` with 200 while `readiness`
+/// is set and 503 otherwise. Abort the returned handle to stop the server.
+pub async fn serve(port: u16, readiness: Readiness) -> io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+
+    Ok(tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(respond(stream, readiness.clone()));
+                }
+                Err(err) => warn!(error = %err, "health endpoint failed to accept connection"),
+            }
+        }
+    }))
+}
+
+async fn respond(mut stream: TcpStream, readiness: Readiness) {
+    let Some(path) = read_request_path(&mut stream).await else {
+        return;
+    };
+
+    let (status, body) = match path.as_str() {
+        "/healthz" if readiness.is_ready() => ("200 OK", "ok"),
+        "/healthz" => ("503 Service Unavailable", "not ready"),
+        _ => ("404 Not Found", "not found"),
+    };
+
+    write_response(&mut stream, status, "text/plain", body).await;
+}
+
+/// Reads the request head and returns the request target.
+pub(crate) async fn read_request_path(stream: &mut TcpStream) -> Option<String> {
+    let mut buffer = [0u8; 1024];
+    let read = stream.read(&mut buffer).await.ok()?;
+
+    let request = String::from_utf8_lossy(&buffer[..read]);
+    Some(
+        request
+            .split_whitespace()
+            .nth(1)
+            .unwrap_or_default()
+            .to_string(),
+    )
+}
+
+/// Writes a complete `Connection: close` response.
+pub(crate) async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
@@ -0,0 +1,541 @@
+use crate::{
+    audio,
+    output_sink::OutputSink,
+    tts::{CancellationToken, EngineError, EngineResult, RetryPolicy, TaskOutput, TtsEngine},
+    voicevox_engine::{
+        check_output_length, check_text_length, ensure_within_root, place_output, resolve_root,
+        TaskText,
+    },
+    TaskMessage,
+};
+use async_trait::async_trait;
+use reqwest::Client;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use tokio::task;
+
+/// Synthesizes through a running VOICEVOX engine server instead of linking
+/// `voicevox_core`: `POST /audio_query`, then `POST /synthesis` with the
+/// returned query, writing the audio it answers with to the task's
+/// `output_dir` through an [`OutputSink`].
+///
+/// Tasks may set the text (inline or `text_path`), speaker, prosody scales,
+/// morphing (both styles are looked up in `/speakers` first),
+/// `output_format`, `result_filename` and `report_levels`; the
+/// server's own defaults apply to everything else. Tasks asking for anything
+/// this engine cannot do, such as post-processing, sidecar files, inline
+/// audio or a named dictionary, fail with `InvalidTask`.
+#[derive(Clone)]
+pub struct HttpVoicevoxTtsEngine {
+    client: Client,
+    base_url: String,
+    output_sink: OutputSink,
+    /// Canonical directory local output must stay inside.
+    output_root: Option<PathBuf>,
+    /// Canonical directory `text_path` must point into; without one,
+    /// `text_path` is rejected.
+    text_root: Option<PathBuf>,
+    allowed_speakers: Option<HashSet<u32>>,
+    max_text_chars: Option<usize>,
+    min_output_frames: usize,
+    retry: RetryPolicy,
+}
+
+impl HttpVoicevoxTtsEngine {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        let base_url = base_url.into();
+        let normalized = base_url.trim_end_matches('/').to_string();
+        Self {
+            client: Client::new(),
+            base_url: normalized,
+            output_sink: OutputSink::default(),
+            output_root: None,
+            text_root: None,
+            allowed_speakers: None,
+            max_text_chars: None,
+            min_output_frames: 0,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// Writes output through `sink` instead of the local filesystem.
+    pub fn with_output_sink(mut self, sink: OutputSink) -> Self {
+        self.output_sink = sink;
+        self
+    }
+
+    /// Confines local output to `root`, as `OUTPUT_ROOT` does for the
+    /// VOICEVOX engine. Fails when `root` cannot be resolved.
+    pub fn with_output_root(mut self, root: &Path) -> EngineResult<Self> {
+        self.output_root = Some(resolve_root(root, "output root")?);
+        Ok(self)
+    }
+
+    /// Accepts `text_path` for files inside `root`. Fails when `root` cannot
+    /// be resolved.
+    pub fn with_text_root(mut self, root: &Path) -> EngineResult<Self> {
+        self.text_root = Some(resolve_root(root, "text root")?);
+        Ok(self)
+    }
+
+    /// Rejects tasks for any other speaker.
+    pub fn with_allowed_speakers(mut self, speakers: HashSet<u32>) -> Self {
+        self.allowed_speakers = Some(speakers);
+        self
+    }
+
+    /// Rejects tasks whose text is longer than `chars` characters.
+    pub fn with_max_text_chars(mut self, chars: usize) -> Self {
+        self.max_text_chars = Some(chars);
+        self
+    }
+
+    /// Fails a synthesis that returns fewer than `frames` sample frames.
+    pub fn with_min_output_frames(mut self, frames: usize) -> Self {
+        self.min_output_frames = frames;
+        self
+    }
+
+    /// Retries server calls that fail with a connection error, a timeout, a
+    /// `429` or a `5xx`.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    fn check_speaker(&self, speaker_id: u32) -> EngineResult<()> {
+        match &self.allowed_speakers {
+            Some(allowed) if !allowed.contains(&speaker_id) => Err(EngineError::InvalidTask(
+                format!("speaker {} is not allowed on this worker", speaker_id),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    async fn ensure_success(response: reqwest::Response) -> EngineResult<reqwest::Response> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        let body = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "<unreadable>".into());
+        Err(EngineError::UnexpectedStatus(status, body))
+    }
+
+    /// Every style id the server offers, from `GET /speakers`.
+    async fn style_ids(&self) -> EngineResult<HashSet<u32>> {
+        let response = self
+            .client
+            .get(format!("{}/speakers", self.base_url))
+            .send()
+            .await?;
+        let speakers: Value = Self::ensure_success(response).await?.json().await?;
+        Ok(speakers
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|speaker| speaker.get("styles")?.as_array())
+            .flatten()
+            .filter_map(|style| style.get("id")?.as_u64())
+            .filter_map(|id| u32::try_from(id).ok())
+            .collect())
+    }
+
+    /// Fails a morphing task with `InvalidTask` unless the server has both
+    /// styles, rather than leaving `/synthesis_morphing` to answer with an
+    /// error status that reads as a server fault.
+    async fn check_morph_styles(&self, base_speaker: u32, target_speaker: u32) -> EngineResult<()> {
+        let styles = self.retry.run(|| self.style_ids()).await?;
+        match [base_speaker, target_speaker]
+            .into_iter()
+            .find(|style| !styles.contains(style))
+        {
+            Some(style) => Err(EngineError::InvalidTask(format!(
+                "unknown speaker/style id {}",
+                style
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    async fn audio_query(&self, text: &str, speaker: u32) -> EngineResult<Value> {
+        let response = self
+            .client
+            .post(format!("{}/audio_query", self.base_url))
+            .query(&[("text", text)])
+            .query(&[("speaker", speaker)])
+            .send()
+            .await?;
+        Ok(Self::ensure_success(response).await?.json().await?)
+    }
+
+    async fn synthesis(&self, query: &Value, speaker: u32) -> EngineResult<Vec<u8>> {
+        let response = self
+            .client
+            .post(format!("{}/synthesis", self.base_url))
+            .query(&[("speaker", speaker)])
+            .json(query)
+            .send()
+            .await?;
+        Ok(Self::ensure_success(response)
+            .await?
+            .bytes()
+            .await?
+            .to_vec())
+    }
+
+    /// `query`, made for `base_speaker`, spoken as a blend with
+    /// `target_speaker`. The server rejects pairs it cannot morph with `400`,
+    /// which fails the task with `InvalidTask`.
+    async fn synthesis_morphing(
+        &self,
+        query: &Value,
+        base_speaker: u32,
+        target_speaker: u32,
+        rate: f64,
+    ) -> EngineResult<Vec<u8>> {
+        let response = self
+            .client
+            .post(format!("{}/synthesis_morphing", self.base_url))
+            .query(&[
+                ("base_speaker", base_speaker),
+                ("target_speaker", target_speaker),
+            ])
+            .query(&[("morph_rate", rate)])
+            .json(query)
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::BAD_REQUEST {
+            let body = response.text().await.unwrap_or_default();
+            return Err(EngineError::InvalidTask(format!(
+                "speakers {} and {} cannot be morphed: {}",
+                base_speaker, target_speaker, body
+            )));
+        }
+        Ok(Self::ensure_success(response)
+            .await?
+            .bytes()
+            .await?
+            .to_vec())
+    }
+}
+
+/// Rejects task fields only the linked VOICEVOX engine implements, rather
+/// than ignoring them and reporting success for output the task did not ask
+/// for.
+fn check_supported(message: &TaskMessage) -> EngineResult<()> {
+    let unsupported = [
+        ("inline_audio", message.inline_audio),
+        ("skip_if_exists", message.skip_if_exists),
+        ("write_metadata", message.write_metadata),
+        ("emit_timing", message.emit_timing),
+        ("emit_audio_query", message.emit_audio_query),
+        ("text_replacements", !message.text_replacements.is_empty()),
+        ("dict_name", message.dict_name.is_some()),
+        ("target_sample_rate", message.target_sample_rate.is_some()),
+        ("trim_silence", message.trim_silence),
+        ("normalize_lufs", message.normalize_lufs.is_some()),
+        ("output_bit_depth", message.output_bit_depth.is_some()),
+        ("embed_metadata", message.embed_metadata),
+    ];
+    match unsupported.iter().find(|(_, set)| *set) {
+        Some((field, _)) => Err(EngineError::InvalidTask(format!(
+            "{} is not supported by the VOICEVOX engine server backend",
+            field
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Writes the task's prosody overrides into an `AudioQuery` returned by the
+/// server.
+fn apply_prosody(query: &mut Value, message: &TaskMessage) {
+    let Some(query) = query.as_object_mut() else {
+        return;
+    };
+    for (key, value) in [
+        ("speedScale", message.speed_scale),
+        ("pitchScale", message.pitch_scale),
+        ("intonationScale", message.intonation_scale),
+        ("volumeScale", message.volume_scale),
+    ] {
+        if let Some(value) = value {
+            query.insert(key.to_string(), Value::from(value));
+        }
+    }
+}
+
+fn filename(message: &TaskMessage) -> String {
+    let extension = message.output_format.extension();
+    match &message.result_filename {
+        Some(name) if Path::new(name).extension().is_some() => name.clone(),
+        Some(name) => format!("{}.{}", name, extension),
+        None => format!("{}.{}", message.eval_id, extension),
+    }
+}
+
+#[async_trait]
+impl TtsEngine for HttpVoicevoxTtsEngine {
+    async fn process_task(
+        &self,
+        _engine_id: u32,
+        message: &TaskMessage,
+        cancel: &CancellationToken,
+    ) -> EngineResult<TaskOutput> {
+        cancel.check()?;
+        message.validate()?;
+        check_supported(message)?;
+        self.check_speaker(message.speaker_id)?;
+        if let Some(target) = message.morph_target_speaker {
+            self.check_speaker(target)?;
+        }
+
+        let output_dir = message
+            .output_dir
+            .clone()
+            .ok_or_else(|| EngineError::InvalidTask("missing output directory".into()))?;
+        let output_path = place_output(
+            output_dir,
+            &filename(message),
+            self.output_root.as_deref(),
+            &self.output_sink,
+        )?;
+        let text = TaskText::from_task(message, self.text_root.as_deref())?;
+        let text = task::spawn_blocking(move || text.load()).await??;
+        check_text_length(text.chars().count(), self.max_text_chars)?;
+        if let Some(target) = message.morph_target_speaker {
+            self.check_morph_styles(message.speaker_id, target).await?;
+        }
+
+        let mut query = self
+            .retry
+            .run(|| self.audio_query(&text, message.speaker_id))
+            .await?;
+        apply_prosody(&mut query, message);
+        cancel.check()?;
+
+        let query = &query;
+        let wav = self
+            .retry
+            .run(|| async move {
+                let wav = match (message.morph_target_speaker, message.morph_rate) {
+                    (Some(target), Some(rate)) => {
+                        self.synthesis_morphing(&query, message.speaker_id, target, rate)
+                            .await?
+                    }
+                    _ => self.synthesis(&query, message.speaker_id).await?,
+                };
+                check_output_length(&wav, self.min_output_frames)?;
+                Ok(wav)
+            })
+            .await?;
+        let levels = if message.report_levels {
+            Some(audio::levels(&wav)?)
+        } else {
+            None
+        };
+        let wav_info = audio::wav_info(&wav);
+        let bytes = audio::encode(message.output_format, wav)?;
+        cancel.check()?;
+
+        let output_sha256 = format!("{:x}", Sha256::digest(&bytes));
+        let output_bytes = bytes.len() as u64;
+        let sink = self.output_sink.clone();
+        let output_root = self.output_root.clone().filter(|_| sink.is_local());
+        let output_file = task::spawn_blocking(move || {
+            if let Some(root) = &output_root {
+                ensure_within_root(root, &output_path)?;
+            }
+            sink.write(&output_path, &bytes)
+        })
+        .await??;
+
+        Ok(TaskOutput {
+            output_file: Some(output_file),
+            audio_duration_ms: wav_info.map(|info| info.duration_ms),
+            output_sha256: Some(output_sha256),
+            output_bytes: Some(output_bytes),
+            levels,
+            ..TaskOutput::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::PcmAudio;
+    use crate::test_http::serve;
+    use std::fs;
+    use std::time::Duration;
+
+    fn wav() -> Vec<u8> {
+        PcmAudio {
+            sample_rate: 24_000,
+            channels: 1,
+            samples: vec![0.25; 240],
+        }
+        .to_wav()
+    }
+
+    fn task(output_dir: &str) -> TaskMessage {
+        TaskMessage {
+            eval_id: "eval".into(),
+            task_id: "task-1".into(),
+            speaker_id: 1,
+            text: Some("こんにちは".into()),
+            output_dir: Some(output_dir.into()),
+            ..Default::default()
+        }
+    }
+
+    async fn run(engine: &HttpVoicevoxTtsEngine, task: &TaskMessage) -> EngineResult<TaskOutput> {
+        engine
+            .process_task(0, task, &CancellationToken::new())
+            .await
+    }
+
+    #[tokio::test]
+    async fn unsupported_fields_fail_without_calling_the_server() {
+        let (url, requests) = serve(Vec::new()).await;
+        let engine = HttpVoicevoxTtsEngine::new(url);
+        let task = TaskMessage {
+            write_metadata: true,
+            ..task("out")
+        };
+
+        match run(&engine, &task).await {
+            Err(EngineError::InvalidTask(message)) => assert!(message.contains("write_metadata")),
+            other => panic!("expected an invalid task, got {:?}", other.map(|_| ())),
+        }
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn output_and_text_are_confined_to_their_roots() {
+        let root = tempfile::tempdir().unwrap();
+        let (url, requests) = serve(Vec::new()).await;
+        let engine = HttpVoicevoxTtsEngine::new(url)
+            .with_output_root(root.path())
+            .unwrap();
+
+        let escape = run(&engine, &task("../elsewhere")).await;
+        assert!(matches!(escape, Err(EngineError::InvalidTask(_))));
+
+        let text_file = TaskMessage {
+            text: None,
+            text_path: Some("/etc/hostname".into()),
+            ..task("out")
+        };
+        let unrooted = run(&engine, &text_file).await;
+        assert!(matches!(unrooted, Err(EngineError::InvalidTask(_))));
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried_and_output_lands_under_the_root() {
+        let root = tempfile::tempdir().unwrap();
+        let (url, requests) =
+            serve(vec![(200, b"{}".to_vec()), (503, Vec::new()), (200, wav())]).await;
+        let engine = HttpVoicevoxTtsEngine::new(url)
+            .with_output_root(root.path())
+            .unwrap()
+            .with_min_output_frames(1)
+            .with_retry_policy(RetryPolicy {
+                max_retries: 1,
+                delay: Duration::ZERO,
+            });
+
+        let output = run(&engine, &task("out")).await.unwrap();
+        let written = fs::canonicalize(root.path()).unwrap().join("out/eval.wav");
+        assert_eq!(output.output_file.as_deref(), written.to_str());
+        assert_eq!(fs::read(&written).unwrap(), wav());
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[1].starts_with("POST /synthesis?"));
+        assert!(requests[2].starts_with("POST /synthesis?"));
+    }
+
+    const SPEAKERS: &[u8] = br#"[{"name":"a","styles":[{"id":1},{"id":3}]}]"#;
+
+    fn morph(target: u32) -> TaskMessage {
+        TaskMessage {
+            morph_target_speaker: Some(target),
+            morph_rate: Some(0.5),
+            ..task("out")
+        }
+    }
+
+    #[tokio::test]
+    async fn morphing_to_an_unknown_style_is_an_invalid_task() {
+        let (url, requests) = serve(vec![(200, SPEAKERS.to_vec())]).await;
+        let engine = HttpVoicevoxTtsEngine::new(url);
+
+        match run(&engine, &morph(2)).await {
+            Err(EngineError::InvalidTask(message)) => assert!(message.contains("id 2")),
+            other => panic!("expected an invalid task, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(*requests.lock().unwrap(), ["GET /speakers"]);
+    }
+
+    #[tokio::test]
+    async fn morphing_between_known_styles_calls_synthesis_morphing() {
+        let root = tempfile::tempdir().unwrap();
+        let (url, requests) = serve(vec![
+            (200, SPEAKERS.to_vec()),
+            (200, b"{}".to_vec()),
+            (200, wav()),
+        ])
+        .await;
+        let engine = HttpVoicevoxTtsEngine::new(url)
+            .with_output_root(root.path())
+            .unwrap();
+
+        run(&engine, &morph(3)).await.unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[2].starts_with("POST /synthesis_morphing?base_speaker=1&target_speaker=3"));
+    }
+
+    #[tokio::test]
+    async fn unmorphable_pairs_are_invalid_tasks() {
+        let (url, _) = serve(vec![
+            (200, SPEAKERS.to_vec()),
+            (200, b"{}".to_vec()),
+            (400, b"{}".to_vec()),
+        ])
+        .await;
+        let root = tempfile::tempdir().unwrap();
+        let engine = HttpVoicevoxTtsEngine::new(url)
+            .with_output_root(root.path())
+            .unwrap();
+
+        assert!(matches!(
+            run(&engine, &morph(3)).await,
+            Err(EngineError::InvalidTask(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn disallowed_speakers_and_long_texts_are_rejected() {
+        let (url, _) = serve(Vec::new()).await;
+        let engine = HttpVoicevoxTtsEngine::new(url)
+            .with_allowed_speakers(HashSet::from([2]))
+            .with_max_text_chars(3);
+        assert!(matches!(
+            run(&engine, &task("out")).await,
+            Err(EngineError::InvalidTask(_))
+        ));
+
+        let engine = engine.with_allowed_speakers(HashSet::from([1]));
+        match run(&engine, &task("out")).await {
+            Err(EngineError::InvalidTask(message)) => assert!(message.contains("limit")),
+            other => panic!("expected an invalid task, got {:?}", other.map(|_| ())),
+        }
+    }
+}
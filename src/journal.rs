@@ -0,0 +1,162 @@
+//! Append-only JSON-lines record of the tasks a worker has handled.
+//!
+//! Each line is flushed to disk before [`TaskJournal::record`] returns, so the
+//! journal survives the worker being killed mid-task. It is for working out
+//! what happened after a crash, not for replaying tasks.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::task;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalEvent {
+    Received,
+    Completed,
+    Failed,
+    /// Returned to the queue, e.g. because its result could not be published.
+    Requeued,
+    /// Dropped because its evaluation was cancelled.
+    Cancelled,
+}
+
+impl JournalEvent {
+    fn is_terminal(self) -> bool {
+        !matches!(self, JournalEvent::Received)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    pub event: JournalEvent,
+    pub eval_id: String,
+    pub task_id: String,
+    pub engine_id: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub struct TaskJournal {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+}
+
+impl TaskJournal {
+    /// Opens `path` for appending. A missing journal is created along with its
+    /// directory, which is then synced so the new file itself is durable.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let parent = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty());
+        let existed = path.exists();
+        if !existed {
+            if let Some(parent) = parent {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        if !existed {
+            file.sync_all()?;
+            if let Some(parent) = parent {
+                File::open(parent)?.sync_all()?;
+            }
+        } else if !ends_with_newline(&mut file)? {
+            // Terminate a line cut short by a crash so the next entry parses.
+            file.write_all(b"\n")?;
+            file.sync_data()?;
+        }
+
+        Ok(Self {
+            path,
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends one entry and waits until it has been written to disk.
+    pub async fn record(
+        &self,
+        event: JournalEvent,
+        eval_id: &str,
+        task_id: &str,
+        engine_id: u32,
+        error: Option<&str>,
+    ) -> io::Result<()> {
+        let entry = JournalEntry {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis() as u64)
+                .unwrap_or_default(),
+            event,
+            eval_id: eval_id.to_owned(),
+            task_id: task_id.to_owned(),
+            engine_id,
+            error: error.map(str::to_owned),
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        let file = Arc::clone(&self.file);
+        task::spawn_blocking(move || {
+            let mut file = file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            file.write_all(&line)?;
+            file.sync_data()
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
+
+fn ends_with_newline(file: &mut File) -> io::Result<bool> {
+    if file.metadata()?.len() == 0 {
+        return Ok(true);
+    }
+    let mut last = [0u8; 1];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] == b'\n')
+}
+
+/// Tasks in the journal at `path` that were received but never reached a
+/// terminal entry, oldest first. A missing journal has none. Unparseable
+/// lines, such as one cut short by a crash, are skipped.
+pub fn unfinished_tasks(path: &Path) -> io::Result<Vec<JournalEntry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut pending: HashMap<(String, String), (usize, JournalEntry)> = HashMap::new();
+    for (line_number, line) in BufReader::new(file).lines().enumerate() {
+        let Ok(entry) = serde_json::from_str::<JournalEntry>(&line?) else {
+            continue;
+        };
+        let key = (entry.eval_id.clone(), entry.task_id.clone());
+        if entry.event.is_terminal() {
+            pending.remove(&key);
+        } else {
+            pending.insert(key, (line_number, entry));
+        }
+    }
+
+    let mut unfinished: Vec<_> = pending.into_values().collect();
+    unfinished.sort_by_key(|(line_number, _)| *line_number);
+    Ok(unfinished.into_iter().map(|(_, entry)| entry).collect())
+}
@@ -1,9 +1,34 @@
+pub mod amqp;
+pub mod audio;
+pub mod config;
+pub mod health;
+pub mod http_voicevox_engine;
+pub mod journal;
 pub mod messages;
+pub mod metrics;
 pub mod mock_engine;
+pub mod output_sink;
+pub mod runner;
+#[cfg(test)]
+mod test_http;
+pub mod trace_context;
 pub mod tts;
 pub mod voicevox_engine;
 
-pub use messages::{TaskMessage, TaskResultMessage};
+pub use http_voicevox_engine::HttpVoicevoxTtsEngine;
+pub use messages::{
+    BitDepth, CancelMessage, DialogueSegment, EvaluationCompleteMessage, OutputFormat,
+    TaskBatchItem, TaskBatchItemResult, TaskBatchMessage, TaskBatchResultMessage,
+    TaskDialogueMessage, TaskMessage, TaskPayload, TaskProgressMessage, TaskResultMessage,
+    TaskStage, WorkerAnnounceMessage, SCHEMA_VERSION,
+};
 pub use mock_engine::MockTtsEngine;
-pub use tts::{EngineError, EngineResult, TtsEngine};
-pub use voicevox_engine::{VoicevoxConfig, VoicevoxTtsEngine};
+pub use output_sink::{OutputSink, S3Config};
+pub use trace_context::TraceContext;
+pub use tts::{
+    CancellationToken, EngineError, EngineResult, ProgressSink, RetryPolicy, TaskOutput, TtsEngine,
+};
+pub use voicevox_engine::{
+    discover_styles, AccelerationMode, ModelScanProgress, StyleInfo, SynthesisParams,
+    VoicevoxConfig, VoicevoxTtsEngine, DEFAULT_FILENAME_TEMPLATE, DEFAULT_ORT_SEARCH_DIRS,
+};
@@ -1,51 +1,802 @@
-use serde::{Deserialize, Serialize};
+use crate::tts::{EngineError, EngineResult};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Wav,
+    Mp3,
+    Flac,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Wav => "wav",
+            OutputFormat::Mp3 => "mp3",
+            OutputFormat::Flac => "flac",
+        }
+    }
+
+    /// Whether this build can encode the format; MP3 and FLAC need the `mp3`
+    /// and `flac` cargo features.
+    pub fn is_supported(self) -> bool {
+        match self {
+            OutputFormat::Wav => true,
+            OutputFormat::Mp3 => cfg!(feature = "mp3"),
+            OutputFormat::Flac => cfg!(feature = "flac"),
+        }
+    }
+
+    /// Every format this build can encode.
+    pub fn supported() -> Vec<OutputFormat> {
+        [OutputFormat::Wav, OutputFormat::Mp3, OutputFormat::Flac]
+            .into_iter()
+            .filter(|format| format.is_supported())
+            .collect()
+    }
+}
+
+/// Sample format of WAV output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BitDepth {
+    Int16,
+    Int24,
+    Float32,
+}
+
+/// Version of the `TaskMessage` / `TaskResultMessage` layout written by this
+/// build. Messages without a `schema_version` are read as this version.
+pub const SCHEMA_VERSION: u32 = 1;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(default)]
 pub struct TaskMessage {
+    pub schema_version: u32,
     pub eval_id: String,
     pub speaker_id: u32,
     pub task_id: String,
     pub text: Option<String>,
+    /// UTF-8 file to read the text from instead of `text`; set exactly one.
+    pub text_path: Option<String>,
+    /// `[from, to]` pairs applied in order to the text before analysis. Matches
+    /// are literal substrings, not patterns.
+    pub text_replacements: Vec<(String, String)>,
+    /// Open JTalk dictionary to analyze the text with, by the name the worker
+    /// configured it under; `None` uses the worker's default dictionary.
+    pub dict_name: Option<String>,
     pub output_dir: Option<String>,
     pub result_filename: Option<String>,
+    pub speed_scale: Option<f32>,
+    pub pitch_scale: Option<f32>,
+    pub intonation_scale: Option<f32>,
+    pub volume_scale: Option<f32>,
+    /// Style to blend `speaker_id` with; set together with `morph_rate`.
+    /// Only the VOICEVOX engine server backend (`--http-engine`) morphs; the
+    /// linked VOICEVOX engine fails such tasks with `invalid_task`.
+    pub morph_target_speaker: Option<u32>,
+    /// Share of `morph_target_speaker` in the blend, from 0 to 1.
+    pub morph_rate: Option<f64>,
+    pub output_format: OutputFormat,
+    /// Convert WAV output to this sample format; `None` keeps what VOICEVOX
+    /// produced.
+    pub output_bit_depth: Option<BitDepth>,
+    pub write_metadata: bool,
+    pub emit_timing: bool,
+    /// Write the `AudioQuery` the audio was synthesized from as
+    /// `<output>.query.json`.
+    pub emit_audio_query: bool,
+    /// AMQP message priority; only honoured when the queue has `x-max-priority`.
+    pub priority: Option<u8>,
+    /// Return the audio base64-encoded in the result when `output_dir` is unset.
+    pub inline_audio: bool,
+    /// Resample the output to this rate in Hz (needs the `resample` feature).
+    pub target_sample_rate: Option<u32>,
+    /// Reuse an existing output file instead of synthesizing again, when its
+    /// metadata sidecar, always written for such tasks, shows it was made with
+    /// the same settings.
+    pub skip_if_exists: bool,
+    /// Cut leading and trailing silence, keeping a short margin around speech.
+    pub trim_silence: bool,
+    /// Normalize integrated loudness to this many LUFS (needs the `loudness`
+    /// feature).
+    pub normalize_lufs: Option<f64>,
+    /// Position of the task within its batch, rendered by the `{index}`
+    /// filename placeholder.
+    pub index: Option<u32>,
+    /// Write the text, speaker id and eval/task ids into a `LIST`/`INFO`
+    /// chunk of the output WAV.
+    pub embed_metadata: bool,
+    /// Measure the peak and RMS level of the output and report them as
+    /// `peak_dbfs` / `rms_dbfs` in the result.
+    pub report_levels: bool,
+    /// Publish a `started` progress message to `<eval_id>.started` as soon as
+    /// a worker receives the task.
+    pub ack_start: bool,
+    /// W3C trace context of the dispatcher; takes precedence over the AMQP
+    /// `traceparent` / `tracestate` headers.
+    pub traceparent: Option<String>,
+    pub tracestate: Option<String>,
 }
 
 impl Default for TaskMessage {
     fn default() -> Self {
         Self {
+            schema_version: SCHEMA_VERSION,
             eval_id: String::new(),
             speaker_id: 0,
             task_id: String::new(),
             text: None,
+            text_path: None,
+            text_replacements: Vec::new(),
+            dict_name: None,
             output_dir: None,
             result_filename: None,
+            speed_scale: None,
+            pitch_scale: None,
+            intonation_scale: None,
+            volume_scale: None,
+            morph_target_speaker: None,
+            morph_rate: None,
+            output_format: OutputFormat::Wav,
+            output_bit_depth: None,
+            write_metadata: false,
+            emit_timing: false,
+            emit_audio_query: false,
+            priority: None,
+            inline_audio: false,
+            target_sample_rate: None,
+            skip_if_exists: false,
+            trim_silence: false,
+            normalize_lufs: None,
+            index: None,
+            embed_metadata: false,
+            report_levels: false,
+            ack_start: false,
+            traceparent: None,
+            tracestate: None,
         }
     }
 }
 
+impl TaskMessage {
+    /// Checks the invariants every engine relies on. Engines add their own
+    /// requirements on top, e.g. VOICEVOX needs text and an output directory.
+    pub fn validate(&self) -> EngineResult<()> {
+        if self.eval_id.trim().is_empty() {
+            return Err(invalid("missing eval_id"));
+        }
+        if self.task_id.trim().is_empty() {
+            return Err(invalid("missing task_id"));
+        }
+
+        if self.text.is_some() && self.text_path.is_some() {
+            return Err(invalid("set either text or text_path, not both"));
+        }
+
+        if self
+            .text_replacements
+            .iter()
+            .any(|(from, _)| from.is_empty())
+        {
+            return Err(invalid(
+                "text_replacements must not replace an empty string",
+            ));
+        }
+
+        for (name, value) in [
+            ("speed_scale", self.speed_scale),
+            ("pitch_scale", self.pitch_scale),
+            ("intonation_scale", self.intonation_scale),
+            ("volume_scale", self.volume_scale),
+        ] {
+            if value.is_some_and(|value| !value.is_finite()) {
+                return Err(invalid(&format!("{} must be a finite number", name)));
+            }
+        }
+        match (self.morph_target_speaker, self.morph_rate) {
+            (Some(_), Some(rate)) if !(0.0..=1.0).contains(&rate) => {
+                return Err(invalid("morph_rate must be between 0 and 1"));
+            }
+            (Some(_), None) | (None, Some(_)) => {
+                return Err(invalid(
+                    "morph_target_speaker and morph_rate must be set together",
+                ));
+            }
+            _ => {}
+        }
+        if self.normalize_lufs.is_some_and(|lufs| !lufs.is_finite()) {
+            return Err(invalid("normalize_lufs must be a finite number"));
+        }
+        if self.output_bit_depth.is_some() && self.output_format != OutputFormat::Wav {
+            return Err(invalid("output_bit_depth is only supported for wav output"));
+        }
+        if self.embed_metadata && self.output_format != OutputFormat::Wav {
+            return Err(invalid("embed_metadata is only supported for wav output"));
+        }
+        if self.target_sample_rate == Some(0) {
+            return Err(invalid("target_sample_rate must be positive"));
+        }
+
+        // Inline audio has nowhere to put sidecar files.
+        if self.inline_audio
+            && self.output_dir.is_none()
+            && (self.write_metadata || self.emit_timing || self.emit_audio_query)
+        {
+            return Err(invalid(
+                "write_metadata, emit_timing and emit_audio_query require an output directory",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid(reason: &str) -> EngineError {
+    EngineError::InvalidTask(reason.to_owned())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(default)]
 pub struct TaskResultMessage {
+    pub schema_version: u32,
     pub eval_id: String,
     pub task_id: String,
     pub engine_id: u32,
     pub speaker_id: u32,
     pub success: bool,
     pub error: Option<String>,
+    /// Category of `error`: `invalid_task`, `voicevox`, `io`, `timeout`, ...
+    pub error_kind: Option<String>,
     pub output_file: Option<String>,
+    pub synthesis_ms: Option<u64>,
+    pub audio_duration_ms: Option<f64>,
+    pub output_sha256: Option<String>,
+    /// Size of the encoded audio in bytes.
+    pub output_bytes: Option<u64>,
+    /// Set by workers running with `--dry-run`; `output_file` is then `None`.
+    pub dry_run: bool,
+    /// Encoded audio for `inline_audio` tasks; `output_file` is then `None`.
+    pub audio_base64: Option<String>,
+    /// Sample peak and RMS level of the output in dBFS, for tasks with
+    /// `report_levels`.
+    pub peak_dbfs: Option<f64>,
+    pub rms_dbfs: Option<f64>,
 }
 
 impl Default for TaskResultMessage {
     fn default() -> Self {
         Self {
+            schema_version: SCHEMA_VERSION,
             eval_id: String::new(),
             task_id: String::new(),
             engine_id: 0,
             speaker_id: 0,
             success: false,
             error: None,
+            error_kind: None,
             output_file: None,
+            synthesis_ms: None,
+            audio_duration_ms: None,
+            output_sha256: None,
+            output_bytes: None,
+            dry_run: false,
+            audio_base64: None,
+            peak_dbfs: None,
+            rms_dbfs: None,
+        }
+    }
+}
+
+/// Points in a task's lifecycle reported while it is being worked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStage {
+    /// Received by a worker; only reported for tasks with `ack_start`.
+    Started,
+    ModelLoading,
+    #[default]
+    Synthesizing,
+    WritingOutput,
+}
+
+impl TaskStage {
+    /// The serialized name, e.g. `model_loading`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TaskStage::Started => "started",
+            TaskStage::ModelLoading => "model_loading",
+            TaskStage::Synthesizing => "synthesizing",
+            TaskStage::WritingOutput => "writing_output",
         }
     }
 }
+
+/// Lightweight heartbeat published to the result exchange while a task runs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TaskProgressMessage {
+    pub eval_id: String,
+    pub task_id: String,
+    pub engine_id: u32,
+    pub stage: TaskStage,
+}
+
+/// Published by the client to `<eval_id>.complete` on the result exchange once
+/// every task result of an evaluation has arrived.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EvaluationCompleteMessage {
+    pub eval_id: String,
+    /// Tasks dispatched, and results received.
+    pub total: usize,
+    pub failures: usize,
+    /// Score reported by the benchmark API; `None` for evaluations that do
+    /// not use it, such as `--manifest` runs.
+    pub score: Option<f64>,
+}
+
+/// Published once per broker session so clients can see what a worker offers.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorkerAnnounceMessage {
+    pub engine_id: u32,
+    /// `None` for the mock engine.
+    pub core_version: Option<String>,
+    pub loaded_model_count: usize,
+    pub supported_styles: Vec<u32>,
+    /// `output_format` values the worker can encode, e.g. `["wav", "flac"]`;
+    /// empty for the mock engine, which writes no audio.
+    pub supported_formats: Vec<String>,
+}
+
+/// Published to the control exchange when a client abandons an evaluation;
+/// workers drop its remaining tasks.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CancelMessage {
+    pub eval_id: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TaskBatchItem {
+    pub task_id: String,
+    pub speaker_id: u32,
+    pub text: Option<String>,
+    pub result_filename: Option<String>,
+}
+
+/// Several tasks for one evaluation delivered as a single AMQP message.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TaskBatchMessage {
+    pub eval_id: String,
+    #[serde(default)]
+    pub output_dir: Option<String>,
+    pub items: Vec<TaskBatchItem>,
+}
+
+impl TaskBatchMessage {
+    /// Expands the item at `index` into a standalone task.
+    pub fn task_message(&self, index: usize) -> TaskMessage {
+        let item = &self.items[index];
+        TaskMessage {
+            eval_id: self.eval_id.clone(),
+            speaker_id: item.speaker_id,
+            task_id: item.task_id.clone(),
+            text: item.text.clone(),
+            output_dir: self.output_dir.clone(),
+            result_filename: item.result_filename.clone(),
+            index: u32::try_from(index).ok(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TaskBatchItemResult {
+    pub task_id: String,
+    pub speaker_id: u32,
+    pub success: bool,
+    pub error: Option<String>,
+    /// Category of `error`, as in [`TaskResultMessage::error_kind`].
+    pub error_kind: Option<String>,
+    pub output_file: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TaskBatchResultMessage {
+    pub eval_id: String,
+    pub engine_id: u32,
+    pub results: Vec<TaskBatchItemResult>,
+}
+
+/// Anything the worker accepts from the task queue. Batches are recognised by
+/// their required `items` field and dialogues by `segments`; every other
+/// payload is a single task. A payload is parsed only as the kind its keys
+/// name, so a malformed batch fails with the batch's own error instead of
+/// being read as a task.
+#[derive(Debug)]
+pub enum TaskPayload {
+    Batch(TaskBatchMessage),
+    Dialogue(TaskDialogueMessage),
+    Task(TaskMessage),
+}
+
+impl<'de> Deserialize<'de> for TaskPayload {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let payload = if value.get("items").is_some() {
+            serde_json::from_value(value).map(TaskPayload::Batch)
+        } else if value.get("segments").is_some() {
+            serde_json::from_value(value).map(TaskPayload::Dialogue)
+        } else {
+            serde_json::from_value(value).map(TaskPayload::Task)
+        };
+        payload.map_err(de::Error::custom)
+    }
+}
+
+impl TaskPayload {
+    pub fn eval_id(&self) -> &str {
+        match self {
+            TaskPayload::Batch(batch) => &batch.eval_id,
+            TaskPayload::Dialogue(dialogue) => &dialogue.eval_id,
+            TaskPayload::Task(task) => &task.eval_id,
+        }
+    }
+}
+
+/// One line of a dialogue.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DialogueSegment {
+    pub text: String,
+    pub speaker_id: u32,
+    /// Silence inserted after this segment, before the next one.
+    pub pause_ms: u32,
+}
+
+/// Several segments, each with its own speaker, synthesized in order into a
+/// single audio file. Answered with a `TaskResultMessage`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TaskDialogueMessage {
+    pub eval_id: String,
+    pub task_id: String,
+    #[serde(default)]
+    pub output_dir: Option<String>,
+    #[serde(default)]
+    pub result_filename: Option<String>,
+    /// Encoding of the joined audio, as for a single task.
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    pub segments: Vec<DialogueSegment>,
+}
+
+impl TaskDialogueMessage {
+    /// The dialogue's identity and output location as a plain task, reported
+    /// under the first segment's speaker.
+    pub fn task_message(&self) -> TaskMessage {
+        TaskMessage {
+            eval_id: self.eval_id.clone(),
+            task_id: self.task_id.clone(),
+            speaker_id: self
+                .segments
+                .first()
+                .map(|segment| segment.speaker_id)
+                .unwrap_or_default(),
+            output_dir: self.output_dir.clone(),
+            result_filename: self.result_filename.clone(),
+            output_format: self.output_format,
+            ..Default::default()
+        }
+    }
+
+    pub fn validate(&self) -> EngineResult<()> {
+        self.task_message().validate()?;
+        if self.segments.is_empty() {
+            return Err(invalid("dialogue has no segments"));
+        }
+        if let Some(index) = self
+            .segments
+            .iter()
+            .position(|segment| segment.text.trim().is_empty())
+        {
+            return Err(invalid(&format!("dialogue segment {} has no text", index)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voicevox_engine::TaskText;
+
+    fn task() -> TaskMessage {
+        TaskMessage {
+            eval_id: "e".into(),
+            task_id: "t".into(),
+            text: Some("a".into()),
+            ..Default::default()
+        }
+    }
+
+    fn assert_invalid(result: EngineResult<()>, reason: &str) {
+        match result {
+            Err(EngineError::InvalidTask(message)) => {
+                assert!(message.contains(reason), "{}", message)
+            }
+            other => panic!("expected an invalid task, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn text_and_text_path_are_mutually_exclusive() {
+        assert!(task().validate().is_ok());
+
+        let both = TaskMessage {
+            text_path: Some("a.txt".into()),
+            ..task()
+        };
+        assert_invalid(both.validate(), "not both");
+
+        let neither = TaskMessage {
+            text: None,
+            ..task()
+        };
+        assert!(neither.validate().is_ok());
+        assert_invalid(
+            TaskText::from_task(&neither, None).map(|_| ()),
+            "missing text or text_path",
+        );
+    }
+
+    #[test]
+    fn validate_requires_eval_and_task_ids() {
+        for eval_id in ["", "  "] {
+            let task = TaskMessage {
+                eval_id: eval_id.into(),
+                ..task()
+            };
+            assert_invalid(task.validate(), "missing eval_id");
+        }
+        for task_id in ["", "\t"] {
+            let task = TaskMessage {
+                task_id: task_id.into(),
+                ..task()
+            };
+            assert_invalid(task.validate(), "missing task_id");
+        }
+    }
+
+    #[test]
+    fn validate_rejects_an_empty_replacement_source() {
+        let task = TaskMessage {
+            text_replacements: vec![("a".into(), "b".into()), (String::new(), "c".into())],
+            ..task()
+        };
+        assert_invalid(task.validate(), "empty string");
+    }
+
+    #[test]
+    fn validate_rejects_non_finite_scales() {
+        for value in [f32::NAN, f32::INFINITY] {
+            let scales = [
+                (
+                    "speed_scale",
+                    TaskMessage {
+                        speed_scale: Some(value),
+                        ..task()
+                    },
+                ),
+                (
+                    "pitch_scale",
+                    TaskMessage {
+                        pitch_scale: Some(value),
+                        ..task()
+                    },
+                ),
+                (
+                    "intonation_scale",
+                    TaskMessage {
+                        intonation_scale: Some(value),
+                        ..task()
+                    },
+                ),
+                (
+                    "volume_scale",
+                    TaskMessage {
+                        volume_scale: Some(value),
+                        ..task()
+                    },
+                ),
+            ];
+            for (name, task) in scales {
+                assert_invalid(task.validate(), name);
+            }
+        }
+    }
+
+    #[test]
+    fn validate_checks_morphing() {
+        for rate in [-0.1, 1.5, f64::NAN] {
+            let task = TaskMessage {
+                morph_target_speaker: Some(2),
+                morph_rate: Some(rate),
+                ..task()
+            };
+            assert_invalid(task.validate(), "between 0 and 1");
+        }
+
+        let target_only = TaskMessage {
+            morph_target_speaker: Some(2),
+            ..task()
+        };
+        assert_invalid(target_only.validate(), "set together");
+        let rate_only = TaskMessage {
+            morph_rate: Some(0.5),
+            ..task()
+        };
+        assert_invalid(rate_only.validate(), "set together");
+
+        let paired = TaskMessage {
+            morph_target_speaker: Some(2),
+            morph_rate: Some(1.0),
+            ..task()
+        };
+        assert!(paired.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_non_finite_loudness() {
+        let task = TaskMessage {
+            normalize_lufs: Some(f64::NEG_INFINITY),
+            ..task()
+        };
+        assert_invalid(task.validate(), "normalize_lufs");
+    }
+
+    #[test]
+    fn validate_keeps_wav_options_to_wav_output() {
+        let bit_depth = TaskMessage {
+            output_format: OutputFormat::Mp3,
+            output_bit_depth: Some(BitDepth::Int24),
+            ..task()
+        };
+        assert_invalid(bit_depth.validate(), "output_bit_depth");
+
+        let embed = TaskMessage {
+            output_format: OutputFormat::Flac,
+            embed_metadata: true,
+            ..task()
+        };
+        assert_invalid(embed.validate(), "embed_metadata");
+
+        let wav = TaskMessage {
+            output_bit_depth: Some(BitDepth::Int24),
+            embed_metadata: true,
+            ..task()
+        };
+        assert!(wav.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_a_zero_sample_rate() {
+        let task = TaskMessage {
+            target_sample_rate: Some(0),
+            ..task()
+        };
+        assert_invalid(task.validate(), "target_sample_rate");
+    }
+
+    #[test]
+    fn validate_rejects_sidecars_for_inline_audio() {
+        for sidecar in [
+            TaskMessage {
+                write_metadata: true,
+                ..task()
+            },
+            TaskMessage {
+                emit_timing: true,
+                ..task()
+            },
+            TaskMessage {
+                emit_audio_query: true,
+                ..task()
+            },
+        ] {
+            let task = TaskMessage {
+                inline_audio: true,
+                ..sidecar
+            };
+            assert_invalid(task.validate(), "require an output directory");
+        }
+
+        let with_dir = TaskMessage {
+            inline_audio: true,
+            write_metadata: true,
+            output_dir: Some("out".into()),
+            ..task()
+        };
+        assert!(with_dir.validate().is_ok());
+    }
+
+    #[test]
+    fn prosody_fields_default_to_none() {
+        let task: TaskMessage =
+            serde_json::from_str(r#"{"eval_id":"e","task_id":"t","text":"a"}"#).unwrap();
+        assert_eq!(task.speed_scale, None);
+        assert_eq!(task.pitch_scale, None);
+        assert_eq!(task.intonation_scale, None);
+        assert_eq!(task.volume_scale, None);
+    }
+
+    #[test]
+    fn prosody_fields_round_trip() {
+        let task = TaskMessage {
+            speed_scale: Some(1.25),
+            pitch_scale: Some(-0.05),
+            intonation_scale: Some(0.5),
+            volume_scale: Some(2.0),
+            ..task()
+        };
+        let json = serde_json::to_string(&task).unwrap();
+        let parsed: TaskMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.speed_scale, Some(1.25));
+        assert_eq!(parsed.pitch_scale, Some(-0.05));
+        assert_eq!(parsed.intonation_scale, Some(0.5));
+        assert_eq!(parsed.volume_scale, Some(2.0));
+    }
+
+    #[test]
+    fn missing_schema_version_reads_as_the_current_one() {
+        let task: TaskMessage =
+            serde_json::from_str(r#"{"eval_id":"e","task_id":"t","text":"a"}"#).unwrap();
+        assert_eq!(task.schema_version, SCHEMA_VERSION);
+
+        let result: TaskResultMessage =
+            serde_json::from_str(r#"{"eval_id":"e","task_id":"t","success":true}"#).unwrap();
+        assert_eq!(result.schema_version, SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn schema_version_is_serialized() {
+        let task = serde_json::to_value(task()).unwrap();
+        assert_eq!(task["schema_version"], SCHEMA_VERSION);
+
+        let result = serde_json::to_value(TaskResultMessage::default()).unwrap();
+        assert_eq!(result["schema_version"], SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn payload_kind_follows_its_keys() {
+        let batch = r#"{"eval_id":"e","items":[{"task_id":"t","speaker_id":1,"text":"a"}]}"#;
+        assert!(matches!(
+            serde_json::from_str::<TaskPayload>(batch).unwrap(),
+            TaskPayload::Batch(_)
+        ));
+        let dialogue = r#"{"eval_id":"e","task_id":"t","segments":[{"text":"a"}]}"#;
+        assert!(matches!(
+            serde_json::from_str::<TaskPayload>(dialogue).unwrap(),
+            TaskPayload::Dialogue(_)
+        ));
+        let task = r#"{"eval_id":"e","task_id":"t","text":"a"}"#;
+        assert!(matches!(
+            serde_json::from_str::<TaskPayload>(task).unwrap(),
+            TaskPayload::Task(_)
+        ));
+    }
+
+    #[test]
+    fn malformed_batch_reports_its_own_error() {
+        let batch = r#"{"eval_id":"e","task_id":"t","items":"not a list"}"#;
+        let err = serde_json::from_str::<TaskPayload>(batch).unwrap_err();
+        assert!(err.to_string().contains("invalid type"), "{}", err);
+    }
+}
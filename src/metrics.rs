@@ -0,0 +1,161 @@
+//! Prometheus metrics for the worker and the VOICEVOX engine.
+//!
+//! Without the `metrics` cargo feature every recorder is a no-op and
+//! [`serve`] fails, so call sites need no feature gates of their own.
+
+use std::io;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Counts one finished task under `vvx_tasks_total{result}`.
+pub fn record_task(success: bool) {
+    #[cfg(feature = "metrics")]
+    registry::get()
+        .tasks_total
+        .with_label_values(&[if success { "success" } else { "failure" }])
+        .inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = success;
+}
+
+/// Records the time spent inside VOICEVOX for one synthesis: the analysis and
+/// synthesis calls only, not waiting for a pool member or loading its model.
+pub fn observe_synthesis(duration: Duration) {
+    #[cfg(feature = "metrics")]
+    registry::get()
+        .synthesis_seconds
+        .observe(duration.as_secs_f64());
+    #[cfg(not(feature = "metrics"))]
+    let _ = duration;
+}
+
+/// Adjusts `vvx_models_loaded`, the number of voice models resident across all
+/// pool members.
+pub fn add_models_loaded(delta: i64) {
+    #[cfg(feature = "metrics")]
+    registry::get().models_loaded.add(delta);
+    #[cfg(not(feature = "metrics"))]
+    let _ = delta;
+}
+
+/// Binds `0.0.0.0:port` and answers `GET /metrics` in the Prometheus text
+/// format. Abort the returned handle to stop the server.
+#[cfg(feature = "metrics")]
+pub async fn serve(port: u16) -> io::Result<JoinHandle<()>> {
+    use crate::health::{read_request_path, write_response};
+    use tokio::net::TcpListener;
+    use tracing::warn;
+
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+
+    Ok(tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((mut stream, _)) => {
+                    tokio::spawn(async move {
+                        let Some(path) = read_request_path(&mut stream).await else {
+                            return;
+                        };
+                        match path.as_str() {
+                            "/metrics" => {
+                                let body = registry::get().encode();
+                                write_response(
+                                    &mut stream,
+                                    "200 OK",
+                                    "text/plain; version=0.0.4",
+                                    &body,
+                                )
+                                .await;
+                            }
+                            _ => {
+                                write_response(
+                                    &mut stream,
+                                    "404 Not Found",
+                                    "text/plain",
+                                    "not found",
+                                )
+                                .await
+                            }
+                        }
+                    });
+                }
+                Err(err) => warn!(error = %err, "metrics endpoint failed to accept connection"),
+            }
+        }
+    }))
+}
+
+#[cfg(not(feature = "metrics"))]
+pub async fn serve(_port: u16) -> io::Result<JoinHandle<()>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the metrics endpoint requires the `metrics` cargo feature",
+    ))
+}
+
+#[cfg(feature = "metrics")]
+mod registry {
+    use prometheus::{
+        Encoder, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+    };
+    use std::sync::OnceLock;
+
+    pub(super) struct Metrics {
+        registry: Registry,
+        pub(super) tasks_total: IntCounterVec,
+        pub(super) synthesis_seconds: Histogram,
+        pub(super) models_loaded: IntGauge,
+    }
+
+    impl Metrics {
+        pub(super) fn encode(&self) -> String {
+            let mut buffer = Vec::new();
+            if TextEncoder::new()
+                .encode(&self.registry.gather(), &mut buffer)
+                .is_err()
+            {
+                return String::new();
+            }
+            String::from_utf8(buffer).unwrap_or_default()
+        }
+    }
+
+    pub(super) fn get() -> &'static Metrics {
+        static METRICS: OnceLock<Metrics> = OnceLock::new();
+        METRICS.get_or_init(|| {
+            let tasks_total = IntCounterVec::new(
+                Opts::new("vvx_tasks_total", "Tasks processed, by result"),
+                &["result"],
+            )
+            .expect("valid tasks_total metric");
+            let synthesis_seconds = Histogram::with_opts(
+                HistogramOpts::new("vvx_synthesis_seconds", "Time spent synthesizing one task")
+                    .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]),
+            )
+            .expect("valid synthesis_seconds metric");
+            let models_loaded = IntGauge::new(
+                "vvx_models_loaded",
+                "Voice models loaded across all synthesizers",
+            )
+            .expect("valid models_loaded metric");
+
+            let registry = Registry::new();
+            registry
+                .register(Box::new(tasks_total.clone()))
+                .expect("register tasks_total");
+            registry
+                .register(Box::new(synthesis_seconds.clone()))
+                .expect("register synthesis_seconds");
+            registry
+                .register(Box::new(models_loaded.clone()))
+                .expect("register models_loaded");
+
+            Metrics {
+                registry,
+                tasks_total,
+                synthesis_seconds,
+                models_loaded,
+            }
+        })
+    }
+}
@@ -1,15 +1,31 @@
 use crate::{
-    tts::{EngineError, EngineResult, TtsEngine},
+    tts::{CancellationToken, EngineError, EngineResult, TaskOutput, TtsEngine},
+    voicevox_engine::check_output_length,
     TaskMessage,
 };
 use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use reqwest::Client;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task;
 
 #[derive(Clone)]
 pub struct MockTtsEngine {
     client: Client,
     base_url: String,
+    /// Slept before every task, to stand in for synthesis time.
+    artificial_latency: Option<Duration>,
+    /// Fraction of tasks, from 0 to 1, that fail without calling the API.
+    failure_rate: f64,
+    /// Shared by clones so a seeded run fails the same tasks in the same order.
+    rng: Arc<Mutex<StdRng>>,
+    /// Sample frames the WAV named by a synthesis response must hold; 0 skips
+    /// the check.
+    min_output_frames: usize,
 }
 
 impl MockTtsEngine {
@@ -19,9 +35,53 @@ impl MockTtsEngine {
         Self {
             client: Client::new(),
             base_url: normalized,
+            artificial_latency: None,
+            failure_rate: 0.0,
+            rng: Arc::new(Mutex::new(StdRng::from_entropy())),
+            min_output_frames: 0,
         }
     }
 
+    /// Sleeps for `latency` at the start of every task.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.artificial_latency = Some(latency);
+        self
+    }
+
+    /// Fails `rate` (clamped to 0..=1, and 0 when not finite) of the tasks
+    /// with a retryable I/O error instead of calling the API. With a `seed`
+    /// the sequence of failures is reproducible.
+    pub fn with_failure_rate(mut self, rate: f64, seed: Option<u64>) -> Self {
+        self.failure_rate = if rate.is_finite() {
+            rate.clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        self.rng = Arc::new(Mutex::new(rng));
+        self
+    }
+
+    /// Reads the WAV a synthesis response names and fails the task with
+    /// `empty synthesis output`, as the VOICEVOX engine does, when it holds
+    /// fewer than `frames` sample frames. The file must be readable from the
+    /// worker.
+    pub fn with_min_output_frames(mut self, frames: usize) -> Self {
+        self.min_output_frames = frames;
+        self
+    }
+
+    fn should_fail(&self) -> bool {
+        if self.failure_rate <= 0.0 {
+            return false;
+        }
+        let mut rng = self.rng.lock().unwrap_or_else(|err| err.into_inner());
+        rng.gen_bool(self.failure_rate)
+    }
+
     fn speaker_url(&self, eval_id: &str, engine_id: u32) -> String {
         format!(
             "{}/evaluations/{}/engines/{}/speaker",
@@ -36,10 +96,10 @@ impl MockTtsEngine {
         )
     }
 
-    async fn ensure_success(response: reqwest::Response) -> EngineResult<()> {
+    async fn ensure_success(response: reqwest::Response) -> EngineResult<reqwest::Response> {
         let status = response.status();
         if status.is_success() {
-            return Ok(());
+            return Ok(response);
         }
 
         let body = response
@@ -61,13 +121,34 @@ struct SynthesisRequest<'a> {
     task_id: &'a str,
 }
 
+/// Optional body of a synthesis response; the benchmark API answers `{}`.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SynthesisResponse {
+    path: Option<String>,
+}
+
 #[async_trait]
 impl TtsEngine for MockTtsEngine {
     async fn process_task(
         &self,
         engine_id: u32,
         message: &TaskMessage,
-    ) -> EngineResult<Option<String>> {
+        cancel: &CancellationToken,
+    ) -> EngineResult<TaskOutput> {
+        message.validate()?;
+
+        if let Some(latency) = self.artificial_latency {
+            tokio::time::sleep(latency).await;
+        }
+        if self.should_fail() {
+            return Err(EngineError::Io(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "simulated mock failure",
+            )));
+        }
+        cancel.check()?;
+
         let response = self
             .client
             .put(self.speaker_url(&message.eval_id, engine_id))
@@ -77,6 +158,7 @@ impl TtsEngine for MockTtsEngine {
             .send()
             .await?;
         Self::ensure_success(response).await?;
+        cancel.check()?;
 
         let response = self
             .client
@@ -87,8 +169,121 @@ impl TtsEngine for MockTtsEngine {
             })
             .send()
             .await?;
-        Self::ensure_success(response).await?;
+        let body = Self::ensure_success(response).await?.text().await?;
+
+        let synthesis = if body.trim().is_empty() {
+            SynthesisResponse::default()
+        } else {
+            serde_json::from_str::<SynthesisResponse>(&body).map_err(io::Error::from)?
+        };
+
+        if let Some(path) = synthesis
+            .path
+            .clone()
+            .filter(|_| self.min_output_frames > 0)
+        {
+            let wav = task::spawn_blocking(move || std::fs::read(path)).await??;
+            check_output_length(&wav, self.min_output_frames)?;
+        }
+
+        Ok(TaskOutput {
+            output_file: synthesis.path,
+            ..TaskOutput::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::PcmAudio;
+    use crate::test_http::serve;
+
+    fn wav(frames: usize) -> Vec<u8> {
+        PcmAudio {
+            sample_rate: 24_000,
+            channels: 1,
+            samples: vec![0.25; frames],
+        }
+        .to_wav()
+    }
+
+    fn task() -> TaskMessage {
+        TaskMessage {
+            eval_id: "eval".into(),
+            task_id: "task-1".into(),
+            speaker_id: 1,
+            text: Some("こんにちは".into()),
+            ..Default::default()
+        }
+    }
+
+    /// Runs one task against an API whose synthesis response names `output`.
+    async fn synthesize_to(output: &std::path::Path) -> EngineResult<TaskOutput> {
+        let body = serde_json::json!({ "path": output }).to_string();
+        let (url, _) = serve(vec![(200, b"{}".to_vec()), (200, body.into_bytes())]).await;
+        MockTtsEngine::new(url)
+            .with_min_output_frames(1)
+            .process_task(0, &task(), &CancellationToken::new())
+            .await
+    }
+
+    #[test]
+    fn non_finite_failure_rates_never_fail() {
+        for rate in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let engine = MockTtsEngine::new("http://unused").with_failure_rate(rate, Some(1));
+            assert_eq!(engine.failure_rate, 0.0);
+        }
+        let engine = MockTtsEngine::new("http://unused").with_failure_rate(2.0, None);
+        assert_eq!(engine.failure_rate, 1.0);
+    }
+
+    #[tokio::test]
+    async fn empty_synthesis_response_has_no_output_file() {
+        let (url, _) = serve(vec![(200, b"{}".to_vec()), (200, Vec::new())]).await;
+        let result = MockTtsEngine::new(url)
+            .process_task(0, &task(), &CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(result.output_file, None);
+    }
+
+    #[tokio::test]
+    async fn synthesis_path_is_reported_without_reading_it() {
+        let body = br#"{"path":"/nonexistent/speech.wav"}"#.to_vec();
+        let (url, _) = serve(vec![(200, b"{}".to_vec()), (200, body)]).await;
+        let result = MockTtsEngine::new(url)
+            .process_task(0, &task(), &CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            result.output_file.as_deref(),
+            Some("/nonexistent/speech.wav")
+        );
+    }
+
+    #[tokio::test]
+    async fn header_only_output_fails_the_task() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("empty.wav");
+        std::fs::write(&output, wav(0)).unwrap();
+
+        match synthesize_to(&output).await {
+            Err(EngineError::Voicevox(message)) => assert_eq!(message, "empty synthesis output"),
+            other => panic!(
+                "expected empty synthesis output, got {:?}",
+                other.map(|_| ())
+            ),
+        }
+    }
+
+    #[tokio::test]
+    async fn output_with_audio_passes_the_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("speech.wav");
+        std::fs::write(&output, wav(240)).unwrap();
 
-        Ok(None)
+        let result = synthesize_to(&output).await.unwrap();
+        assert_eq!(result.output_file.as_deref(), output.to_str());
     }
 }
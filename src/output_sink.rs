@@ -0,0 +1,314 @@
+use crate::tts::{EngineError, EngineResult};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+#[cfg(feature = "s3")]
+use std::sync::Arc;
+
+/// Where synthesized audio and its sidecar files are stored.
+#[derive(Clone, Debug)]
+pub enum OutputSink {
+    /// Write to the local filesystem at the task's `output_dir`.
+    LocalFs {
+        /// Write each file to a temporary name in the same directory and
+        /// rename it into place, so readers never see a partial file.
+        atomic_write: bool,
+        /// Unix permission bits for directories the sink creates, applied
+        /// after creation so the umask does not mask them. Ignored elsewhere.
+        dir_mode: Option<u32>,
+        /// Unix permission bits for every file written. Ignored elsewhere.
+        file_mode: Option<u32>,
+    },
+    /// Upload to an S3-compatible bucket, using the output path as object key.
+    #[cfg(feature = "s3")]
+    S3(Arc<S3Sink>),
+}
+
+/// Connection settings for the S3 sink. Credentials are read from the usual
+/// `AWS_ACCESS_KEY_ID` / `AWS_SECRET_ACCESS_KEY` environment variables.
+#[derive(Clone, Debug)]
+pub struct S3Config {
+    pub bucket: String,
+    /// Prepended to every object key, e.g. `tts/`.
+    pub prefix: String,
+    /// Custom endpoint for MinIO and other S3-compatible stores; path-style
+    /// addressing is used when set.
+    pub endpoint: Option<String>,
+    pub region: String,
+}
+
+impl Default for OutputSink {
+    fn default() -> Self {
+        OutputSink::LocalFs {
+            atomic_write: true,
+            dir_mode: None,
+            file_mode: None,
+        }
+    }
+}
+
+/// Describes the sink, e.g. for printing the worker's configuration. S3
+/// credentials are never included.
+impl Serialize for OutputSink {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            OutputSink::LocalFs {
+                atomic_write,
+                dir_mode,
+                file_mode,
+            } => {
+                let octal = |mode: &Option<u32>| mode.map(|mode| format!("{:o}", mode));
+                let mut state = serializer.serialize_struct("OutputSink", 4)?;
+                state.serialize_field("kind", "local")?;
+                state.serialize_field("atomic_write", atomic_write)?;
+                state.serialize_field("dir_mode", &octal(dir_mode))?;
+                state.serialize_field("file_mode", &octal(file_mode))?;
+                state.end()
+            }
+            #[cfg(feature = "s3")]
+            OutputSink::S3(sink) => {
+                let mut state = serializer.serialize_struct("OutputSink", 3)?;
+                state.serialize_field("kind", "s3")?;
+                state.serialize_field("bucket", &sink.bucket.name())?;
+                state.serialize_field("prefix", &sink.prefix)?;
+                state.end()
+            }
+        }
+    }
+}
+
+impl OutputSink {
+    #[cfg(feature = "s3")]
+    pub fn s3(config: S3Config) -> EngineResult<Self> {
+        Ok(OutputSink::S3(Arc::new(S3Sink::new(config)?)))
+    }
+
+    #[cfg(not(feature = "s3"))]
+    pub fn s3(_config: S3Config) -> EngineResult<Self> {
+        Err(EngineError::InvalidTask(
+            "S3 output requires the `s3` cargo feature".into(),
+        ))
+    }
+
+    pub fn is_local(&self) -> bool {
+        matches!(self, OutputSink::LocalFs { .. })
+    }
+
+    /// Stores `bytes` at `path` and returns where the file can be found: the
+    /// filesystem path or the object URL.
+    ///
+    /// Blocks the calling thread; call it from a blocking task.
+    pub fn write(&self, path: &Path, bytes: &[u8]) -> EngineResult<String> {
+        match self {
+            OutputSink::LocalFs {
+                atomic_write,
+                dir_mode,
+                file_mode,
+            } => {
+                if let Some(parent) = path.parent() {
+                    create_dirs(parent, *dir_mode)?;
+                }
+                if *atomic_write {
+                    write_atomically(path, bytes)?;
+                } else {
+                    fs::write(path, bytes)?;
+                }
+                if let Some(mode) = file_mode {
+                    set_mode(path, *mode)?;
+                }
+                Ok(path.to_string_lossy().into_owned())
+            }
+            #[cfg(feature = "s3")]
+            OutputSink::S3(sink) => sink.put(path, bytes),
+        }
+    }
+}
+
+/// Creates `dir` and any missing parents, giving each directory created here,
+/// but none that already existed, `mode`.
+fn create_dirs(dir: &Path, mode: Option<u32>) -> io::Result<()> {
+    let Some(mode) = mode else {
+        return fs::create_dir_all(dir);
+    };
+    let missing: Vec<&Path> = dir
+        .ancestors()
+        .take_while(|ancestor| !ancestor.as_os_str().is_empty() && !ancestor.exists())
+        .collect();
+    fs::create_dir_all(dir)?;
+    for created in missing.into_iter().rev() {
+        set_mode(created, mode)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+/// Permission bits are a Unix notion; elsewhere the defaults are kept.
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Writes `bytes` to a hidden temporary file next to `path`, syncs it and
+/// renames it over `path`. The temporary file is removed if any step fails.
+fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let dir = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".vvx-").suffix(".tmp");
+    // Temporary files are private by default; the output should be readable
+    // like one written by `fs::write`.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        builder.permissions(fs::Permissions::from_mode(0o644));
+    }
+    let mut temp = builder.tempfile_in(dir)?;
+    temp.write_all(bytes)?;
+    temp.as_file().sync_all()?;
+    match temp.persist(path) {
+        Ok(_) => Ok(()),
+        // Only possible when `path` itself is a mount point, e.g. a file
+        // bind-mounted into a container; fall back to a plain copy.
+        Err(err) if err.error.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(err.file.path(), path)?;
+            Ok(())
+        }
+        Err(err) => Err(err.error),
+    }
+}
+
+#[cfg(feature = "s3")]
+pub struct S3Sink {
+    bucket: Box<s3::Bucket>,
+    prefix: String,
+}
+
+#[cfg(feature = "s3")]
+impl std::fmt::Debug for S3Sink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Sink")
+            .field("bucket", &self.bucket.name())
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+#[cfg(feature = "s3")]
+impl S3Sink {
+    fn new(config: S3Config) -> EngineResult<Self> {
+        use s3::{creds::Credentials, Bucket, Region};
+
+        let credentials = Credentials::default().map_err(s3_error)?;
+        let bucket = match config.endpoint {
+            Some(endpoint) => Bucket::new(
+                &config.bucket,
+                Region::Custom {
+                    region: config.region,
+                    endpoint,
+                },
+                credentials,
+            )
+            .map_err(s3_error)?
+            .with_path_style(),
+            None => Bucket::new(
+                &config.bucket,
+                config.region.parse::<Region>().map_err(s3_error)?,
+                credentials,
+            )
+            .map_err(s3_error)?,
+        };
+
+        Ok(Self {
+            bucket,
+            prefix: config.prefix,
+        })
+    }
+
+    fn put(&self, path: &Path, bytes: &[u8]) -> EngineResult<String> {
+        let key = self.object_key(path);
+        let response = tokio::runtime::Handle::current()
+            .block_on(
+                self.bucket
+                    .put_object_with_content_type(&key, bytes, content_type(path)),
+            )
+            .map_err(s3_error)?;
+
+        if !(200..300).contains(&response.status_code()) {
+            return Err(s3_error(format!(
+                "upload of {} returned status {}",
+                key,
+                response.status_code()
+            )));
+        }
+
+        Ok(format!("{}/{}", self.bucket.url(), key))
+    }
+
+    /// `prefix` followed by the output path with `/` separators and no leading
+    /// slash.
+    fn object_key(&self, path: &Path) -> String {
+        let path = path.to_string_lossy().replace('\\', "/");
+        let path = path.trim_start_matches("./").trim_start_matches('/');
+        if self.prefix.is_empty() || self.prefix.ends_with('/') {
+            format!("{}{}", self.prefix, path)
+        } else {
+            format!("{}/{}", self.prefix, path)
+        }
+    }
+}
+
+#[cfg(feature = "s3")]
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("wav") => "audio/wav",
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("json") => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(feature = "s3")]
+fn s3_error(err: impl std::fmt::Display) -> EngineError {
+    EngineError::Io(std::io::Error::other(format!("s3 error: {}", err)))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn local_sink_applies_modes_to_created_dirs_and_files() {
+        for atomic_write in [true, false] {
+            let root = tempfile::tempdir().unwrap();
+            let root_mode = mode(root.path());
+            let sink = OutputSink::LocalFs {
+                atomic_write,
+                dir_mode: Some(0o2775),
+                file_mode: Some(0o664),
+            };
+            let output = root.path().join("eval/task/out.wav");
+
+            sink.write(&output, b"RIFF").unwrap();
+            assert_eq!(fs::read(&output).unwrap(), b"RIFF");
+            assert_eq!(mode(&output), 0o664);
+            assert_eq!(mode(&root.path().join("eval")), 0o2775);
+            assert_eq!(mode(&root.path().join("eval/task")), 0o2775);
+            // Directories that already existed keep their mode.
+            assert_eq!(mode(root.path()), root_mode);
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Turning a task into its result message, independent of any transport.
+//!
+//! The worker binary wraps these in AMQP delivery handling; services that embed
+//! an engine directly can call [`run_task`] and publish the result themselves.
+
+use crate::{
+    tts::{CancellationToken, EngineResult, TaskOutput, TtsEngine},
+    TaskMessage, TaskResultMessage,
+};
+
+/// Processes `task` on `engine` and reports the outcome, success or failure,
+/// as a result message. Engine errors never escape; they end up in `error`
+/// and `error_kind`.
+pub async fn run_task(
+    engine: &dyn TtsEngine,
+    engine_id: u32,
+    task: &TaskMessage,
+    cancel: &CancellationToken,
+) -> TaskResultMessage {
+    let outcome = engine.process_task(engine_id, task, cancel).await;
+    task_result(engine_id, task, outcome)
+}
+
+/// Builds the result message for `task` from what the engine returned.
+pub fn task_result(
+    engine_id: u32,
+    task: &TaskMessage,
+    outcome: EngineResult<TaskOutput>,
+) -> TaskResultMessage {
+    match outcome {
+        Ok(output) => TaskResultMessage {
+            success: true,
+            output_file: output.output_file,
+            synthesis_ms: output.synthesis_ms,
+            audio_duration_ms: output.audio_duration_ms,
+            output_sha256: output.output_sha256,
+            output_bytes: output.output_bytes,
+            dry_run: output.dry_run,
+            audio_base64: output.audio_base64,
+            peak_dbfs: output.levels.map(|levels| levels.peak_dbfs),
+            rms_dbfs: output.levels.map(|levels| levels.rms_dbfs),
+            ..result_header(engine_id, task)
+        },
+        Err(err) => failed_result(engine_id, task, err.to_string(), err.code()),
+    }
+}
+
+/// A failed result for `task`, e.g. for a timeout that never reached the engine.
+pub fn failed_result(
+    engine_id: u32,
+    task: &TaskMessage,
+    error: String,
+    error_kind: &str,
+) -> TaskResultMessage {
+    TaskResultMessage {
+        success: false,
+        error: Some(error),
+        error_kind: Some(error_kind.to_string()),
+        ..result_header(engine_id, task)
+    }
+}
+
+fn result_header(engine_id: u32, task: &TaskMessage) -> TaskResultMessage {
+    TaskResultMessage {
+        eval_id: task.eval_id.clone(),
+        task_id: task.task_id.clone(),
+        engine_id,
+        speaker_id: task.speaker_id,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_http::serve;
+    use crate::MockTtsEngine;
+
+    fn task() -> TaskMessage {
+        TaskMessage {
+            eval_id: "eval".into(),
+            task_id: "task-1".into(),
+            speaker_id: 3,
+            text: Some("こんにちは".into()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn successful_task_reports_the_engine_output() {
+        let body = br#"{"path": "/out/task-1.wav"}"#.to_vec();
+        let (url, requests) = serve(vec![(200, b"{}".to_vec()), (200, body)]).await;
+        let engine = MockTtsEngine::new(url);
+
+        let result = run_task(&engine, 7, &task(), &CancellationToken::new()).await;
+        assert!(result.success);
+        assert_eq!(result.eval_id, "eval");
+        assert_eq!(result.task_id, "task-1");
+        assert_eq!(result.engine_id, 7);
+        assert_eq!(result.speaker_id, 3);
+        assert_eq!(result.output_file.as_deref(), Some("/out/task-1.wav"));
+        assert_eq!(result.error, None);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [
+                "PUT /evaluations/eval/engines/7/speaker",
+                "POST /evaluations/eval/engines/7/synthesis",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn engine_errors_become_a_failed_result() {
+        let (url, _) = serve(vec![(500, b"boom".to_vec())]).await;
+        let engine = MockTtsEngine::new(url);
+
+        let result = run_task(&engine, 7, &task(), &CancellationToken::new()).await;
+        assert!(!result.success);
+        assert_eq!(result.task_id, "task-1");
+        assert_eq!(result.error_kind.as_deref(), Some("unexpected_status"));
+        assert!(result.error.unwrap().contains("boom"));
+    }
+
+    #[tokio::test]
+    async fn invalid_tasks_fail_without_calling_the_engine() {
+        let (url, requests) = serve(Vec::new()).await;
+        let engine = MockTtsEngine::new(url);
+        let task = TaskMessage {
+            task_id: String::new(),
+            ..task()
+        };
+
+        let result = run_task(&engine, 7, &task, &CancellationToken::new()).await;
+        assert!(!result.success);
+        assert_eq!(result.error_kind.as_deref(), Some("invalid_task"));
+        assert!(requests.lock().unwrap().is_empty());
+    }
+}
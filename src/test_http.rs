@@ -0,0 +1,62 @@
+//! A scripted HTTP server for tests of the engines that call an API.
+
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Answers one request per connection with the next of `responses` (status
+/// and body). Returns the base URL and the request lines seen so far, e.g.
+/// `POST /audio_query?text=a&speaker=1`.
+pub(crate) async fn serve(responses: Vec<(u16, Vec<u8>)>) -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&requests);
+    tokio::spawn(async move {
+        for (status, body) in responses {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let request_line = read_request(&mut socket).await;
+            seen.lock().unwrap().push(request_line);
+            let head = format!(
+                "HTTP/1.1 {} Test\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                status,
+                body.len()
+            );
+            socket.write_all(head.as_bytes()).await.unwrap();
+            socket.write_all(&body).await.unwrap();
+            let _ = socket.shutdown().await;
+        }
+    });
+    (url, requests)
+}
+
+/// Reads the request head and a `content-length` body, returning the method
+/// and target of the request line.
+async fn read_request(socket: &mut TcpStream) -> String {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    let head_end = loop {
+        let read = socket.read(&mut buf).await.unwrap();
+        assert!(read > 0, "connection closed mid-request");
+        request.extend_from_slice(&buf[..read]);
+        if let Some(at) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            break at + 4;
+        }
+    };
+    let head = String::from_utf8_lossy(&request[..head_end]).into_owned();
+    let body_len = head
+        .to_ascii_lowercase()
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:").map(str::to_owned))
+        .map_or(0, |len| len.trim().parse::<usize>().unwrap());
+    while request.len() < head_end + body_len {
+        let read = socket.read(&mut buf).await.unwrap();
+        assert!(read > 0, "connection closed mid-body");
+        request.extend_from_slice(&buf[..read]);
+    }
+    let request_line = head.lines().next().unwrap_or_default();
+    request_line
+        .rsplit_once(' ')
+        .map_or(request_line, |(line, _version)| line)
+        .to_string()
+}
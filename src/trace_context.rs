@@ -0,0 +1,127 @@
+//! W3C Trace Context (`traceparent` / `tracestate`) carried through AMQP.
+//!
+//! The worker does not export spans. It joins the dispatcher's trace by
+//! recording the trace id and a span id of its own on each task's span, and
+//! passes the context on in the result's headers so that whoever consumes the
+//! result stays in the same trace.
+
+use crate::TaskMessage;
+use lapin::types::{AMQPValue, FieldTable};
+use lapin::BasicProperties;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    /// 32 lowercase hex digits.
+    pub trace_id: String,
+    /// 16 lowercase hex digits identifying the span that did the work.
+    pub span_id: String,
+    /// Two hex digits; `01` means sampled.
+    pub flags: String,
+    /// Vendor-specific state, passed on unchanged.
+    pub tracestate: Option<String>,
+}
+
+impl TraceContext {
+    /// Parses a `traceparent` value, returning `None` for anything the spec
+    /// says to ignore: unknown layouts, invalid hex or all-zero ids.
+    pub fn parse(traceparent: &str, tracestate: Option<&str>) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+        // Later versions may append fields; version 00 has exactly four.
+        if !is_hex(version, 2) || version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        if !is_hex(trace_id, 32) || !is_hex(span_id, 16) || !is_hex(flags, 2) {
+            return None;
+        }
+        if is_zero(trace_id) || is_zero(span_id) {
+            return None;
+        }
+        Some(Self {
+            trace_id: trace_id.to_ascii_lowercase(),
+            span_id: span_id.to_ascii_lowercase(),
+            flags: flags.to_ascii_lowercase(),
+            tracestate: tracestate
+                .map(str::trim)
+                .filter(|state| !state.is_empty())
+                .map(str::to_string),
+        })
+    }
+
+    /// The context of a task: its `traceparent` field, else the delivery's
+    /// `traceparent` header.
+    pub fn from_task(task: &TaskMessage, properties: &BasicProperties) -> Option<Self> {
+        match &task.traceparent {
+            Some(traceparent) => Self::parse(traceparent, task.tracestate.as_deref()),
+            None => Self::from_headers(properties),
+        }
+    }
+
+    /// Reads the `traceparent` and `tracestate` headers.
+    pub fn from_headers(properties: &BasicProperties) -> Option<Self> {
+        let headers = properties.headers().as_ref()?;
+        let traceparent = header(headers, "traceparent")?;
+        Self::parse(&traceparent, header(headers, "tracestate").as_deref())
+    }
+
+    /// Context for work done on behalf of this one: the same trace under a
+    /// new span id.
+    pub fn child(&self) -> Self {
+        let span_id = loop {
+            let id: u64 = rand::random();
+            if id != 0 {
+                break format!("{:016x}", id);
+            }
+        };
+        Self {
+            span_id,
+            ..self.clone()
+        }
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{}", self.trace_id, self.span_id, self.flags)
+    }
+
+    /// Adds `traceparent`, and `tracestate` if any, to the headers of
+    /// `properties`.
+    pub fn inject(&self, properties: BasicProperties) -> BasicProperties {
+        let mut headers = properties.headers().clone().unwrap_or_default();
+        headers.insert(
+            "traceparent".into(),
+            AMQPValue::LongString(self.traceparent().into()),
+        );
+        if let Some(state) = &self.tracestate {
+            headers.insert(
+                "tracestate".into(),
+                AMQPValue::LongString(state.as_str().into()),
+            );
+        }
+        properties.with_headers(headers)
+    }
+}
+
+fn header(headers: &FieldTable, name: &str) -> Option<String> {
+    let (_, value) = headers
+        .inner()
+        .iter()
+        .find(|(key, _)| key.as_str() == name)?;
+    match value {
+        AMQPValue::LongString(value) => {
+            Some(String::from_utf8_lossy(value.as_bytes()).into_owned())
+        }
+        AMQPValue::ShortString(value) => Some(value.as_str().to_string()),
+        _ => None,
+    }
+}
+
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len && value.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+fn is_zero(value: &str) -> bool {
+    value.bytes().all(|byte| byte == b'0')
+}
@@ -1,7 +1,13 @@
-use crate::TaskMessage;
+use crate::audio::Levels;
+use crate::{TaskBatchMessage, TaskDialogueMessage, TaskMessage, TaskStage};
 use async_trait::async_trait;
 use std::error::Error;
 use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 pub type EngineResult<T> = Result<T, EngineError>;
 
@@ -14,6 +20,48 @@ pub enum EngineError {
     InvalidTask(String),
     TaskJoin(tokio::task::JoinError),
     Zip(zip::result::ZipError),
+    /// The caller tripped the task's [`CancellationToken`].
+    Cancelled,
+}
+
+impl EngineError {
+    /// Stable, machine-readable category, reported as a result's `error_kind`,
+    /// so clients can tell bad input (`invalid_task`) from engine or
+    /// infrastructure failures.
+    pub fn code(&self) -> &'static str {
+        match self {
+            EngineError::Http(_) => "http",
+            EngineError::UnexpectedStatus(_, _) => "unexpected_status",
+            EngineError::Io(_) => "io",
+            EngineError::Voicevox(_) => "voicevox",
+            EngineError::InvalidTask(_) => "invalid_task",
+            EngineError::TaskJoin(_) => "task_join",
+            EngineError::Zip(_) => "zip",
+            EngineError::Cancelled => "cancelled",
+        }
+    }
+
+    /// Whether trying again may succeed. `Voicevox` errors are treated as
+    /// transient, and so are `Io` errors of an interrupted, timed-out or
+    /// would-block operation, `Http` errors connecting or timing out, and
+    /// `UnexpectedStatus` for a `429` or `5xx` answer. Any other `Io` error,
+    /// such as a missing file, unreadable WAV data or a failed encode, fails
+    /// the same way every time, as do other HTTP errors and statuses and
+    /// everything else.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            EngineError::Voicevox(_) => true,
+            EngineError::Http(err) => err.is_connect() || err.is_timeout(),
+            EngineError::UnexpectedStatus(status, _) => {
+                status.is_server_error() || *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            EngineError::Io(err) => matches!(
+                err.kind(),
+                io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            ),
+            _ => false,
+        }
+    }
 }
 
 impl Display for EngineError {
@@ -28,6 +76,7 @@ impl Display for EngineError {
             EngineError::InvalidTask(err) => write!(f, "invalid task: {}", err),
             EngineError::TaskJoin(err) => write!(f, "task join error: {}", err),
             EngineError::Zip(err) => write!(f, "zip error: {}", err),
+            EngineError::Cancelled => write!(f, "task cancelled"),
         }
     }
 }
@@ -42,6 +91,7 @@ impl Error for EngineError {
             EngineError::InvalidTask(_) => None,
             EngineError::TaskJoin(err) => Some(err),
             EngineError::Zip(err) => Some(err),
+            EngineError::Cancelled => None,
         }
     }
 }
@@ -76,11 +126,250 @@ impl From<zip::result::ZipError> for EngineError {
     }
 }
 
+/// Retries operations that failed with a transient error, as judged by
+/// [`EngineError::is_retryable`]; anything else, notably `InvalidTask`, fails
+/// on the first attempt.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    pub async fn run<T, F, Fut>(&self, mut operation: F) -> EngineResult<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = EngineResult<T>>,
+    {
+        let mut retries = 0;
+        loop {
+            match operation().await {
+                Err(err) if retries < self.max_retries && err.is_retryable() => {
+                    retries += 1;
+                    tokio::time::sleep(self.delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// What an engine reports for a successfully processed task.
+#[derive(Debug, Default)]
+pub struct TaskOutput {
+    pub output_file: Option<String>,
+    pub synthesis_ms: Option<u64>,
+    pub audio_duration_ms: Option<f64>,
+    /// Lowercase hex SHA-256 of the bytes written to `output_file`.
+    pub output_sha256: Option<String>,
+    /// Length of the encoded audio, whether written or returned inline.
+    pub output_bytes: Option<u64>,
+    /// The task was only validated; nothing was synthesized or written.
+    pub dry_run: bool,
+    /// Base64 audio for tasks that asked for it inline instead of a file.
+    pub audio_base64: Option<String>,
+    /// Levels measured for tasks with `report_levels`.
+    pub levels: Option<Levels>,
+}
+
+/// Asks an engine to give up on a task, e.g. after a timeout. Clones share
+/// one flag. Engines check it between stages; a VOICEVOX call already running
+/// cannot be interrupted and finishes first.
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<TokenState>);
+
+#[derive(Default)]
+struct TokenState {
+    cancelled: AtomicBool,
+    /// Values kept alive by [`CancellationToken::hold`].
+    held: Mutex<Vec<Box<dyn Send>>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Keeps `value` alive until the last clone of the token is dropped.
+    /// Engines hand a clone to any blocking work they start, so a guard held
+    /// here outlives a timed-out or cancelled task until that work returns.
+    pub fn hold(&self, value: impl Send + 'static) {
+        self.0
+            .held
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push(Box::new(value));
+    }
+
+    /// Fails with [`EngineError::Cancelled`] once the token has been tripped.
+    pub fn check(&self) -> EngineResult<()> {
+        if self.is_cancelled() {
+            Err(EngineError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("CancellationToken")
+            .field(&self.is_cancelled())
+            .finish()
+    }
+}
+
+/// Receives stage notifications while an engine works on a task. Called from
+/// blocking synthesis threads, so implementations must not block on async work.
+pub trait ProgressSink: Send + Sync {
+    fn report(&self, eval_id: &str, task_id: &str, stage: TaskStage);
+}
+
 #[async_trait]
 pub trait TtsEngine: Send + Sync {
+    /// Processes one task. Once `cancel` is tripped the engine should stop at
+    /// its next opportunity and return [`EngineError::Cancelled`].
     async fn process_task(
         &self,
         engine_id: u32,
         message: &TaskMessage,
-    ) -> EngineResult<Option<String>>;
+        cancel: &CancellationToken,
+    ) -> EngineResult<TaskOutput>;
+
+    /// Processes batch items in order, returning one result per item.
+    async fn process_batch(
+        &self,
+        engine_id: u32,
+        batch: &TaskBatchMessage,
+        cancel: &CancellationToken,
+    ) -> Vec<EngineResult<TaskOutput>> {
+        let mut results = Vec::with_capacity(batch.items.len());
+        for index in 0..batch.items.len() {
+            let message = batch.task_message(index);
+            results.push(self.process_task(engine_id, &message, cancel).await);
+        }
+        results
+    }
+
+    /// Synthesizes every segment of a dialogue into one output file.
+    async fn process_dialogue(
+        &self,
+        _engine_id: u32,
+        _dialogue: &TaskDialogueMessage,
+        _cancel: &CancellationToken,
+    ) -> EngineResult<TaskOutput> {
+        Err(EngineError::InvalidTask(
+            "dialogue tasks are not supported by this engine".into(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    fn counted_run(policy: RetryPolicy, error: fn() -> EngineError) -> (EngineResult<()>, u32) {
+        let attempts = AtomicU32::new(0);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let result = runtime.block_on(policy.run(|| {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            async move { Err::<(), _>(error()) }
+        }));
+        (result, attempts.into_inner())
+    }
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        max_retries: 3,
+        delay: Duration::ZERO,
+    };
+
+    #[test]
+    fn invalid_task_is_never_retried() {
+        let (result, attempts) = counted_run(POLICY, || EngineError::InvalidTask("bad".into()));
+        assert!(matches!(result, Err(EngineError::InvalidTask(_))));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn transient_errors_are_retried_up_to_the_limit() {
+        let (result, attempts) = counted_run(POLICY, || EngineError::Voicevox("busy".into()));
+        assert!(matches!(result, Err(EngineError::Voicevox(_))));
+        assert_eq!(attempts, 4);
+
+        let (_, attempts) = counted_run(POLICY, || {
+            EngineError::Io(io::Error::from(io::ErrorKind::TimedOut))
+        });
+        assert_eq!(attempts, 4);
+    }
+
+    fn join_error() -> tokio::task::JoinError {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(tokio::spawn(async { panic!("task failed") }))
+            .unwrap_err()
+    }
+
+    #[test]
+    fn each_variant_has_a_stable_code() {
+        let http = reqwest::Client::new().get("not a url").build().unwrap_err();
+        let cases = [
+            (EngineError::Http(http), "http"),
+            (
+                EngineError::UnexpectedStatus(reqwest::StatusCode::BAD_REQUEST, String::new()),
+                "unexpected_status",
+            ),
+            (EngineError::Io(io::Error::from(io::ErrorKind::Other)), "io"),
+            (EngineError::Voicevox("busy".into()), "voicevox"),
+            (EngineError::InvalidTask("bad".into()), "invalid_task"),
+            (EngineError::TaskJoin(join_error()), "task_join"),
+            (EngineError::Zip(zip::result::ZipError::FileNotFound), "zip"),
+            (EngineError::Cancelled, "cancelled"),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.code(), code, "{}", error);
+        }
+    }
+
+    #[test]
+    fn only_transient_http_failures_are_retried() {
+        let http = reqwest::Client::new().get("not a url").build().unwrap_err();
+        assert!(!EngineError::Http(http).is_retryable());
+
+        for (status, retryable) in [
+            (reqwest::StatusCode::SERVICE_UNAVAILABLE, true),
+            (reqwest::StatusCode::INTERNAL_SERVER_ERROR, true),
+            (reqwest::StatusCode::TOO_MANY_REQUESTS, true),
+            (reqwest::StatusCode::BAD_REQUEST, false),
+            (reqwest::StatusCode::NOT_FOUND, false),
+        ] {
+            let error = EngineError::UnexpectedStatus(status, String::new());
+            assert_eq!(error.is_retryable(), retryable, "{}", status);
+        }
+    }
+
+    #[test]
+    fn deterministic_io_errors_are_not_retried() {
+        for kind in [
+            io::ErrorKind::NotFound,
+            io::ErrorKind::InvalidData,
+            io::ErrorKind::Other,
+        ] {
+            let error = EngineError::Io(io::Error::from(kind));
+            assert!(!error.is_retryable(), "{:?}", kind);
+        }
+    }
 }
@@ -1,53 +1,335 @@
 use crate::{
-    tts::{EngineError, EngineResult, TtsEngine},
-    TaskMessage,
+    audio, metrics,
+    output_sink::OutputSink,
+    tts::{
+        CancellationToken, EngineError, EngineResult, ProgressSink, RetryPolicy, TaskOutput,
+        TtsEngine,
+    },
+    BitDepth, DialogueSegment, OutputFormat, TaskBatchMessage, TaskDialogueMessage, TaskMessage,
+    TaskStage,
 };
 use async_trait::async_trait;
+use base64::prelude::{Engine as _, BASE64_STANDARD};
 use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
-    collections::HashMap,
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    env,
     ffi::OsStr,
+    fmt::{self, Display, Formatter},
     fs, io,
-    path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    num::NonZeroUsize,
+    ops::Deref,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, TryLockError,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 use tokio::task;
+use tracing::{info, warn};
 use voicevox_core::{
-    blocking::{Onnxruntime, OpenJtalk, Synthesizer, VoiceModelFile},
-    StyleId,
+    blocking::{Onnxruntime, OpenJtalk, Synthesizer, UserDict, VoiceModelFile},
+    AccentPhrase, AudioQuery, StyleId, VoiceModelId,
 };
-#[derive(Debug)]
+
+/// Which ONNX Runtime execution provider the synthesizers should use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccelerationMode {
+    /// Let VOICEVOX pick the GPU when one is usable, otherwise the CPU.
+    #[default]
+    Auto,
+    Cpu,
+    /// CUDA or DirectML; construction fails when neither is available.
+    Gpu,
+}
+
+impl FromStr for AccelerationMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "auto" => Ok(AccelerationMode::Auto),
+            "cpu" => Ok(AccelerationMode::Cpu),
+            "gpu" => Ok(AccelerationMode::Gpu),
+            other => Err(format!(
+                "invalid acceleration mode '{}' (expected auto, cpu or gpu)",
+                other
+            )),
+        }
+    }
+}
+
+impl Display for AccelerationMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            AccelerationMode::Auto => write!(f, "auto"),
+            AccelerationMode::Cpu => write!(f, "cpu"),
+            AccelerationMode::Gpu => write!(f, "gpu"),
+        }
+    }
+}
+
+impl From<AccelerationMode> for voicevox_core::AccelerationMode {
+    fn from(mode: AccelerationMode) -> Self {
+        match mode {
+            AccelerationMode::Auto => voicevox_core::AccelerationMode::Auto,
+            AccelerationMode::Cpu => voicevox_core::AccelerationMode::Cpu,
+            AccelerationMode::Gpu => voicevox_core::AccelerationMode::Gpu,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
 pub struct VoicevoxConfig {
     pub onnxruntime_path: Option<PathBuf>,
+    /// Files, or directories holding the library, tried in order when
+    /// `onnxruntime_path` is unset, before ONNX Runtime's own default lookup.
+    /// Empty means the executable's directory, its `lib` subdirectory and
+    /// [`DEFAULT_ORT_SEARCH_DIRS`].
+    pub onnxruntime_search_paths: Vec<PathBuf>,
+    #[serde(serialize_with = "serialize_utf8_path")]
     pub open_jtalk_dict_dir: Utf8PathBuf,
+    /// Further Open JTalk dictionaries, selected by a task's `dict_name`.
+    /// Each one gets a pool of `pool_size` synthesizers of its own.
+    pub named_dicts: BTreeMap<String, PathBuf>,
+    #[serde(serialize_with = "serialize_utf8_path")]
     pub model_dir: Utf8PathBuf,
+    /// Number of synthesizers to keep; values below 1 are treated as 1.
+    pub pool_size: usize,
+    /// Extra attempts for a task that failed with a transient error.
+    pub max_retries: u32,
+    pub retry_delay_ms: u64,
+    /// Style ids whose models are loaded into every synthesizer at startup.
+    pub preload_styles: Vec<u32>,
+    /// Preload every discovered style, ignoring `preload_styles`.
+    pub preload_all_styles: bool,
+    /// Synthesize a short phrase on every synthesizer during construction so
+    /// ONNX Runtime and Open JTalk finish their lazy setup before the first
+    /// task. A failed warmup is logged, not returned.
+    pub warmup: bool,
+    /// Style id used for the warmup; `None` picks the lowest known id.
+    pub warmup_style: Option<u32>,
+    /// When set, every output file must resolve to a path inside this directory,
+    /// and a relative task `output_dir` is taken relative to it rather than to
+    /// the working directory.
+    pub output_root: Option<PathBuf>,
+    /// Directory a task's `text_path` must resolve inside; a relative path is
+    /// taken relative to it. Without one, tasks with `text_path` are rejected.
+    pub text_root: Option<PathBuf>,
+    /// When set, only these speaker ids are accepted.
+    pub allowed_speakers: Option<HashSet<u32>>,
+    /// VOICEVOX user dictionary (JSON) applied to the Open JTalk analyzer.
+    pub user_dict_path: Option<PathBuf>,
+    pub acceleration: AccelerationMode,
+    /// Threads per synthesizer; `Some(0)` or `None` lets VOICEVOX decide.
+    pub cpu_num_threads: Option<u16>,
+    /// Validate tasks and report success without synthesizing or writing audio.
+    pub dry_run: bool,
+    /// Destination for synthesized files; defaults to the local filesystem.
+    pub output_sink: OutputSink,
+    /// Largest base64 `audio_base64`, in bytes, returned inline in a result
+    /// message.
+    pub max_inline_audio_bytes: usize,
+    /// Fail construction when two model files expose the same style id instead
+    /// of warning and keeping the first.
+    pub strict_models: bool,
+    /// Fail construction, listing every bad file, when a model file cannot be
+    /// opened or exposes no styles. Otherwise the first unreadable file stops
+    /// construction and style-less files are ignored.
+    pub validate_models: bool,
+    /// Output filename for tasks without a `result_filename`; see
+    /// [`DEFAULT_FILENAME_TEMPLATE`] for the placeholders. `None` uses the default.
+    pub filename_template: Option<String>,
+    /// Let the rendered template contain `/` so outputs land in subdirectories.
+    pub filename_template_subdirs: bool,
+    /// Translates task speaker ids into VOICEVOX style ids; unmapped ids are
+    /// used as style ids directly.
+    pub speaker_map: HashMap<u32, u32>,
+    /// Prosody applied to every task synthesized with a style id, unless the
+    /// task sets that parameter itself.
+    pub speaker_defaults: HashMap<u32, SynthesisParams>,
+    /// Level in dBFS below which `trim_silence` treats audio as silent.
+    pub silence_threshold_db: f32,
+    /// Sample frames VOICEVOX must return for a synthesis to count; shorter
+    /// output, such as a header-only WAV, fails the task. 0 disables the check.
+    pub min_output_frames: usize,
+    /// Voice models each synthesizer keeps loaded before unloading the least
+    /// recently used one; 0 means no limit.
+    pub model_cache_capacity: usize,
+    /// File and directory extensions recognized as voice models, compared
+    /// case-insensitively and without the leading dot. Empty means `vvm`.
+    pub model_extensions: Vec<String>,
+    /// Descend into model subdirectories whose names are not valid UTF-8,
+    /// logging them lossily, instead of failing model discovery.
+    pub allow_lossy_paths: bool,
+    /// Longest text, in Unicode scalar values, a task may ask to synthesize.
+    pub max_text_chars: Option<usize>,
+    /// Told about every model file read while discovering styles at startup.
+    #[serde(skip)]
+    pub model_scan_progress: Option<ModelScanProgress>,
 }
 
+/// Callback receiving `(read, total)` after each voice model file is opened
+/// during style discovery. Files are read on several threads, so calls come
+/// from any of them and not necessarily in order of `read`.
+#[derive(Clone)]
+pub struct ModelScanProgress(Arc<dyn Fn(usize, usize) + Send + Sync>);
+
+impl ModelScanProgress {
+    pub fn new(callback: impl Fn(usize, usize) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for ModelScanProgress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("ModelScanProgress(..)")
+    }
+}
+
+fn serialize_utf8_path<S: serde::Serializer>(
+    path: &Utf8PathBuf,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(path.as_str())
+}
+
+/// Filename used when neither the task nor the config chooses one.
+///
+/// Templates may use `{eval_id}`, `{task_id}`, `{speaker_id}`, `{index}` (the
+/// batch position, zero-padded to four digits) and `{ext}` (the output format's
+/// extension). The extension is appended when the rendered name has none.
+pub const DEFAULT_FILENAME_TEMPLATE: &str = "{eval_id}.{ext}";
+
+/// Directories searched for ONNX Runtime when no search paths are configured,
+/// after those next to the executable.
+pub const DEFAULT_ORT_SEARCH_DIRS: &[&str] = &[
+    "lib",
+    "/usr/local/lib",
+    "/usr/lib",
+    "/opt/voicevox_core/lib",
+];
+
+/// VOICEVOX engine backed by a pool of synthesizers.
+///
+/// Voice models cannot be shared between `Synthesizer` instances, so each pool
+/// member loads models lazily, keeping up to `model_cache_capacity` of them.
+/// The opened `.vvm` files are shared, so a model's file is read once however
+/// many members load it. To avoid loading the same model into every member, a
+/// task prefers an idle member that already has its style loaded, then any
+/// idle member, and only blocks when all members are busy.
+///
+/// A synthesizer's text analyzer is fixed when it is built, so every entry of
+/// `named_dicts` is served by a separate pool whose synthesizers were built
+/// with that dictionary. Tasks naming it only use that pool. This costs a set
+/// of synthesizers, and their loaded models, per dictionary, but switching
+/// dictionaries never reloads anything.
 pub struct VoicevoxTtsEngine {
-    synthesizer: Arc<Mutex<Synthesizer<OpenJtalk>>>,
-    model_paths: Arc<HashMap<u32, PathBuf>>,
+    synthesizers: Arc<Vec<Mutex<PoolMember>>>,
+    /// Pools for `VoicevoxConfig::named_dicts`, by name.
+    dict_pools: HashMap<String, Arc<Vec<Mutex<PoolMember>>>>,
+    next_synthesizer: AtomicUsize,
+    models: Arc<ModelRegistry>,
+    retry: RetryPolicy,
+    output_root: Option<PathBuf>,
+    /// Canonical `VoicevoxConfig::text_root`.
+    text_root: Option<PathBuf>,
+    allowed_speakers: Option<HashSet<u32>>,
+    progress: Option<Arc<dyn ProgressSink>>,
+    dry_run: bool,
+    output_sink: OutputSink,
+    max_inline_audio_bytes: usize,
+    filename_template: String,
+    filename_template_subdirs: bool,
+    speaker_map: HashMap<u32, u32>,
+    speaker_defaults: HashMap<u32, SynthesisParams>,
+    silence_threshold_db: f32,
+    min_output_frames: usize,
+    max_text_chars: Option<usize>,
 }
 
 impl VoicevoxTtsEngine {
     pub fn new(config: VoicevoxConfig) -> EngineResult<Self> {
         let VoicevoxConfig {
             onnxruntime_path,
+            onnxruntime_search_paths,
             open_jtalk_dict_dir,
+            named_dicts,
             model_dir,
+            pool_size,
+            max_retries,
+            retry_delay_ms,
+            preload_styles,
+            preload_all_styles,
+            warmup,
+            warmup_style,
+            output_root,
+            text_root,
+            allowed_speakers,
+            user_dict_path,
+            acceleration,
+            cpu_num_threads,
+            dry_run,
+            output_sink,
+            max_inline_audio_bytes,
+            strict_models,
+            validate_models,
+            filename_template,
+            filename_template_subdirs,
+            speaker_map,
+            speaker_defaults,
+            silence_threshold_db,
+            min_output_frames,
+            model_cache_capacity,
+            model_extensions,
+            allow_lossy_paths,
+            max_text_chars,
+            model_scan_progress,
         } = config;
 
-        let ort_builder = Onnxruntime::load_once();
+        let filename_template =
+            filename_template.unwrap_or_else(|| DEFAULT_FILENAME_TEMPLATE.to_string());
+        let sample = TaskMessage {
+            index: Some(0),
+            ..Default::default()
+        };
+        render_filename_template(&filename_template, &sample, OutputFormat::Wav)?;
+
+        let output_root = output_root
+            .map(|root| resolve_root(&root, "output root"))
+            .transpose()?;
+        let text_root = text_root
+            .map(|root| resolve_root(&root, "text root"))
+            .transpose()?;
+
         let ort = match onnxruntime_path {
-            Some(path) => ort_builder.filename(path).perform()?,
-            None => ort_builder.perform()?,
+            Some(path) => Onnxruntime::load_once().filename(path).perform()?,
+            None => load_onnxruntime(&onnxruntime_search_paths)?,
         };
 
-        let text_analyzer = OpenJtalk::new(open_jtalk_dict_dir.as_path())?;
-        let synthesizer = Synthesizer::builder(ort)
-            .text_analyzer(text_analyzer)
-            .build()?;
+        if acceleration == AccelerationMode::Gpu {
+            let devices = ort.supported_devices()?;
+            if !devices.cuda && !devices.dml {
+                return Err(EngineError::Voicevox(
+                    "GPU acceleration requested but neither CUDA nor DirectML is available to ONNX Runtime".into(),
+                ));
+            }
+        }
 
-        let model_paths = prepare_models(model_dir.as_path())?;
+        let model_paths = prepare_models(
+            model_dir.as_path(),
+            &model_extensions,
+            strict_models,
+            validate_models,
+            allow_lossy_paths,
+            model_scan_progress.as_ref(),
+        )?;
 
         if model_paths.is_empty() {
             return Err(EngineError::InvalidTask(format!(
@@ -56,11 +338,230 @@ impl VoicevoxTtsEngine {
             )));
         }
 
+        let mut mapping: Vec<_> = speaker_map.iter().collect();
+        mapping.sort_unstable();
+        if let Some((speaker, style)) = mapping
+            .into_iter()
+            .find(|(_, style)| !model_paths.contains_key(style))
+        {
+            return Err(EngineError::InvalidTask(format!(
+                "speaker map sends speaker {} to unknown style id {}",
+                speaker, style
+            )));
+        }
+
+        let mut defaults: Vec<_> = speaker_defaults.iter().collect();
+        defaults.sort_unstable_by_key(|(style, _)| **style);
+        for (style, params) in defaults {
+            if !model_paths.contains_key(style) {
+                return Err(EngineError::InvalidTask(format!(
+                    "speaker defaults given for unknown style id {}",
+                    style
+                )));
+            }
+            if !params.is_finite() {
+                return Err(EngineError::InvalidTask(format!(
+                    "speaker defaults for style id {} must be finite numbers",
+                    style
+                )));
+            }
+        }
+
+        let user_dict = match user_dict_path {
+            Some(path) => {
+                if !path.is_file() {
+                    return Err(EngineError::Io(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("user dictionary not found: {}", path.display()),
+                    )));
+                }
+                let user_dict = UserDict::new();
+                user_dict.load(&path)?;
+                info!(path = %path.display(), "loaded user dictionary");
+                Some(user_dict)
+            }
+            None => None,
+        };
+
+        // The default pool comes first, then one pool per named dictionary in
+        // name order; they are split apart again after preloading and warmup.
+        let pool_size = pool_size.max(1);
+        let mut text_analyzers = vec![open_text_analyzer(
+            open_jtalk_dict_dir.as_path(),
+            user_dict.as_ref(),
+        )?];
+        for (name, dir) in &named_dicts {
+            let dir = Utf8Path::from_path(dir).ok_or_else(|| {
+                EngineError::InvalidTask(format!(
+                    "dictionary {} path {} is not valid UTF-8",
+                    name,
+                    dir.display()
+                ))
+            })?;
+            text_analyzers.push(open_text_analyzer(dir, user_dict.as_ref())?);
+            info!(name = %name, path = %dir, "loaded named dictionary");
+        }
+        let synthesizers = text_analyzers
+            .iter()
+            .flat_map(|text_analyzer| (0..pool_size).map(move |_| text_analyzer))
+            .map(|text_analyzer| {
+                build_synthesizer(ort, text_analyzer, acceleration, cpu_num_threads)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| match acceleration {
+                AccelerationMode::Gpu => {
+                    EngineError::Voicevox(format!("failed to create GPU synthesizer: {}", err))
+                }
+                _ => EngineError::from(err),
+            })?;
+        info!(
+            acceleration = %acceleration,
+            gpu = synthesizers.first().is_some_and(|synthesizer| synthesizer.is_gpu_mode()),
+            "created synthesizers"
+        );
+
+        let preload_styles = if preload_all_styles {
+            model_paths.keys().copied().collect()
+        } else {
+            preload_styles
+        };
+        let models = ModelRegistry::new(model_paths);
+        let mut synthesizers: Vec<_> = synthesizers
+            .into_iter()
+            .map(|synthesizer| PoolMember {
+                synthesizer,
+                lru: ModelLru::new(model_cache_capacity),
+            })
+            .collect();
+        if !preload_styles.is_empty() && !dry_run {
+            let loaded = preload_models(
+                &mut synthesizers,
+                &models,
+                &preload_styles,
+                model_cache_capacity,
+            )?;
+            info!(
+                models = loaded,
+                styles = preload_styles.len(),
+                synthesizers = synthesizers.len(),
+                "preloaded voice models"
+            );
+        }
+        if warmup && !dry_run {
+            let style_id = warmup_style.or_else(|| models.paths.keys().min().copied());
+            if let Some(style_id) = style_id {
+                let started = Instant::now();
+                match warm_up(&mut synthesizers, &models, style_id) {
+                    Ok(()) => info!(
+                        style_id,
+                        synthesizers = synthesizers.len(),
+                        elapsed_ms = started.elapsed().as_millis() as u64,
+                        "warmup synthesis finished"
+                    ),
+                    Err(err) => warn!(style_id, error = %err, "warmup synthesis failed"),
+                }
+            }
+        }
+        let mut synthesizers = synthesizers.into_iter().map(Mutex::new);
+        let default_pool: Vec<_> = synthesizers.by_ref().take(pool_size).collect();
+        let dict_pools = named_dicts
+            .into_keys()
+            .map(|name| {
+                let pool: Vec<_> = synthesizers.by_ref().take(pool_size).collect();
+                (name, Arc::new(pool))
+            })
+            .collect();
+
         Ok(Self {
-            synthesizer: Arc::new(Mutex::new(synthesizer)),
-            model_paths: Arc::new(model_paths),
+            synthesizers: Arc::new(default_pool),
+            dict_pools,
+            next_synthesizer: AtomicUsize::new(0),
+            models: Arc::new(models),
+            retry: RetryPolicy {
+                max_retries,
+                delay: Duration::from_millis(retry_delay_ms),
+            },
+            output_root,
+            text_root,
+            allowed_speakers,
+            progress: None,
+            dry_run,
+            output_sink,
+            max_inline_audio_bytes,
+            filename_template,
+            filename_template_subdirs,
+            speaker_map,
+            speaker_defaults,
+            silence_threshold_db,
+            min_output_frames,
+            max_text_chars,
         })
     }
+
+    /// Reports model loading, synthesis and output writing for every task to `sink`.
+    pub fn with_progress(mut self, sink: Arc<dyn ProgressSink>) -> Self {
+        self.progress = Some(sink);
+        self
+    }
+
+    pub fn pool_size(&self) -> usize {
+        self.synthesizers.len()
+    }
+
+    /// The pool serving `dict_name`, or the default pool for `None`.
+    fn pool_for(&self, dict_name: Option<&str>) -> EngineResult<&Arc<Vec<Mutex<PoolMember>>>> {
+        match dict_name {
+            None => Ok(&self.synthesizers),
+            Some(name) => self
+                .dict_pools
+                .get(name)
+                .ok_or_else(|| EngineError::InvalidTask(format!("unknown dictionary {}", name))),
+        }
+    }
+
+    /// Version of the linked `voicevox_core`.
+    pub fn core_version(&self) -> &'static str {
+        voicevox_core::VERSION
+    }
+
+    /// Number of distinct voice model files backing the known styles.
+    pub fn model_count(&self) -> usize {
+        self.models.paths.values().collect::<HashSet<_>>().len()
+    }
+
+    /// Every style id this engine can synthesize, sorted.
+    pub fn supported_styles(&self) -> Vec<u32> {
+        servable_styles(
+            self.models.paths.keys().copied(),
+            self.allowed_speakers.as_ref(),
+            &self.speaker_map,
+        )
+    }
+}
+
+/// The loaded `styles` a task may reach, sorted. `allowed` holds request
+/// speaker ids, so they go through `speaker_map` before being compared with
+/// style ids.
+fn servable_styles(
+    styles: impl Iterator<Item = u32>,
+    allowed: Option<&HashSet<u32>>,
+    speaker_map: &HashMap<u32, u32>,
+) -> Vec<u32> {
+    let reachable: Option<HashSet<u32>> = allowed.map(|allowed| {
+        allowed
+            .iter()
+            .map(|speaker| speaker_map.get(speaker).copied().unwrap_or(*speaker))
+            .collect()
+    });
+    let mut styles: Vec<u32> = styles
+        .filter(|style| {
+            reachable
+                .as_ref()
+                .map_or(true, |reachable| reachable.contains(style))
+        })
+        .collect();
+    styles.sort_unstable();
+    styles
 }
 
 #[async_trait]
@@ -69,115 +570,2247 @@ impl TtsEngine for VoicevoxTtsEngine {
         &self,
         _engine_id: u32,
         message: &TaskMessage,
-    ) -> EngineResult<Option<String>> {
-        let text = message
-            .text
-            .as_ref()
-            .ok_or_else(|| EngineError::InvalidTask("missing text for synthesis".into()))?
-            .to_owned();
+        cancel: &CancellationToken,
+    ) -> EngineResult<TaskOutput> {
+        let start = self.next_synthesizer.fetch_add(1, Ordering::Relaxed);
+        self.run_task(message, start, cancel).await
+    }
+
+    /// Runs the whole batch against one preferred pool member, grouping items by
+    /// speaker so each model is loaded at most once for the batch. Results are
+    /// returned in the original item order.
+    async fn process_batch(
+        &self,
+        _engine_id: u32,
+        batch: &TaskBatchMessage,
+        cancel: &CancellationToken,
+    ) -> Vec<EngineResult<TaskOutput>> {
+        let start = self.next_synthesizer.fetch_add(1, Ordering::Relaxed);
+
+        let mut order: Vec<usize> = (0..batch.items.len()).collect();
+        order.sort_by_key(|&index| batch.items[index].speaker_id);
+
+        let mut results: Vec<Option<EngineResult<TaskOutput>>> =
+            (0..batch.items.len()).map(|_| None).collect();
+        for index in order {
+            let message = batch.task_message(index);
+            results[index] = Some(self.run_task(&message, start, cancel).await);
+        }
+
+        results.into_iter().flatten().collect()
+    }
+
+    async fn process_dialogue(
+        &self,
+        _engine_id: u32,
+        dialogue: &TaskDialogueMessage,
+        cancel: &CancellationToken,
+    ) -> EngineResult<TaskOutput> {
+        let start = self.next_synthesizer.fetch_add(1, Ordering::Relaxed);
+        self.run_dialogue(dialogue, start, cancel).await
+    }
+}
+
+impl VoicevoxTtsEngine {
+    /// Synthesizes one task, preferring the pool member at `start`.
+    async fn run_task(
+        &self,
+        message: &TaskMessage,
+        start: usize,
+        cancel: &CancellationToken,
+    ) -> EngineResult<TaskOutput> {
+        cancel.check()?;
+        message.validate()?;
+
+        if let Some(allowed) = &self.allowed_speakers {
+            if !allowed.contains(&message.speaker_id) {
+                return Err(EngineError::InvalidTask(format!(
+                    "speaker {} is not allowed on this worker",
+                    message.speaker_id
+                )));
+            }
+        }
+
+        let text = TaskText::from_task(message, self.text_root.as_deref())?;
+        let synthesizers = Arc::clone(self.pool_for(message.dict_name.as_deref())?);
+
+        if let Some(rate) = message.target_sample_rate {
+            audio::check_sample_rate(rate)?;
+        }
+
+        if let Some(lufs) = message.normalize_lufs {
+            audio::check_loudness_target(lufs)?;
+        }
+
+        // Without an output directory, `inline_audio` returns the audio in the
+        // result message instead of writing it anywhere.
+        let inline_audio = message.inline_audio && message.output_dir.is_none();
 
+        let output_dir = match &message.output_dir {
+            Some(dir) => dir.to_owned(),
+            None if inline_audio => String::new(),
+            None => return Err(EngineError::InvalidTask("missing output directory".into())),
+        };
+
+        let output_format = message.output_format;
+        let output_path = self.output_path(message, output_dir, output_format)?;
+
+        let style_id = self
+            .speaker_map
+            .get(&message.speaker_id)
+            .copied()
+            .unwrap_or(message.speaker_id);
+
+        if let Some(target) = message.morph_target_speaker {
+            self.check_morphing(style_id, target)?;
+        }
+
+        let job = SynthesisJob {
+            eval_id: message.eval_id.clone(),
+            task_id: message.task_id.clone(),
+            synthesizers,
+            models: Arc::clone(&self.models),
+            start,
+            speaker_id: message.speaker_id,
+            style_id,
+            text,
+            text_replacements: message.text_replacements.clone(),
+            prosody: Prosody::from_task(message, self.speaker_defaults.get(&style_id)),
+            output_format,
+            output_path,
+            output_root: self.output_root.clone(),
+            write_metadata: message.write_metadata,
+            emit_timing: message.emit_timing,
+            emit_audio_query: message.emit_audio_query,
+            progress: self.progress.clone(),
+            output_sink: self.output_sink.clone(),
+            inline_audio_limit: inline_audio.then_some(self.max_inline_audio_bytes),
+            target_sample_rate: message.target_sample_rate,
+            normalize_lufs: message.normalize_lufs,
+            trim_silence_db: message.trim_silence.then_some(self.silence_threshold_db),
+            output_bit_depth: message.output_bit_depth,
+            skip_if_exists: message.skip_if_exists,
+            dict_name: message.dict_name.clone(),
+            embed_metadata: message.embed_metadata,
+            report_levels: message.report_levels,
+            min_output_frames: self.min_output_frames,
+            max_text_chars: self.max_text_chars,
+            cancel: cancel.clone(),
+        };
+
+        if self.dry_run {
+            return task::spawn_blocking(move || job.validate()).await?;
+        }
+
+        let started = Instant::now();
+        let mut output = self
+            .retry
+            .run(|| {
+                let job = job.clone();
+                async move { task::spawn_blocking(move || job.run()).await? }
+            })
+            .await?;
+        output.synthesis_ms = Some(started.elapsed().as_millis() as u64);
+
+        Ok(output)
+    }
+
+    /// Synthesizes the segments of a dialogue in order, preferring the pool
+    /// member at `start`, and writes them as one WAV file.
+    async fn run_dialogue(
+        &self,
+        dialogue: &TaskDialogueMessage,
+        start: usize,
+        cancel: &CancellationToken,
+    ) -> EngineResult<TaskOutput> {
+        dialogue.validate()?;
+
+        let mut segments = Vec::with_capacity(dialogue.segments.len());
+        for segment in &dialogue.segments {
+            if let Some(allowed) = &self.allowed_speakers {
+                if !allowed.contains(&segment.speaker_id) {
+                    return Err(EngineError::InvalidTask(format!(
+                        "speaker {} is not allowed on this worker",
+                        segment.speaker_id
+                    )));
+                }
+            }
+            let style_id = self
+                .speaker_map
+                .get(&segment.speaker_id)
+                .copied()
+                .unwrap_or(segment.speaker_id);
+            segments.push((style_id, segment.clone()));
+        }
+        check_text_length(
+            dialogue
+                .segments
+                .iter()
+                .map(|segment| segment.text.chars().count())
+                .sum(),
+            self.max_text_chars,
+        )?;
+
+        let message = dialogue.task_message();
         let output_dir = message
             .output_dir
-            .as_ref()
-            .ok_or_else(|| EngineError::InvalidTask("missing output directory".into()))?
-            .to_owned();
-
-        let filename = message
-            .result_filename
             .clone()
-            .unwrap_or_else(|| format!("{}.wav", message.eval_id));
+            .ok_or_else(|| EngineError::InvalidTask("missing output directory".into()))?;
+        let output_path = self.output_path(&message, output_dir, message.output_format)?;
 
-        let synthesizer = Arc::clone(&self.synthesizer);
-        let model_paths = Arc::clone(&self.model_paths);
-        let style_id = message.speaker_id;
+        let job = DialogueJob {
+            eval_id: message.eval_id.clone(),
+            task_id: message.task_id.clone(),
+            synthesizers: Arc::clone(&self.synthesizers),
+            models: Arc::clone(&self.models),
+            start,
+            segments,
+            output_format: message.output_format,
+            output_path,
+            output_root: self.output_root.clone(),
+            progress: self.progress.clone(),
+            output_sink: self.output_sink.clone(),
+            min_output_frames: self.min_output_frames,
+            cancel: cancel.clone(),
+        };
 
-        let output_path = PathBuf::from(output_dir).join(filename);
-        let output_path_clone = output_path.clone();
+        if self.dry_run {
+            return task::spawn_blocking(move || job.validate()).await?;
+        }
 
-        let result_path = task::spawn_blocking(move || {
-            let guard = synthesizer
-                .lock()
-                .map_err(|_| EngineError::Voicevox("synthesizer lock poisoned".into()))?;
+        let started = Instant::now();
+        let mut output = self
+            .retry
+            .run(|| {
+                let job = job.clone();
+                async move { task::spawn_blocking(move || job.run()).await? }
+            })
+            .await?;
+        output.synthesis_ms = Some(started.elapsed().as_millis() as u64);
 
-            if !guard.is_loaded_model_by_style_id(StyleId(style_id)) {
-                let path = model_paths.get(&style_id).ok_or_else(|| {
-                    EngineError::InvalidTask(format!("unknown speaker/style id {}", style_id))
-                })?;
-                let voice_model = VoiceModelFile::open(path)?;
-                guard.load_voice_model(&voice_model)?;
+        Ok(output)
+    }
+
+    /// Rejects a morphing task. `voicevox_core` has no morphing API; the
+    /// VOICEVOX engine server implements it on top of the WORLD vocoder, which
+    /// is not linked here. Bad requests still get their specific error first.
+    fn check_morphing(&self, style_id: u32, target_speaker: u32) -> EngineResult<()> {
+        if let Some(allowed) = &self.allowed_speakers {
+            if !allowed.contains(&target_speaker) {
+                return Err(EngineError::InvalidTask(format!(
+                    "speaker {} is not allowed on this worker",
+                    target_speaker
+                )));
+            }
+        }
+        let target_style = self
+            .speaker_map
+            .get(&target_speaker)
+            .copied()
+            .unwrap_or(target_speaker);
+        for style in [style_id, target_style] {
+            if !self.models.paths.contains_key(&style) {
+                return Err(EngineError::InvalidTask(format!(
+                    "unknown speaker/style id {}",
+                    style
+                )));
             }
+        }
+        Err(EngineError::InvalidTask(
+            "style morphing is not supported by voicevox_core; use a worker with --http-engine"
+                .into(),
+        ))
+    }
 
-            let bytes = guard.tts(&text, StyleId(style_id)).perform()?;
-            drop(guard);
+    /// Resolves where a task's output goes: `result_filename`, or the
+    /// filename template, inside `output_dir`. A relative `output_dir` for
+    /// local output is placed under `output_root` when one is configured.
+    fn output_path(
+        &self,
+        message: &TaskMessage,
+        output_dir: String,
+        output_format: OutputFormat,
+    ) -> EngineResult<PathBuf> {
+        let filename = match message.result_filename.clone() {
+            Some(name) if Path::new(&name).extension().is_some() => name,
+            Some(name) => format!("{}.{}", name, output_format.extension()),
+            None => {
+                let name =
+                    render_filename_template(&self.filename_template, message, output_format)?;
+                if !self.filename_template_subdirs && name.contains(['/', '\\']) {
+                    return Err(EngineError::InvalidTask(format!(
+                        "filename template rendered {} for task {}, which contains a path separator",
+                        name, message.task_id
+                    )));
+                }
+                name
+            }
+        };
+        place_output(
+            output_dir,
+            &filename,
+            self.output_root.as_deref(),
+            &self.output_sink,
+        )
+    }
+}
+
+/// Canonicalizes a configured root directory, so paths can be checked
+/// against it by prefix.
+pub(crate) fn resolve_root(root: &Path, what: &str) -> EngineResult<PathBuf> {
+    fs::canonicalize(root).map_err(|err| {
+        EngineError::Io(io::Error::new(
+            err.kind(),
+            format!("failed to resolve {} {}: {}", what, root.display(), err),
+        ))
+    })
+}
+
+/// Joins a validated `filename` onto `output_dir`. With an `output_root`,
+/// `output_dir` must not contain `..`, and a relative one for local output
+/// is placed under the root.
+pub(crate) fn place_output(
+    output_dir: String,
+    filename: &str,
+    output_root: Option<&Path>,
+    output_sink: &OutputSink,
+) -> EngineResult<PathBuf> {
+    validate_filename(filename)?;
+
+    if output_root.is_some()
+        && Path::new(&output_dir)
+            .components()
+            .any(|component| component == Component::ParentDir)
+    {
+        return Err(EngineError::InvalidTask(format!(
+            "output directory {} must not contain `..`",
+            output_dir
+        )));
+    }
+
+    let output_dir = PathBuf::from(output_dir);
+    let output_dir = match output_root {
+        Some(root) if output_sink.is_local() && output_dir.is_relative() => root.join(output_dir),
+        _ => output_dir,
+    };
+
+    Ok(output_dir.join(filename))
+}
+
+/// Everything the blocking synthesis step needs, cloneable so it can be retried.
+#[derive(Clone)]
+struct SynthesisJob {
+    eval_id: String,
+    task_id: String,
+    synthesizers: Arc<Vec<Mutex<PoolMember>>>,
+    models: Arc<ModelRegistry>,
+    start: usize,
+    /// As requested by the task, before the speaker map.
+    speaker_id: u32,
+    style_id: u32,
+    text: TaskText,
+    text_replacements: Vec<(String, String)>,
+    prosody: Prosody,
+    output_format: OutputFormat,
+    output_path: PathBuf,
+    output_root: Option<PathBuf>,
+    write_metadata: bool,
+    emit_timing: bool,
+    emit_audio_query: bool,
+    progress: Option<Arc<dyn ProgressSink>>,
+    output_sink: OutputSink,
+    /// Set when the audio is returned inline rather than written; holds the
+    /// limit on its base64 size in bytes.
+    inline_audio_limit: Option<usize>,
+    target_sample_rate: Option<u32>,
+    normalize_lufs: Option<f64>,
+    /// Threshold in dBFS when the task asked for silence trimming.
+    trim_silence_db: Option<f32>,
+    output_bit_depth: Option<BitDepth>,
+    skip_if_exists: bool,
+    dict_name: Option<String>,
+    embed_metadata: bool,
+    report_levels: bool,
+    min_output_frames: usize,
+    max_text_chars: Option<usize>,
+    cancel: CancellationToken,
+}
+
+/// Contents of the `<output>.json` sidecar written when a task asks for
+/// metadata or for `skip_if_exists`.
+#[derive(Serialize)]
+struct OutputMetadata<'a> {
+    eval_id: &'a str,
+    task_id: &'a str,
+    #[serde(flatten)]
+    settings: &'a SynthesisSettings,
+    sample_rate: Option<u32>,
+    synthesis_ms: u64,
+    audio_duration_ms: Option<f64>,
+    output_sha256: &'a str,
+}
+
+/// Everything that shapes a task's audio. `skip_if_exists` reuses a file only
+/// when its sidecar records the same settings.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct SynthesisSettings {
+    speaker_id: u32,
+    /// After `text_replacements`.
+    text: String,
+    dict_name: Option<String>,
+    speed_scale: Option<f32>,
+    pitch_scale: Option<f32>,
+    intonation_scale: Option<f32>,
+    volume_scale: Option<f32>,
+    output_format: OutputFormat,
+    target_sample_rate: Option<u32>,
+    normalize_lufs: Option<f64>,
+    trim_silence_db: Option<f32>,
+    output_bit_depth: Option<BitDepth>,
+    embed_metadata: bool,
+}
+
+/// The parts of an existing metadata sidecar that identify its audio.
+#[derive(Deserialize)]
+struct StoredMetadata {
+    eval_id: String,
+    task_id: String,
+    #[serde(flatten)]
+    settings: SynthesisSettings,
+    output_sha256: String,
+}
+
+/// Contents of the `<output>.timing.json` file: per-mora consonant/vowel
+/// lengths in seconds, before `speed_scale` is applied.
+#[derive(Serialize)]
+struct TimingDocument<'a> {
+    speed_scale: f32,
+    pre_phoneme_length: f32,
+    post_phoneme_length: f32,
+    accent_phrases: &'a [AccentPhrase],
+}
+
+impl SynthesisJob {
+    fn run(self) -> EngineResult<TaskOutput> {
+        let started = Instant::now();
+
+        self.check_output_root()?;
+
+        let text = self.text_with_replacements()?;
+
+        if self.skip_if_exists && self.output_sink.is_local() && self.inline_audio_limit.is_none() {
+            if let Some(output) = self.existing_output(&text)? {
+                info!(
+                    output_file = output.output_file.as_deref(),
+                    "output already exists, skipping synthesis"
+                );
+                return Ok(output);
+            }
+        }
+
+        let style_id = self.style_id;
+        self.cancel.check()?;
+        let mut guard = acquire_synthesizer(&self.synthesizers, self.start, StyleId(style_id));
+
+        if guard.is_loaded_model_by_style_id(StyleId(style_id)) {
+            guard.touch_model(&self.models, style_id);
+        } else {
+            self.report(TaskStage::ModelLoading);
+            guard.load_model(&self.models, style_id)?;
+            self.cancel.check()?;
+        }
 
-            if let Some(parent) = output_path_clone.parent() {
-                fs::create_dir_all(parent)?;
+        self.report(TaskStage::Synthesizing);
+        let synthesis_started = Instant::now();
+        // Sidecars built from the query go through `synthesis` with that same
+        // query, so they describe exactly the audio that was produced.
+        let needs_query = self.emit_timing || self.emit_audio_query;
+        let (bytes, query) = if self.prosody.is_default() && !needs_query {
+            (guard.tts(&text, StyleId(style_id)).perform()?, None)
+        } else {
+            let mut query = guard.create_audio_query(&text, StyleId(style_id))?;
+            self.prosody.apply(&mut query);
+            let bytes = guard.synthesis(&query, StyleId(style_id)).perform()?;
+            (bytes, Some(query))
+        };
+        drop(guard);
+        check_output_length(&bytes, self.min_output_frames)?;
+        let synthesis_ms = started.elapsed().as_millis() as u64;
+        metrics::observe_synthesis(synthesis_started.elapsed());
+        self.cancel.check()?;
+
+        self.report(TaskStage::WritingOutput);
+        let bytes = match self.trim_silence_db {
+            Some(threshold_db) => audio::trim_silence(bytes, threshold_db)?,
+            None => bytes,
+        };
+        let bytes = match self.target_sample_rate {
+            Some(rate) => audio::resample(bytes, rate)?,
+            None => bytes,
+        };
+        let bytes = match self.normalize_lufs {
+            Some(lufs) => audio::normalize_loudness(bytes, lufs)?,
+            None => bytes,
+        };
+        let bytes = match self.output_bit_depth {
+            Some(depth) => audio::convert_bit_depth(bytes, depth)?,
+            None => bytes,
+        };
+        let bytes = if self.embed_metadata {
+            let comment = info_comment(&self.eval_id, &self.task_id, self.speaker_id, style_id);
+            audio::embed_info(
+                bytes,
+                &[
+                    (*b"INAM", text.as_str()),
+                    (*b"ICMT", comment.as_str()),
+                    (*b"ISFT", "vvx-worker"),
+                ],
+            )?
+        } else {
+            bytes
+        };
+        let levels = if self.report_levels {
+            Some(audio::levels(&bytes)?)
+        } else {
+            None
+        };
+        let wav_info = audio::wav_info(&bytes);
+        let bytes = audio::encode(self.output_format, bytes)?;
+
+        let output_sha256 = format!("{:x}", Sha256::digest(&bytes));
+
+        if let Some(limit) = self.inline_audio_limit {
+            // The limit bounds what lands in the result message, which is the
+            // base64 text: 4 bytes for every 3, padded.
+            let encoded_len = bytes.len().div_ceil(3) * 4;
+            if encoded_len > limit {
+                return Err(EngineError::InvalidTask(format!(
+                    "inline audio is {} bytes base64-encoded, over the {} byte limit; set output_dir instead",
+                    encoded_len, limit
+                )));
             }
 
-            fs::write(&output_path_clone, &bytes)?;
+            return Ok(TaskOutput {
+                audio_duration_ms: wav_info.map(|info| info.duration_ms),
+                output_sha256: Some(output_sha256),
+                output_bytes: Some(bytes.len() as u64),
+                audio_base64: Some(BASE64_STANDARD.encode(&bytes)),
+                levels,
+                ..TaskOutput::default()
+            });
+        }
+
+        // A task abandoned on timeout must not write after its result went
+        // out; post-processing can take a while, so check once more.
+        self.cancel.check()?;
+
+        // Sidecars go first: if one cannot be written the task fails without
+        // leaving audio behind that `skip_if_exists` would later trust.
+        if self.write_metadata || self.skip_if_exists {
+            let settings = self.settings(&text);
+            let metadata = OutputMetadata {
+                eval_id: &self.eval_id,
+                task_id: &self.task_id,
+                settings: &settings,
+                sample_rate: wav_info.map(|info| info.sample_rate),
+                synthesis_ms,
+                audio_duration_ms: wav_info.map(|info| info.duration_ms),
+                output_sha256: &output_sha256,
+            };
+            let json = serde_json::to_vec_pretty(&metadata).map_err(io::Error::from)?;
+            self.output_sink
+                .write(&sidecar_path(&self.output_path, "json"), &json)?;
+        }
+
+        if let (true, Some(query)) = (self.emit_timing, &query) {
+            let timing = TimingDocument {
+                speed_scale: query.speed_scale,
+                pre_phoneme_length: query.pre_phoneme_length,
+                post_phoneme_length: query.post_phoneme_length,
+                accent_phrases: &query.accent_phrases,
+            };
+            let json = serde_json::to_vec_pretty(&timing).map_err(io::Error::from)?;
+            self.output_sink
+                .write(&sidecar_path(&self.output_path, "timing.json"), &json)?;
+        }
+
+        if let (true, Some(query)) = (self.emit_audio_query, &query) {
+            let json = serde_json::to_vec_pretty(query).map_err(io::Error::from)?;
+            self.output_sink
+                .write(&sidecar_path(&self.output_path, "query.json"), &json)?;
+        }
+
+        let output_file = self.output_sink.write(&self.output_path, &bytes)?;
+
+        Ok(TaskOutput {
+            output_file: Some(output_file),
+            audio_duration_ms: wav_info.map(|info| info.duration_ms),
+            output_sha256: Some(output_sha256),
+            output_bytes: Some(bytes.len() as u64),
+            levels,
+            ..TaskOutput::default()
+        })
+    }
+
+    /// Dry-run counterpart of `run`: checks the speaker is known and the output
+    /// directory is writable, without touching a synthesizer.
+    fn validate(self) -> EngineResult<TaskOutput> {
+        self.check_output_root()?;
+
+        self.text_with_replacements()?;
 
-            Ok::<_, EngineError>(output_path_clone)
+        if !self.models.paths.contains_key(&self.style_id) {
+            return Err(EngineError::InvalidTask(format!(
+                "unknown speaker/style id {}",
+                self.style_id
+            )));
+        }
+
+        if !self.output_sink.is_local() || self.inline_audio_limit.is_some() {
+            return Ok(TaskOutput {
+                dry_run: true,
+                ..TaskOutput::default()
+            });
+        }
+
+        let dir = self
+            .output_path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        check_output_dir(dir)?;
+
+        Ok(TaskOutput {
+            dry_run: true,
+            ..TaskOutput::default()
         })
-        .await??;
+    }
 
-        Ok(Some(result_path.to_string_lossy().into_owned()))
+    /// The settings this job synthesizes `text` with.
+    fn settings(&self, text: &str) -> SynthesisSettings {
+        SynthesisSettings {
+            speaker_id: self.style_id,
+            text: text.to_string(),
+            dict_name: self.dict_name.clone(),
+            speed_scale: self.prosody.speed_scale,
+            pitch_scale: self.prosody.pitch_scale,
+            intonation_scale: self.prosody.intonation_scale,
+            volume_scale: self.prosody.volume_scale,
+            output_format: self.output_format,
+            target_sample_rate: self.target_sample_rate,
+            normalize_lufs: self.normalize_lufs,
+            trim_silence_db: self.trim_silence_db,
+            output_bit_depth: self.output_bit_depth,
+            embed_metadata: self.embed_metadata,
+        }
+    }
+
+    /// Returns the existing output file for `skip_if_exists` tasks.
+    fn existing_output(&self, text: &str) -> EngineResult<Option<TaskOutput>> {
+        let output = reusable_output(
+            &self.output_path,
+            &self.settings(text),
+            &self.eval_id,
+            &self.task_id,
+        )?;
+        if output.is_none() && self.output_path.exists() {
+            info!(
+                output_file = %self.output_path.display(),
+                "existing output does not match the task, synthesizing again"
+            );
+        }
+        Ok(output)
+    }
+
+    /// Applies `output_root` to local output; object keys are not confined.
+    fn check_output_root(&self) -> EngineResult<()> {
+        match &self.output_root {
+            Some(root) if self.output_sink.is_local() && self.inline_audio_limit.is_none() => {
+                ensure_within_root(root, &self.output_path)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Loads the text and applies `text_replacements`.
+    fn text_with_replacements(&self) -> EngineResult<String> {
+        apply_replacements(
+            self.text.load()?,
+            &self.text_replacements,
+            self.max_text_chars,
+        )
+    }
+
+    fn report(&self, stage: TaskStage) {
+        if let Some(progress) = &self.progress {
+            progress.report(&self.eval_id, &self.task_id, stage);
+        }
     }
 }
 
-fn prepare_models(root: &Utf8Path) -> EngineResult<HashMap<u32, PathBuf>> {
-    let mut mapping = HashMap::new();
-    let mut stack = vec![root.to_path_buf()];
+/// The blocking part of a dialogue task, cloneable so it can be retried.
+#[derive(Clone)]
+struct DialogueJob {
+    eval_id: String,
+    task_id: String,
+    synthesizers: Arc<Vec<Mutex<PoolMember>>>,
+    models: Arc<ModelRegistry>,
+    start: usize,
+    /// Each segment with the style id its speaker maps to.
+    segments: Vec<(u32, DialogueSegment)>,
+    output_format: OutputFormat,
+    output_path: PathBuf,
+    output_root: Option<PathBuf>,
+    progress: Option<Arc<dyn ProgressSink>>,
+    output_sink: OutputSink,
+    min_output_frames: usize,
+    cancel: CancellationToken,
+}
 
-    while let Some(dir) = stack.pop() {
-        let entries = fs::read_dir(dir.as_std_path()).map_err(|err| {
-            EngineError::Io(io::Error::new(
-                err.kind(),
-                format!("failed to read model directory {}: {}", dir, err),
-            ))
-        })?;
+impl DialogueJob {
+    fn run(self) -> EngineResult<TaskOutput> {
+        self.check_output_root()?;
 
-        for entry in entries {
-            let entry = entry?;
-            let path = entry.path();
+        self.report(TaskStage::Synthesizing);
+        let mut combined: Option<audio::PcmAudio> = None;
+        let mut synthesis_time = Duration::ZERO;
+        let last = self.segments.len() - 1;
+        for (index, (style_id, segment)) in self.segments.iter().enumerate() {
+            self.cancel.check()?;
+            let mut guard = acquire_synthesizer(&self.synthesizers, self.start, StyleId(*style_id));
+            if guard.is_loaded_model_by_style_id(StyleId(*style_id)) {
+                guard.touch_model(&self.models, *style_id);
+            } else {
+                self.report(TaskStage::ModelLoading);
+                guard.load_model(&self.models, *style_id)?;
+            }
+            let synthesis_started = Instant::now();
+            let bytes = guard.tts(&segment.text, StyleId(*style_id)).perform()?;
+            synthesis_time += synthesis_started.elapsed();
+            drop(guard);
+            check_output_length(&bytes, self.min_output_frames)?;
 
-            if path.is_dir() {
-                if has_vvm_extension(path.as_path()) {
-                    collect_styles(path.as_path(), &mut mapping)?;
-                    continue;
+            let mut pcm = audio::PcmAudio::from_wav(&bytes)?;
+            if index < last {
+                let frames = pcm.sample_rate as u64 * segment.pause_ms as u64 / 1000;
+                let pause = frames as usize * pcm.channels as usize;
+                pcm.samples.extend(std::iter::repeat(0.0).take(pause));
+            }
+            match &mut combined {
+                None => combined = Some(pcm),
+                Some(combined) => {
+                    if combined.sample_rate != pcm.sample_rate || combined.channels != pcm.channels
+                    {
+                        return Err(EngineError::Voicevox(format!(
+                            "dialogue segment {} is {} Hz with {} channels, unlike the segments before it",
+                            index, pcm.sample_rate, pcm.channels
+                        )));
+                    }
+                    combined.samples.extend(pcm.samples);
                 }
+            }
+        }
+        metrics::observe_synthesis(synthesis_time);
+        self.cancel.check()?;
 
-                let utf8 = Utf8PathBuf::from_path_buf(path.clone()).map_err(|_| {
-                    EngineError::InvalidTask(format!(
-                        "model directory path contains invalid UTF-8: {}",
-                        path.display()
-                    ))
-                })?;
+        self.report(TaskStage::WritingOutput);
+        let bytes = combined.map(|pcm| pcm.to_wav()).unwrap_or_default();
+        let wav_info = audio::wav_info(&bytes);
+        let bytes = audio::encode(self.output_format, bytes)?;
+        self.cancel.check()?;
+        let output_sha256 = format!("{:x}", Sha256::digest(&bytes));
+        let output_file = self.output_sink.write(&self.output_path, &bytes)?;
 
-                stack.push(utf8);
-            } else if path.is_file() && has_vvm_extension(path.as_path()) {
-                collect_styles(path.as_path(), &mut mapping)?;
+        Ok(TaskOutput {
+            output_file: Some(output_file),
+            audio_duration_ms: wav_info.map(|info| info.duration_ms),
+            output_sha256: Some(output_sha256),
+            output_bytes: Some(bytes.len() as u64),
+            ..TaskOutput::default()
+        })
+    }
+
+    /// Dry-run counterpart of `run`: checks every segment's speaker is known
+    /// and, for local output, that the output directory is writable.
+    fn validate(self) -> EngineResult<TaskOutput> {
+        self.check_output_root()?;
+
+        for (style_id, _) in &self.segments {
+            if !self.models.paths.contains_key(style_id) {
+                return Err(EngineError::InvalidTask(format!(
+                    "unknown speaker/style id {}",
+                    style_id
+                )));
+            }
+        }
+
+        if self.output_sink.is_local() {
+            let dir = self
+                .output_path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            check_output_dir(dir)?;
+        }
+
+        Ok(TaskOutput {
+            dry_run: true,
+            ..TaskOutput::default()
+        })
+    }
+
+    fn check_output_root(&self) -> EngineResult<()> {
+        match &self.output_root {
+            Some(root) if self.output_sink.is_local() => {
+                ensure_within_root(root, &self.output_path)
             }
+            _ => Ok(()),
         }
     }
 
-    Ok(mapping)
+    fn report(&self, stage: TaskStage) {
+        if let Some(progress) = &self.progress {
+            progress.report(&self.eval_id, &self.task_id, stage);
+        }
+    }
 }
 
-fn collect_styles(path: &Path, mapping: &mut HashMap<u32, PathBuf>) -> EngineResult<()> {
-    let voice_model = VoiceModelFile::open(path)?;
-    for character in voice_model.metas() {
-        for style in &character.styles {
-            mapping
-                .entry(style.id.0)
-                .or_insert_with(|| path.to_path_buf());
+/// Checks, without creating or writing anything, that output could be written
+/// to `dir`: its nearest existing ancestor must be a directory that is not
+/// read-only. Used by dry runs, which leave the filesystem untouched.
+fn check_output_dir(dir: &Path) -> EngineResult<()> {
+    let mut existing = dir;
+    let metadata = loop {
+        match fs::metadata(existing) {
+            Ok(metadata) => break metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                existing = match existing.parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => parent,
+                    _ if existing != Path::new(".") => Path::new("."),
+                    _ => return Err(err.into()),
+                };
+            }
+            Err(err) => return Err(err.into()),
         }
+    };
+
+    if !metadata.is_dir() || metadata.permissions().readonly() {
+        return Err(EngineError::Io(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "output directory {} is not writable: {} is not a writable directory",
+                dir.display(),
+                existing.display()
+            ),
+        )));
+    }
+    Ok(())
+}
+
+/// The file at `output_path`, if its metadata sidecar shows it was made with
+/// `settings` and the file is unchanged since. Files with embedded metadata
+/// also carry their task's ids, so those must match too. Without a readable
+/// sidecar nothing is reused.
+fn reusable_output(
+    output_path: &Path,
+    settings: &SynthesisSettings,
+    eval_id: &str,
+    task_id: &str,
+) -> EngineResult<Option<TaskOutput>> {
+    let json = match fs::read(sidecar_path(output_path, "json")) {
+        Ok(json) => json,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let Ok(stored) = serde_json::from_slice::<StoredMetadata>(&json) else {
+        return Ok(None);
+    };
+    if stored.settings != *settings
+        || (settings.embed_metadata && (stored.eval_id != eval_id || stored.task_id != task_id))
+    {
+        return Ok(None);
+    }
+
+    let bytes = match fs::read(output_path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let output_sha256 = format!("{:x}", Sha256::digest(&bytes));
+    if stored.output_sha256 != output_sha256 {
+        return Ok(None);
+    }
+
+    Ok(Some(TaskOutput {
+        output_file: Some(output_path.to_string_lossy().into_owned()),
+        audio_duration_ms: audio::wav_info(&bytes).map(|info| info.duration_ms),
+        output_sha256: Some(output_sha256),
+        output_bytes: Some(bytes.len() as u64),
+        ..TaskOutput::default()
+    }))
+}
+
+/// `<output>.<suffix>`: the suffix is appended to the full file name, so
+/// `a.wav` and `a.mp3` never share a sidecar.
+fn sidecar_path(output: &Path, suffix: &str) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Fails a synthesis whose WAV holds fewer than `min_frames` sample frames,
+/// which VOICEVOX occasionally returns instead of an error.
+pub(crate) fn check_output_length(wav: &[u8], min_frames: usize) -> EngineResult<()> {
+    let frames = audio::wav_info(wav).map_or(0, |info| info.frames);
+    if frames < min_frames {
+        warn!(
+            bytes = wav.len(),
+            frames, min_frames, "VOICEVOX returned too little audio"
+        );
+        return Err(EngineError::Voicevox("empty synthesis output".into()));
     }
     Ok(())
 }
 
-fn has_vvm_extension(path: &Path) -> bool {
-    path.extension()
-        .and_then(OsStr::to_str)
-        .map(|ext| ext.eq_ignore_ascii_case("vvm"))
-        .unwrap_or(false)
+/// Substitutes the placeholders of `template` for one task. The output
+/// extension is appended when the rendered name has none.
+fn render_filename_template(
+    template: &str,
+    message: &TaskMessage,
+    format: OutputFormat,
+) -> EngineResult<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(open) = rest.find('{') {
+        rendered.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+        let close = after.find('}').ok_or_else(|| {
+            EngineError::InvalidTask(format!(
+                "filename template {} has an unclosed `{{`",
+                template
+            ))
+        })?;
+
+        match &after[..close] {
+            "eval_id" => rendered.push_str(&message.eval_id),
+            "task_id" => rendered.push_str(&message.task_id),
+            "speaker_id" => rendered.push_str(&message.speaker_id.to_string()),
+            "ext" => rendered.push_str(format.extension()),
+            "index" => {
+                let index = message.index.ok_or_else(|| {
+                    EngineError::InvalidTask(format!(
+                        "filename template {} uses {{index}} but task {} is not part of a batch",
+                        template, message.task_id
+                    ))
+                })?;
+                rendered.push_str(&format!("{:04}", index));
+            }
+            other => {
+                return Err(EngineError::InvalidTask(format!(
+                    "unknown placeholder {{{}}} in filename template {}",
+                    other, template
+                )))
+            }
+        }
+
+        rest = &after[close + 1..];
+    }
+    rendered.push_str(rest);
+
+    if Path::new(&rendered).extension().is_none() {
+        rendered = format!("{}.{}", rendered, format.extension());
+    }
+
+    Ok(rendered)
+}
+
+/// The `ICMT` value of an embedded `LIST`/`INFO` chunk.
+fn info_comment(eval_id: &str, task_id: &str, speaker_id: u32, style_id: u32) -> String {
+    format!(
+        "eval_id={} task_id={} speaker_id={} style_id={}",
+        eval_id, task_id, speaker_id, style_id
+    )
+}
+
+/// Applies `replacements` to `text` in order, then checks the length of the
+/// result, so an expanding replacement cannot get past `limit`.
+fn apply_replacements(
+    mut text: String,
+    replacements: &[(String, String)],
+    limit: Option<usize>,
+) -> EngineResult<String> {
+    for (from, to) in replacements {
+        text = text.replace(from.as_str(), to);
+    }
+    check_text_length(text.chars().count(), limit)?;
+    Ok(text)
+}
+
+/// Rejects a text of `chars` Unicode scalar values when it exceeds `limit`.
+pub(crate) fn check_text_length(chars: usize, limit: Option<usize>) -> EngineResult<()> {
+    match limit {
+        Some(limit) if chars > limit => Err(EngineError::InvalidTask(format!(
+            "text is {} characters long, more than the limit of {}",
+            chars, limit
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Rejects result filenames that are absolute or contain empty, `.` or `..`
+/// components.
+pub(crate) fn validate_filename(filename: &str) -> EngineResult<()> {
+    if filename.starts_with(['/', '\\']) || Path::new(filename).is_absolute() {
+        return Err(EngineError::InvalidTask(format!(
+            "result filename {} must be relative",
+            filename
+        )));
+    }
+
+    if filename
+        .split(['/', '\\'])
+        .any(|component| component.is_empty() || component == "." || component == "..")
+    {
+        return Err(EngineError::InvalidTask(format!(
+            "result filename {} contains an empty, `.` or `..` component",
+            filename
+        )));
+    }
+
+    Ok(())
+}
+
+/// Checks that `path` stays inside the canonical `root` once symlinks in its
+/// existing ancestors are resolved. Components that do not exist yet are
+/// appended as-is, which is safe because `..` has already been rejected.
+pub(crate) fn ensure_within_root(root: &Path, path: &Path) -> EngineResult<()> {
+    let mut existing = path;
+    let mut missing = Vec::new();
+
+    while existing.symlink_metadata().is_err() {
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => break,
+        }
+    }
+
+    if existing.as_os_str().is_empty() {
+        existing = Path::new(".");
+    }
+
+    let mut resolved = fs::canonicalize(existing)?;
+    resolved.extend(missing.iter().rev());
+
+    if resolved.starts_with(root) {
+        Ok(())
+    } else {
+        Err(EngineError::InvalidTask(format!(
+            "output path {} escapes output root {}",
+            path.display(),
+            root.display()
+        )))
+    }
+}
+
+/// Loads the models backing `styles` into every synthesizer, opening each
+/// `.vvm` file once. Returns the number of distinct model files loaded.
+fn preload_models(
+    members: &mut [PoolMember],
+    models: &ModelRegistry,
+    styles: &[u32],
+    cache_capacity: usize,
+) -> EngineResult<usize> {
+    let mut paths = BTreeSet::new();
+    for style_id in styles {
+        let path = models.paths.get(style_id).ok_or_else(|| {
+            EngineError::InvalidTask(format!(
+                "preload style id {} not found in model directory",
+                style_id
+            ))
+        })?;
+        paths.insert(path);
+    }
+
+    if cache_capacity > 0 && paths.len() > cache_capacity {
+        return Err(EngineError::InvalidTask(format!(
+            "preloading {} models exceeds the model cache capacity of {}",
+            paths.len(),
+            cache_capacity
+        )));
+    }
+
+    for path in &paths {
+        let voice_model = models.open(path)?;
+        for member in members.iter_mut() {
+            member.add_model(path, &voice_model)?;
+        }
+    }
+
+    Ok(paths.len())
+}
+
+/// Loads ONNX Runtime from the first search path that works, else through its
+/// default lookup. The error lists every attempt so a deployment can tell
+/// where the library was expected.
+fn load_onnxruntime(search_paths: &[PathBuf]) -> EngineResult<&'static Onnxruntime> {
+    let mut tried = Vec::new();
+    for candidate in ort_candidates(search_paths) {
+        if !candidate.is_file() {
+            tried.push(format!("{} (not found)", candidate.display()));
+            continue;
+        }
+        match Onnxruntime::load_once()
+            .filename(candidate.clone())
+            .perform()
+        {
+            Ok(ort) => {
+                info!(path = %candidate.display(), "loaded ONNX Runtime");
+                return Ok(ort);
+            }
+            Err(err) => tried.push(format!("{} ({})", candidate.display(), err)),
+        }
+    }
+
+    Onnxruntime::load_once().perform().map_err(|err| {
+        tried.push(format!(
+            "{} on the system library path ({})",
+            Onnxruntime::LIB_VERSIONED_FILENAME,
+            err
+        ));
+        EngineError::Voicevox(format!(
+            "failed to load ONNX Runtime; tried {}. Point VOICEVOX_ORT_LIB at the library or add its directory to VOICEVOX_ORT_SEARCH_PATHS",
+            tried.join(", ")
+        ))
+    })
+}
+
+/// Library files to try for `search_paths`; a directory stands for the
+/// versioned and unversioned library names inside it.
+fn ort_candidates(search_paths: &[PathBuf]) -> Vec<PathBuf> {
+    let search_paths = if search_paths.is_empty() {
+        default_ort_search_paths()
+    } else {
+        search_paths.to_vec()
+    };
+
+    search_paths
+        .iter()
+        .flat_map(|path| {
+            if path.is_dir() {
+                vec![
+                    path.join(Onnxruntime::LIB_VERSIONED_FILENAME),
+                    path.join(Onnxruntime::LIB_UNVERSIONED_FILENAME),
+                ]
+            } else {
+                vec![path.clone()]
+            }
+        })
+        .collect()
+}
+
+/// The executable's directory and its `lib` subdirectory, then
+/// [`DEFAULT_ORT_SEARCH_DIRS`].
+fn default_ort_search_paths() -> Vec<PathBuf> {
+    let exe_dir = env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf));
+    exe_dir
+        .into_iter()
+        .flat_map(|dir| [dir.clone(), dir.join("lib")])
+        .chain(DEFAULT_ORT_SEARCH_DIRS.iter().map(PathBuf::from))
+        .collect()
+}
+
+/// Opens the Open JTalk dictionary in `dir`, applying `user_dict` if given.
+fn open_text_analyzer(dir: &Utf8Path, user_dict: Option<&UserDict>) -> EngineResult<OpenJtalk> {
+    let text_analyzer = OpenJtalk::new(dir)?;
+    if let Some(user_dict) = user_dict {
+        text_analyzer.use_user_dict(user_dict)?;
+    }
+    Ok(text_analyzer)
+}
+
+/// One synthesizer of the pool; `cpu_num_threads` is passed on only when set.
+fn build_synthesizer(
+    ort: &'static Onnxruntime,
+    text_analyzer: &OpenJtalk,
+    acceleration: AccelerationMode,
+    cpu_num_threads: Option<u16>,
+) -> Result<Synthesizer<OpenJtalk>, voicevox_core::Error> {
+    let builder = Synthesizer::builder(ort)
+        .text_analyzer(text_analyzer.clone())
+        .acceleration_mode(acceleration.into());
+    match cpu_num_threads {
+        Some(threads) => builder.cpu_num_threads(threads).build(),
+        None => builder.build(),
+    }
+}
+
+/// Phrase synthesized, and discarded, by the startup warmup.
+const WARMUP_TEXT: &str = "こんにちは";
+
+/// Runs one throwaway synthesis of `style_id` on every member.
+fn warm_up(members: &mut [PoolMember], models: &ModelRegistry, style_id: u32) -> EngineResult<()> {
+    for member in members.iter_mut() {
+        if member.is_loaded_model_by_style_id(StyleId(style_id)) {
+            member.touch_model(models, style_id);
+        } else {
+            member.load_model(models, style_id)?;
+        }
+        member.tts(WARMUP_TEXT, StyleId(style_id)).perform()?;
+    }
+    Ok(())
+}
+
+/// Every voice model the engine can serve, shared by all pool members.
+///
+/// A synthesizer cannot use a model loaded into another one, so each member
+/// still loads a model itself; what is shared is the opened `.vvm` file. The
+/// first member to need a model opens it and the others reuse that handle.
+///
+/// Each path has its own slot lock. Two tasks that need the same new model at
+/// once serialize on that slot: the first opens the file, the second waits
+/// and then finds it open. Tasks for other models are not held up, since the
+/// map lock is only taken to find the slot. A failed open is not cached, so
+/// the next task tries again.
+struct ModelRegistry {
+    paths: HashMap<u32, PathBuf>,
+    files: Mutex<HashMap<PathBuf, Arc<Mutex<Option<Arc<VoiceModelFile>>>>>>,
+}
+
+impl ModelRegistry {
+    fn new(paths: HashMap<u32, PathBuf>) -> Self {
+        Self {
+            paths,
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The model file at `path`, opened on first use.
+    fn open(&self, path: &Path) -> EngineResult<Arc<VoiceModelFile>> {
+        let slot = {
+            let mut files = self.files.lock().unwrap_or_else(PoisonError::into_inner);
+            Arc::clone(files.entry(path.to_path_buf()).or_default())
+        };
+
+        let mut slot = slot.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(voice_model) = slot.as_ref() {
+            return Ok(Arc::clone(voice_model));
+        }
+        let voice_model = Arc::new(VoiceModelFile::open(path)?);
+        *slot = Some(Arc::clone(&voice_model));
+        Ok(voice_model)
+    }
+}
+
+/// One synthesizer of the pool together with the models it has loaded.
+/// Derefs to the synthesizer.
+struct PoolMember {
+    synthesizer: Synthesizer<OpenJtalk>,
+    lru: ModelLru<VoiceModelId>,
+}
+
+impl Deref for PoolMember {
+    type Target = Synthesizer<OpenJtalk>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.synthesizer
+    }
+}
+
+impl PoolMember {
+    /// Loads the model backing `style_id`, making room for it first when the
+    /// cache is full.
+    fn load_model(&mut self, models: &ModelRegistry, style_id: u32) -> EngineResult<()> {
+        let path = models.paths.get(&style_id).ok_or_else(|| {
+            EngineError::InvalidTask(format!("unknown speaker/style id {}", style_id))
+        })?;
+        let voice_model = models.open(path)?;
+        self.add_model(path, &voice_model)
+    }
+
+    /// Marks the already loaded model backing `style_id` as just used.
+    fn touch_model(&mut self, models: &ModelRegistry, style_id: u32) {
+        if let Some(path) = models.paths.get(&style_id) {
+            self.lru.touch(path);
+        }
+    }
+
+    /// Loads the model, then unloads whatever it pushed out of the cache. A
+    /// failed load leaves the loaded models as they were; a model that fails
+    /// to unload stays tracked as least recently used.
+    fn add_model(&mut self, path: &Path, voice_model: &VoiceModelFile) -> EngineResult<()> {
+        self.synthesizer.load_voice_model(voice_model)?;
+        metrics::add_models_loaded(1);
+        let mut evicted = self
+            .lru
+            .insert(path.to_path_buf(), voice_model.id())
+            .into_iter();
+        while let Some((model, model_id)) = evicted.next() {
+            if let Err(err) = self.synthesizer.unload_voice_model(model_id) {
+                self.lru
+                    .restore(std::iter::once((model, model_id)).chain(evicted).collect());
+                return Err(err.into());
+            }
+            metrics::add_models_loaded(-1);
+            info!(model = %model.display(), "unloaded least recently used voice model");
+        }
+        Ok(())
+    }
+}
+
+/// The voice models loaded into one synthesizer, least recently used first.
+/// Generic over the model id so the bookkeeping can be tested without
+/// `voicevox_core`.
+struct ModelLru<Id> {
+    /// Most models kept loaded at once; 0 means no limit.
+    capacity: usize,
+    loaded: VecDeque<(PathBuf, Id)>,
+}
+
+impl<Id> ModelLru<Id> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            loaded: VecDeque::new(),
+        }
+    }
+
+    fn touch(&mut self, path: &Path) {
+        if let Some(index) = self.loaded.iter().position(|(loaded, _)| loaded == path) {
+            if let Some(entry) = self.loaded.remove(index) {
+                self.loaded.push_back(entry);
+            }
+        }
+    }
+
+    /// Records a freshly loaded model as the most recently used, then removes
+    /// and returns the least recently used ones over the capacity, oldest
+    /// first. The new model is never among them.
+    fn insert(&mut self, path: PathBuf, model_id: Id) -> Vec<(PathBuf, Id)> {
+        self.loaded.push_back((path, model_id));
+        let excess = match self.capacity {
+            0 => 0,
+            capacity => self.loaded.len().saturating_sub(capacity),
+        };
+        self.loaded.drain(..excess).collect()
+    }
+
+    /// Puts back evicted models that are still loaded, as least recently used.
+    fn restore(&mut self, models: Vec<(PathBuf, Id)>) {
+        for entry in models.into_iter().rev() {
+            self.loaded.push_front(entry);
+        }
+    }
+}
+
+/// Picks a pool member for `style_id`, starting the scan at `start` so load is
+/// spread round-robin across members.
+fn acquire_synthesizer(
+    pool: &[Mutex<PoolMember>],
+    start: usize,
+    style_id: StyleId,
+) -> MutexGuard<'_, PoolMember> {
+    acquire_member(pool, start, |member| {
+        member.is_loaded_model_by_style_id(style_id)
+    })
+}
+
+/// The pool-walking part of [`acquire_synthesizer`]: the first idle member
+/// from `start` that is `preferred`, else the first idle one, else waits for
+/// the member at `start`.
+fn acquire_member<T>(
+    pool: &[Mutex<T>],
+    start: usize,
+    preferred: impl Fn(&T) -> bool,
+) -> MutexGuard<'_, T> {
+    let mut idle = None;
+
+    for offset in 0..pool.len() {
+        let index = (start + offset) % pool.len();
+        let guard = match pool[index].try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::WouldBlock) => continue,
+            Err(TryLockError::Poisoned(poisoned)) => {
+                recover_poisoned(&pool[index], index, poisoned)
+            }
+        };
+        if preferred(&guard) {
+            return guard;
+        }
+        if idle.is_none() {
+            idle = Some(guard);
+        }
+    }
+
+    if let Some(guard) = idle {
+        return guard;
+    }
+
+    let index = start % pool.len();
+    pool[index]
+        .lock()
+        .unwrap_or_else(|poisoned| recover_poisoned(&pool[index], index, poisoned))
+}
+
+/// Takes over a lock poisoned by a task that panicked mid-synthesis. The
+/// synthesizer keeps no per-call state, so it stays usable; clearing the
+/// poison stops one panic from failing every later task on this member.
+fn recover_poisoned<'a, T>(
+    member: &'a Mutex<T>,
+    index: usize,
+    poisoned: PoisonError<MutexGuard<'a, T>>,
+) -> MutexGuard<'a, T> {
+    warn!(
+        synthesizer = index,
+        "recovering synthesizer after a panicked task"
+    );
+    member.clear_poison();
+    poisoned.into_inner()
+}
+
+/// Where a task's text comes from: inline in the message or a UTF-8 file
+/// inside the text root.
+#[derive(Clone, Debug)]
+pub(crate) enum TaskText {
+    Inline(String),
+    File { path: String, root: PathBuf },
+}
+
+impl TaskText {
+    /// Requires one of `text` and `text_path`; [`TaskMessage::validate`] has
+    /// already rejected both being set. `text_path` also requires a `root`.
+    pub(crate) fn from_task(message: &TaskMessage, root: Option<&Path>) -> EngineResult<Self> {
+        match (&message.text, &message.text_path, root) {
+            (Some(text), _, _) => Ok(TaskText::Inline(text.clone())),
+            (None, Some(path), Some(root)) => Ok(TaskText::File {
+                path: path.clone(),
+                root: root.to_path_buf(),
+            }),
+            (None, Some(_), None) => Err(EngineError::InvalidTask(
+                "text_path is not accepted: this worker has no TEXT_ROOT".into(),
+            )),
+            (None, None, _) => Err(EngineError::InvalidTask(
+                "missing text or text_path for synthesis".into(),
+            )),
+        }
+    }
+
+    /// Returns the text, reading the file for `File`. Blocks on file I/O.
+    pub(crate) fn load(&self) -> EngineResult<String> {
+        match self {
+            TaskText::Inline(text) => Ok(text.clone()),
+            TaskText::File { path, root } => read_text_file(root, path),
+        }
+    }
+}
+
+/// Reads the UTF-8 text file a task's `text_path` names, which must resolve,
+/// symlinks included, to a file inside the canonical `root`. A relative path
+/// is taken relative to `root`. Blocks on file I/O.
+pub(crate) fn read_text_file(root: &Path, path: &str) -> EngineResult<String> {
+    let resolved = fs::canonicalize(root.join(path)).map_err(|err| {
+        EngineError::InvalidTask(format!("cannot open text file {}: {}", path, err))
+    })?;
+    if !resolved.starts_with(root) {
+        return Err(EngineError::InvalidTask(format!(
+            "text file {} is outside the text root {}",
+            path,
+            root.display()
+        )));
+    }
+
+    let bytes = fs::read(&resolved).map_err(|err| {
+        EngineError::Io(io::Error::new(
+            err.kind(),
+            format!("failed to read text file {}: {}", path, err),
+        ))
+    })?;
+    String::from_utf8(bytes)
+        .map_err(|_| EngineError::InvalidTask(format!("text file {} is not valid UTF-8", path)))
+}
+
+/// Per-task overrides for the prosody fields of an `AudioQuery`.
+#[derive(Clone, Copy, Debug, Default)]
+struct Prosody {
+    speed_scale: Option<f32>,
+    pitch_scale: Option<f32>,
+    intonation_scale: Option<f32>,
+    volume_scale: Option<f32>,
+}
+
+impl Prosody {
+    /// The task's own parameters, falling back to the style's configured
+    /// defaults one parameter at a time.
+    fn from_task(message: &TaskMessage, defaults: Option<&SynthesisParams>) -> Self {
+        let defaults = defaults.copied().unwrap_or_default();
+        Self {
+            speed_scale: message.speed_scale.or(defaults.speed_scale),
+            pitch_scale: message.pitch_scale.or(defaults.pitch_scale),
+            intonation_scale: message.intonation_scale.or(defaults.intonation_scale),
+            volume_scale: message.volume_scale.or(defaults.volume_scale),
+        }
+    }
+
+    /// True when no override is set, so the one-shot `tts` path can be used.
+    fn is_default(&self) -> bool {
+        self.speed_scale.is_none()
+            && self.pitch_scale.is_none()
+            && self.intonation_scale.is_none()
+            && self.volume_scale.is_none()
+    }
+
+    fn apply(&self, query: &mut AudioQuery) {
+        if let Some(value) = self.speed_scale {
+            query.speed_scale = value;
+        }
+        if let Some(value) = self.pitch_scale {
+            query.pitch_scale = value;
+        }
+        if let Some(value) = self.intonation_scale {
+            query.intonation_scale = value;
+        }
+        if let Some(value) = self.volume_scale {
+            query.volume_scale = value;
+        }
+    }
+}
+
+/// Default `AudioQuery` parameters for one style, e.g. `{"speed_scale": 1.1}`.
+/// Unset parameters keep VOICEVOX's own value.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SynthesisParams {
+    pub speed_scale: Option<f32>,
+    pub pitch_scale: Option<f32>,
+    pub intonation_scale: Option<f32>,
+    pub volume_scale: Option<f32>,
+}
+
+impl SynthesisParams {
+    fn is_finite(&self) -> bool {
+        [
+            self.speed_scale,
+            self.pitch_scale,
+            self.intonation_scale,
+            self.volume_scale,
+        ]
+        .into_iter()
+        .flatten()
+        .all(f32::is_finite)
+    }
+}
+
+/// A style exposed by a voice model file.
+#[derive(Clone, Debug, Serialize)]
+pub struct StyleInfo {
+    pub style_id: u32,
+    pub character: String,
+    pub style: String,
+    pub model_path: PathBuf,
+}
+
+/// Lists the styles under `model_dir`, sorted by id, resolved exactly as the
+/// engine maps them at startup.
+pub fn discover_styles(
+    model_dir: &Utf8Path,
+    extensions: &[String],
+    strict: bool,
+    allow_lossy_paths: bool,
+) -> EngineResult<Vec<StyleInfo>> {
+    Ok(discover_models(
+        model_dir,
+        extensions,
+        strict,
+        false,
+        allow_lossy_paths,
+        None,
+    )?
+    .into_values()
+    .collect())
+}
+
+fn prepare_models(
+    root: &Utf8Path,
+    extensions: &[String],
+    strict: bool,
+    validate: bool,
+    allow_lossy_paths: bool,
+    progress: Option<&ModelScanProgress>,
+) -> EngineResult<HashMap<u32, PathBuf>> {
+    Ok(discover_models(
+        root,
+        extensions,
+        strict,
+        validate,
+        allow_lossy_paths,
+        progress,
+    )?
+    .into_iter()
+    .map(|(style_id, info)| (style_id, info.model_path))
+    .collect())
+}
+
+/// Maps style ids to model files under `root`. Models are opened in parallel,
+/// then merged in sorted path order so duplicate style ids resolve the same way
+/// on every start. With `validate`, every file is checked before any is used;
+/// see [`validate_models`].
+fn discover_models(
+    root: &Utf8Path,
+    extensions: &[String],
+    strict: bool,
+    validate: bool,
+    allow_lossy_paths: bool,
+    progress: Option<&ModelScanProgress>,
+) -> EngineResult<BTreeMap<u32, StyleInfo>> {
+    let mut model_files = find_model_files(root, extensions, allow_lossy_paths)?;
+    model_files.sort();
+
+    let model_styles = read_model_styles(&model_files, progress)?;
+    if validate {
+        validate_models(&model_files, &model_styles)?;
+    }
+
+    let mut mapping = BTreeMap::new();
+    for styles in model_styles {
+        collect_styles(styles?, &mut mapping, strict)?;
+    }
+
+    Ok(mapping)
+}
+
+/// Lists model files and directories below `root`, recognized by `extensions`.
+/// Subdirectories with non-UTF-8 names are rejected unless `allow_lossy_paths`
+/// is set.
+fn find_model_files(
+    root: &Utf8Path,
+    extensions: &[String],
+    allow_lossy_paths: bool,
+) -> EngineResult<Vec<PathBuf>> {
+    let mut model_files = Vec::new();
+    let mut stack = vec![root.as_std_path().to_path_buf()];
+
+    while let Some(dir) = stack.pop() {
+        let entries = fs::read_dir(&dir).map_err(|err| {
+            EngineError::Io(io::Error::new(
+                err.kind(),
+                format!("failed to read model directory {}: {}", dir.display(), err),
+            ))
+        })?;
+
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+
+            if path.is_dir() {
+                if has_model_extension(path.as_path(), extensions) {
+                    model_files.push(path);
+                    continue;
+                }
+
+                if path.to_str().is_none() {
+                    if !allow_lossy_paths {
+                        return Err(EngineError::InvalidTask(format!(
+                            "model directory path contains invalid UTF-8: {}",
+                            path.display()
+                        )));
+                    }
+                    warn!(
+                        path = %path.to_string_lossy(),
+                        "model directory path contains invalid UTF-8; reading it anyway"
+                    );
+                }
+
+                stack.push(path);
+            } else if path.is_file() && has_model_extension(path.as_path(), extensions) {
+                model_files.push(path);
+            }
+        }
+    }
+
+    Ok(model_files)
+}
+
+/// Fails with one error naming every model file that could not be opened or
+/// exposes no styles, given the results of [`read_model_styles`] for `paths`.
+fn validate_models(
+    paths: &[PathBuf],
+    model_styles: &[EngineResult<Vec<StyleInfo>>],
+) -> EngineResult<()> {
+    let problems: Vec<String> = paths
+        .iter()
+        .zip(model_styles)
+        .filter_map(|(path, styles)| match styles {
+            Ok(styles) if styles.is_empty() => Some(format!("{}: no styles", path.display())),
+            Ok(_) => None,
+            Err(err) => Some(format!("{}: {}", path.display(), err)),
+        })
+        .collect();
+
+    if !problems.is_empty() {
+        return Err(EngineError::Voicevox(format!(
+            "{} of {} voice models failed validation: {}",
+            problems.len(),
+            paths.len(),
+            problems.join("; ")
+        )));
+    }
+    info!(models = paths.len(), "validated voice models");
+    Ok(())
+}
+
+/// Opens every model to read its styles, splitting the work across one thread
+/// per available core. Results are returned in the order of `paths`, one per
+/// file, so a bad file does not hide the others.
+fn read_model_styles(
+    paths: &[PathBuf],
+    progress: Option<&ModelScanProgress>,
+) -> EngineResult<Vec<EngineResult<Vec<StyleInfo>>>> {
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let threads = thread::available_parallelism()
+        .map(NonZeroUsize::get)
+        .unwrap_or(1)
+        .min(paths.len());
+    let chunk_size = paths.len().div_ceil(threads);
+    let total = paths.len();
+    let read = AtomicUsize::new(0);
+    let read = &read;
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = paths
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|path| {
+                            let styles = model_styles(path);
+                            let done = read.fetch_add(1, Ordering::Relaxed) + 1;
+                            if let Some(progress) = progress {
+                                (progress.0)(done, total);
+                            }
+                            styles
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let mut styles = Vec::with_capacity(paths.len());
+        for worker in workers {
+            let chunk = worker.join().map_err(|_| {
+                EngineError::Voicevox("voice model discovery thread panicked".into())
+            })?;
+            styles.extend(chunk);
+        }
+        Ok(styles)
+    })
+}
+
+fn model_styles(path: &Path) -> EngineResult<Vec<StyleInfo>> {
+    let voice_model = VoiceModelFile::open(path)?;
+    Ok(voice_model
+        .metas()
+        .iter()
+        .flat_map(|character| {
+            character.styles.iter().map(|style| StyleInfo {
+                style_id: style.id.0,
+                character: character.name.clone(),
+                style: style.name.clone(),
+                model_path: path.to_path_buf(),
+            })
+        })
+        .collect())
+}
+
+/// Adds the styles of one model file. A style id already claimed by another
+/// file keeps its first model; the collision is logged, or rejected when
+/// `strict` is set.
+fn collect_styles(
+    styles: Vec<StyleInfo>,
+    mapping: &mut BTreeMap<u32, StyleInfo>,
+    strict: bool,
+) -> EngineResult<()> {
+    for style in styles {
+        match mapping.entry(style.style_id) {
+            Entry::Vacant(entry) => {
+                entry.insert(style);
+            }
+            Entry::Occupied(entry) if entry.get().model_path != style.model_path => {
+                if strict {
+                    return Err(EngineError::InvalidTask(format!(
+                        "style id {} is provided by both {} and {}",
+                        style.style_id,
+                        entry.get().model_path.display(),
+                        style.model_path.display()
+                    )));
+                }
+                warn!(
+                    style_id = style.style_id,
+                    kept = %entry.get().model_path.display(),
+                    ignored = %style.model_path.display(),
+                    "duplicate style id across voice models; keeping the first"
+                );
+            }
+            Entry::Occupied(_) => {}
+        }
+    }
+    Ok(())
+}
+
+fn has_model_extension(path: &Path, extensions: &[String]) -> bool {
+    let Some(ext) = path.extension().and_then(OsStr::to_str) else {
+        return false;
+    };
+    if extensions.is_empty() {
+        return ext.eq_ignore_ascii_case("vvm");
+    }
+    extensions
+        .iter()
+        .any(|allowed| ext.eq_ignore_ascii_case(allowed.trim_start_matches('.')))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supported_styles_follow_the_speaker_map() {
+        let loaded = [2, 302, 5];
+        let map = HashMap::from([(1, 302)]);
+        assert_eq!(servable_styles(loaded.into_iter(), None, &map), [2, 5, 302]);
+
+        let allowed = HashSet::from([1]);
+        assert_eq!(
+            servable_styles(loaded.into_iter(), Some(&allowed), &map),
+            [302]
+        );
+
+        let allowed = HashSet::from([1, 5, 9]);
+        assert_eq!(
+            servable_styles(loaded.into_iter(), Some(&allowed), &map),
+            [5, 302]
+        );
+    }
+
+    fn style(style_id: u32, model: &str) -> StyleInfo {
+        StyleInfo {
+            style_id,
+            character: "character".into(),
+            style: "normal".into(),
+            model_path: PathBuf::from(model),
+        }
+    }
+
+    #[test]
+    fn colliding_style_ids_keep_the_first_model() {
+        let mut mapping = BTreeMap::new();
+        collect_styles(
+            vec![style(1, "a.vvm"), style(2, "a.vvm")],
+            &mut mapping,
+            false,
+        )
+        .unwrap();
+        collect_styles(
+            vec![style(2, "b.vvm"), style(3, "b.vvm")],
+            &mut mapping,
+            false,
+        )
+        .unwrap();
+
+        let models: Vec<_> = mapping
+            .iter()
+            .map(|(id, style)| (*id, style.model_path.to_str().unwrap()))
+            .collect();
+        assert_eq!(models, [(1, "a.vvm"), (2, "a.vvm"), (3, "b.vvm")]);
+    }
+
+    #[test]
+    fn colliding_style_ids_fail_in_strict_mode() {
+        let mut mapping = BTreeMap::new();
+        collect_styles(vec![style(1, "a.vvm")], &mut mapping, true).unwrap();
+
+        match collect_styles(vec![style(1, "b.vvm")], &mut mapping, true) {
+            Err(EngineError::InvalidTask(message)) => {
+                assert!(message.contains("a.vvm"), "{}", message);
+                assert!(message.contains("b.vvm"), "{}", message);
+            }
+            other => panic!("expected an invalid task, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn repeated_styles_of_one_model_are_not_a_collision() {
+        let mut mapping = BTreeMap::new();
+        collect_styles(vec![style(1, "a.vvm")], &mut mapping, true).unwrap();
+        collect_styles(vec![style(1, "a.vvm")], &mut mapping, true).unwrap();
+        assert_eq!(mapping.len(), 1);
+    }
+
+    #[test]
+    fn model_extensions_are_configurable() {
+        let model = |ext: &str| vec![ext.to_string()];
+        assert!(has_model_extension(Path::new("foo.MODEL"), &model("model")));
+        assert!(has_model_extension(
+            Path::new("foo.MODEL"),
+            &model(".model")
+        ));
+        assert!(!has_model_extension(Path::new("foo.vvm"), &model("model")));
+
+        assert!(has_model_extension(Path::new("foo.vvm"), &[]));
+        assert!(!has_model_extension(Path::new("foo.model"), &[]));
+        assert!(!has_model_extension(Path::new("vvm"), &[]));
+    }
+
+    #[test]
+    #[ignore = "needs ONNX Runtime and an Open JTalk dictionary in VOICEVOX_OPEN_JTALK_DIR"]
+    fn synthesizer_builds_with_explicit_cpu_threads() {
+        let dict = env::var("VOICEVOX_OPEN_JTALK_DIR").expect("VOICEVOX_OPEN_JTALK_DIR");
+        let ort = load_onnxruntime(&[]).unwrap();
+        let text_analyzer = open_text_analyzer(Utf8Path::new(&dict), None).unwrap();
+
+        for threads in [Some(1), Some(0), None] {
+            let synthesizer =
+                build_synthesizer(ort, &text_analyzer, AccelerationMode::Cpu, threads).unwrap();
+            assert!(!synthesizer.is_gpu_mode());
+        }
+    }
+
+    fn lru_paths(lru: &ModelLru<u32>) -> Vec<&Path> {
+        lru.loaded.iter().map(|(path, _)| path.as_path()).collect()
+    }
+
+    #[test]
+    fn model_lru_with_capacity_one_evicts_the_previous_model() {
+        let mut lru = ModelLru::new(1);
+        assert!(lru.insert(PathBuf::from("a.vvm"), 1).is_empty());
+
+        let evicted = lru.insert(PathBuf::from("b.vvm"), 2);
+        assert_eq!(evicted, vec![(PathBuf::from("a.vvm"), 1)]);
+        assert_eq!(lru_paths(&lru), [Path::new("b.vvm")]);
+    }
+
+    #[test]
+    fn model_lru_evicts_the_least_recently_used() {
+        let mut lru = ModelLru::new(2);
+        lru.insert(PathBuf::from("a.vvm"), 1);
+        lru.insert(PathBuf::from("b.vvm"), 2);
+        lru.touch(Path::new("a.vvm"));
+
+        let evicted = lru.insert(PathBuf::from("c.vvm"), 3);
+        assert_eq!(evicted, vec![(PathBuf::from("b.vvm"), 2)]);
+        assert_eq!(lru_paths(&lru), [Path::new("a.vvm"), Path::new("c.vvm")]);
+    }
+
+    #[test]
+    fn model_lru_without_capacity_keeps_everything() {
+        let mut lru = ModelLru::new(0);
+        for id in 0..5 {
+            assert!(lru
+                .insert(PathBuf::from(format!("{}.vvm", id)), id)
+                .is_empty());
+        }
+        assert_eq!(lru.loaded.len(), 5);
+    }
+
+    #[test]
+    fn model_lru_restore_puts_models_back_as_least_recent() {
+        let mut lru = ModelLru::new(1);
+        lru.insert(PathBuf::from("a.vvm"), 1);
+        let evicted = lru.insert(PathBuf::from("b.vvm"), 2);
+
+        lru.restore(evicted);
+        assert_eq!(lru_paths(&lru), [Path::new("a.vvm"), Path::new("b.vvm")]);
+        // The restored model is the first to go next time.
+        let evicted = lru.insert(PathBuf::from("c.vvm"), 3);
+        assert_eq!(evicted[0], (PathBuf::from("a.vvm"), 1));
+    }
+
+    #[test]
+    fn validate_filename_rejects_traversal_and_absolute_paths() {
+        for name in [
+            "../escape.wav",
+            "sub/../../escape.wav",
+            "..\\escape.wav",
+            "/etc/passwd",
+            "\\share\\out.wav",
+            "sub//out.wav",
+            "./out.wav",
+        ] {
+            assert!(
+                matches!(validate_filename(name), Err(EngineError::InvalidTask(_))),
+                "{} was accepted",
+                name
+            );
+        }
+        validate_filename("out.wav").unwrap();
+        validate_filename("sub/dir/out.wav").unwrap();
+    }
+
+    #[test]
+    fn sidecar_path_keeps_the_audio_extension() {
+        assert_eq!(
+            sidecar_path(Path::new("out/a.wav"), "json"),
+            PathBuf::from("out/a.wav.json")
+        );
+        assert_ne!(
+            sidecar_path(Path::new("a.wav"), "json"),
+            sidecar_path(Path::new("a.mp3"), "json")
+        );
+    }
+
+    #[test]
+    fn ensure_within_root_rejects_paths_outside_the_root() {
+        let root = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(root.path()).unwrap();
+        let outside = tempfile::tempdir().unwrap();
+
+        ensure_within_root(&root, &root.join("out.wav")).unwrap();
+        ensure_within_root(&root, &root.join("new/dir/out.wav")).unwrap();
+        assert!(matches!(
+            ensure_within_root(&root, &outside.path().join("out.wav")),
+            Err(EngineError::InvalidTask(_))
+        ));
+        assert!(matches!(
+            ensure_within_root(&root, Path::new("/etc/out.wav")),
+            Err(EngineError::InvalidTask(_))
+        ));
+    }
+
+    #[test]
+    fn text_path_is_read_inside_the_text_root() {
+        let root = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(root.path()).unwrap();
+        fs::create_dir(root.join("scripts")).unwrap();
+        fs::write(root.join("scripts/line.txt"), "こんにちは").unwrap();
+
+        assert_eq!(
+            read_text_file(&root, "scripts/line.txt").unwrap(),
+            "こんにちは"
+        );
+        let absolute = root.join("scripts/line.txt");
+        assert_eq!(
+            read_text_file(&root, absolute.to_str().unwrap()).unwrap(),
+            "こんにちは"
+        );
+    }
+
+    #[test]
+    fn text_path_outside_the_text_root_is_rejected() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("texts")).unwrap();
+        fs::write(root.path().join("sibling.txt"), "sibling").unwrap();
+        fs::write(outside.path().join("secret.txt"), "secret").unwrap();
+        let texts = fs::canonicalize(root.path().join("texts")).unwrap();
+        let secret = outside.path().join("secret.txt");
+
+        for path in [secret.to_str().unwrap(), "../sibling.txt", "missing.txt"] {
+            assert!(
+                matches!(
+                    read_text_file(&texts, path),
+                    Err(EngineError::InvalidTask(_))
+                ),
+                "{} was read",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn text_path_requires_a_text_root() {
+        let message = TaskMessage {
+            text_path: Some("line.txt".into()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            TaskText::from_task(&message, None),
+            Err(EngineError::InvalidTask(_))
+        ));
+        assert!(TaskText::from_task(&message, Some(Path::new("/srv/texts"))).is_ok());
+    }
+
+    #[test]
+    fn info_comment_labels_the_requested_speaker_and_the_style() {
+        assert_eq!(
+            info_comment("eval", "task-1", 3, 302),
+            "eval_id=eval task_id=task-1 speaker_id=3 style_id=302"
+        );
+    }
+
+    #[test]
+    fn replacements_apply_in_order() {
+        let chain = [
+            ("a".to_string(), "b".to_string()),
+            ("b".to_string(), "c".to_string()),
+        ];
+        assert_eq!(apply_replacements("a".into(), &chain, None).unwrap(), "c");
+    }
+
+    #[test]
+    fn no_replacements_leave_the_text_unchanged() {
+        assert_eq!(
+            apply_replacements("AIです".into(), &[], None).unwrap(),
+            "AIです"
+        );
+    }
+
+    #[test]
+    fn text_length_is_checked_after_replacements() {
+        let expand = [("AI".to_string(), "エーアイ".to_string())];
+        assert_eq!(
+            apply_replacements("AIです".into(), &expand, Some(6)).unwrap(),
+            "エーアイです"
+        );
+        assert!(matches!(
+            apply_replacements("AIです".into(), &expand, Some(5)),
+            Err(EngineError::InvalidTask(_))
+        ));
+
+        let shrink = [("ー".to_string(), String::new())];
+        assert_eq!(
+            apply_replacements("ーーーあ".into(), &shrink, Some(1)).unwrap(),
+            "あ"
+        );
+    }
+
+    fn settings() -> SynthesisSettings {
+        SynthesisSettings {
+            speaker_id: 3,
+            text: "こんにちは".into(),
+            dict_name: None,
+            speed_scale: Some(1.2),
+            pitch_scale: None,
+            intonation_scale: None,
+            volume_scale: None,
+            output_format: OutputFormat::Wav,
+            target_sample_rate: None,
+            normalize_lufs: None,
+            trim_silence_db: None,
+            output_bit_depth: None,
+            embed_metadata: false,
+        }
+    }
+
+    fn write_output(path: &Path, audio: &[u8], settings: &SynthesisSettings) {
+        fs::write(path, audio).unwrap();
+        let metadata = OutputMetadata {
+            eval_id: "e",
+            task_id: "t",
+            settings,
+            sample_rate: None,
+            synthesis_ms: 1,
+            audio_duration_ms: None,
+            output_sha256: &format!("{:x}", Sha256::digest(audio)),
+        };
+        fs::write(
+            sidecar_path(path, "json"),
+            serde_json::to_vec(&metadata).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn skip_if_exists_reuses_only_output_made_with_the_same_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.wav");
+        write_output(&path, b"audio", &settings());
+
+        let output = reusable_output(&path, &settings(), "e", "t")
+            .unwrap()
+            .unwrap();
+        assert_eq!(output.output_bytes, Some(5));
+
+        let faster = SynthesisSettings {
+            speed_scale: Some(1.5),
+            ..settings()
+        };
+        assert!(reusable_output(&path, &faster, "e", "t").unwrap().is_none());
+        let mp3 = SynthesisSettings {
+            output_format: OutputFormat::Mp3,
+            ..settings()
+        };
+        assert!(reusable_output(&path, &mp3, "e", "t").unwrap().is_none());
+
+        fs::write(&path, b"changed").unwrap();
+        assert!(reusable_output(&path, &settings(), "e", "t")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn skip_if_exists_does_not_trust_output_without_a_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.wav");
+        fs::write(&path, b"audio").unwrap();
+
+        assert!(reusable_output(&path, &settings(), "e", "t")
+            .unwrap()
+            .is_none());
+    }
+
+    fn poison(member: &Mutex<u32>) {
+        thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let _guard = member.lock().unwrap();
+                    panic!("task panicked mid-synthesis");
+                })
+                .join()
+                .unwrap_err();
+        });
+        assert!(member.is_poisoned());
+    }
+
+    #[test]
+    fn poisoned_pool_member_is_recovered() {
+        let pool = [Mutex::new(0u32), Mutex::new(1u32)];
+        poison(&pool[0]);
+
+        let guard = acquire_member(&pool, 0, |_| false);
+        assert_eq!(*guard, 0);
+        drop(guard);
+        assert!(!pool[0].is_poisoned());
+
+        poison(&pool[1]);
+        let _busy = pool[0].lock().unwrap();
+        let guard = acquire_member(&pool, 0, |_| false);
+        assert_eq!(*guard, 1);
+        drop(guard);
+        assert!(!pool[1].is_poisoned());
+    }
+
+    #[test]
+    fn dry_run_output_check_creates_nothing() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("a/b");
+
+        check_output_dir(&dir).unwrap();
+        assert!(!root.path().join("a").exists());
+
+        fs::write(root.path().join("file"), b"").unwrap();
+        assert!(check_output_dir(&root.path().join("file/sub")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ensure_within_root_follows_symlinks_out_of_the_root() {
+        let root = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(root.path()).unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.join("link")).unwrap();
+
+        assert!(matches!(
+            ensure_within_root(&root, &root.join("link/out.wav")),
+            Err(EngineError::InvalidTask(_))
+        ));
+    }
 }